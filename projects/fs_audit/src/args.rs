@@ -0,0 +1,101 @@
+use clap::Parser;
+use std::{num::NonZeroUsize, path::PathBuf};
+use utils::arg_parsers::{check_if_file_exists, check_if_parent_path_exists};
+use utils::logging::LogArgs;
+
+#[derive(Parser, Debug)]
+#[command(
+    author,
+    version,
+    about,
+    help_template = "{before-help}{name} {version}
+
+Author: {author}
+
+{about-with-newline}
+{usage-heading} {usage}
+
+{all-args}{after-help}
+"
+)]
+pub(crate) struct Args {
+    #[arg(
+        id = "filesystem state file",
+        long = "state",
+        short = 's',
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the filesystem state file to audit"
+    )]
+    pub state: PathBuf,
+    #[arg(
+        id = "baseline filesystem state file",
+        long = "baseline",
+        short = 'b',
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to an older filesystem state file, only violations not already present in it are reported"
+    )]
+    pub baseline_state: Option<PathBuf>,
+    #[arg(
+        id = "rules file",
+        long = "rules",
+        short = 'r',
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to a TOML file with the rules to evaluate, defaults to checking world-writable entries, setuid and setgid files and unknown owners and groups"
+    )]
+    pub rules: Option<PathBuf>,
+    #[arg(
+        id = "passwd file",
+        long = "passwd",
+        default_value = "/etc/passwd",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the passwd file listing the known users"
+    )]
+    pub passwd: PathBuf,
+    #[arg(
+        id = "group file",
+        long = "group",
+        default_value = "/etc/group",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the group file listing the known groups"
+    )]
+    pub group: PathBuf,
+    #[arg(
+        id = "threads",
+        long,
+        short = 't',
+        help = "",
+        long_help = "Number of threads to use, defaults to CPU count"
+    )]
+    pub threads: Option<NonZeroUsize>,
+    #[arg(
+        id = "write violations to file",
+        long = "output",
+        short = 'o',
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the violations to, defaults to standard output"
+    )]
+    pub write_violations_to: Option<PathBuf>,
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+impl Args {
+    pub fn threads(&self) -> usize {
+        let cpus = num_cpus::get();
+        self.threads
+            .unwrap_or_else(|| {
+                if cfg!(target_vendor = "apple") {
+                    NonZeroUsize::new(cpus).unwrap()
+                } else {
+                    std::thread::available_parallelism().unwrap_or(NonZeroUsize::new(cpus).unwrap())
+                }
+            })
+            .get()
+    }
+}
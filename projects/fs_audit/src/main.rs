@@ -0,0 +1,137 @@
+pub(crate) mod args;
+pub(crate) mod rules;
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process,
+};
+
+use args::Args;
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
+use rules::{read_ids, KnownIds, Rules};
+use tracing::{error, warn};
+use utils::{
+    fs::{escape_name, FsEntries, FsEntry},
+    state::read_state,
+};
+
+/// Exit code used when the audit found violations.
+const VIOLATIONS_EXIT_CODE: i32 = 3;
+
+struct Violation<'a> {
+    rule: &'a str,
+    entry: &'a FsEntry,
+}
+
+fn read_entries(path: &Path) -> FsEntries {
+    read_state(path)
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        })
+        .into_fs_entries()
+}
+
+fn find_violations<'a>(
+    entries: &'a FsEntries,
+    rules: &'a Rules,
+    known_ids: &KnownIds,
+) -> Vec<Violation<'a>> {
+    entries
+        .entries
+        .par_iter()
+        .flat_map_iter(|entry| {
+            rules
+                .rules
+                .iter()
+                .filter(|rule| rule.is_violated_by(entry, known_ids))
+                .map(move |rule| Violation {
+                    rule: rule.name.as_str(),
+                    entry,
+                })
+        })
+        .collect()
+}
+
+fn write_violations(writer: &mut impl Write, violations: &[Violation]) -> io::Result<()> {
+    for violation in violations {
+        writeln!(
+            writer,
+            "{}\t{}\towner={}\tgroup={}\tmode={:o}",
+            violation.rule,
+            escape_name(&violation.entry.name),
+            violation.entry.owner,
+            violation.entry.group,
+            violation.entry.mode
+        )?;
+    }
+    writer.flush()
+}
+
+fn main() {
+    let args: Args = utils::config::parse();
+    utils::logging::init(&args.log, None);
+
+    ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build_global()
+        .unwrap();
+
+    let rules = match &args.rules {
+        Some(path) => Rules::load(path).unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        }),
+        None => Rules::default(),
+    };
+    let known_ids = match (read_ids(&args.passwd), read_ids(&args.group)) {
+        (Ok(users), Ok(groups)) => KnownIds { users, groups },
+        (Err(err), _) | (_, Err(err)) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let state = read_entries(&args.state);
+    let mut violations = find_violations(&state, &rules, &known_ids);
+
+    let baseline_state = args.baseline_state.as_deref().map(read_entries);
+    if let Some(baseline_state) = &baseline_state {
+        let known_violations: HashSet<(&str, &OsStr)> =
+            find_violations(baseline_state, &rules, &known_ids)
+                .into_iter()
+                .map(|violation| (violation.rule, violation.entry.name.as_os_str()))
+                .collect();
+        violations.retain(|violation| {
+            !known_violations.contains(&(violation.rule, violation.entry.name.as_os_str()))
+        });
+    }
+    violations.sort_unstable_by(|a, b| {
+        a.entry
+            .name
+            .cmp(&b.entry.name)
+            .then_with(|| a.rule.cmp(b.rule))
+    });
+
+    let write_result = match &args.write_violations_to {
+        Some(path) => File::create(path)
+            .and_then(|file| write_violations(&mut BufWriter::new(file), &violations)),
+        None => write_violations(&mut io::stdout().lock(), &violations),
+    };
+    if let Err(err) = write_result {
+        error!("Failed to write violations. Error : {}", err);
+        process::exit(1);
+    }
+
+    if !violations.is_empty() {
+        warn!(violations = violations.len(), "Found violations");
+        process::exit(VIOLATIONS_EXIT_CODE);
+    }
+}
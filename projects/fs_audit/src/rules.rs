@@ -0,0 +1,279 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use utils::{fs::FsEntry, ids::read_id_names};
+
+const S_ISUID: u32 = 0o4000;
+const S_ISGID: u32 = 0o2000;
+const S_ISVTX: u32 = 0o1000;
+const S_IWOTH: u32 = 0o0002;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Check {
+    /// World-writable files, and world-writable directories without the sticky bit.
+    WorldWritable,
+    /// Regular files with the setuid bit.
+    Setuid,
+    /// Regular files with the setgid bit.
+    Setgid,
+    /// Entries owned by a uid missing from the passwd file.
+    UnknownOwner,
+    /// Entries owned by a gid missing from the group file.
+    UnknownGroup,
+    /// Directories without the sticky bit.
+    MissingStickyBit,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rule {
+    pub name: String,
+    pub check: Check,
+    /// Subtrees the rule applies to, relative to the state root. Applies everywhere when empty.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Subtrees excluded from the rule, relative to the state root.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rules {
+    #[serde(rename = "rule", default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Users and groups known on the system the state was generated from.
+pub(crate) struct KnownIds {
+    pub users: HashSet<u32>,
+    pub groups: HashSet<u32>,
+}
+
+fn is_within(name: &OsStr, subtree: &str) -> bool {
+    let name = name.as_bytes();
+    let subtree = subtree.trim_matches('/').as_bytes();
+    subtree.is_empty()
+        || name == subtree
+        || (name.starts_with(subtree) && name[subtree.len()..].starts_with(b"/"))
+}
+
+impl Rule {
+    fn default_rule(name: &str, check: Check) -> Rule {
+        Rule {
+            name: String::from(name),
+            check,
+            paths: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    fn applies_to(&self, name: &OsStr) -> bool {
+        (self.paths.is_empty() || self.paths.iter().any(|subtree| is_within(name, subtree)))
+            && !self.exclude.iter().any(|subtree| is_within(name, subtree))
+    }
+
+    pub fn is_violated_by(&self, entry: &FsEntry, known_ids: &KnownIds) -> bool {
+        if !self.applies_to(&entry.name) {
+            return false;
+        }
+        match self.check {
+            Check::WorldWritable => {
+                !entry.is_symlink
+                    && entry.mode & S_IWOTH != 0
+                    && !(entry.is_dir && entry.mode & S_ISVTX != 0)
+            }
+            Check::Setuid => entry.is_file && entry.mode & S_ISUID != 0,
+            Check::Setgid => entry.is_file && entry.mode & S_ISGID != 0,
+            Check::UnknownOwner => !known_ids.users.contains(&entry.owner),
+            Check::UnknownGroup => !known_ids.groups.contains(&entry.group),
+            Check::MissingStickyBit => entry.is_dir && entry.mode & S_ISVTX == 0,
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            rules: vec![
+                Rule::default_rule("world-writable", Check::WorldWritable),
+                Rule::default_rule("setuid", Check::Setuid),
+                Rule::default_rule("setgid", Check::Setgid),
+                Rule::default_rule("unknown-owner", Check::UnknownOwner),
+                Rule::default_rule("unknown-group", Check::UnknownGroup),
+            ],
+        }
+    }
+}
+
+impl Rules {
+    pub fn load(path: &PathBuf) -> Result<Rules, String> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "Failed to read rules file '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })?;
+        toml::from_str(&contents).map_err(|err| {
+            format!(
+                "Failed to parse rules file '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })
+    }
+}
+
+/// Reads the numeric ids (third field) of a passwd or group file.
+pub(crate) fn read_ids(path: &Path) -> Result<HashSet<u32>, String> {
+    Ok(read_id_names(path)?.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn entry(name: &str, mode: u32) -> FsEntry {
+        FsEntry {
+            name: OsString::from(name),
+            owner: 1000,
+            group: 1000,
+            mode,
+            mtime: 1,
+            ctime: 0,
+            inode: 1,
+            size: 1,
+            is_dir: mode & 0o170000 == 0o040000,
+            is_file: mode & 0o170000 == 0o100000,
+            is_symlink: mode & 0o170000 == 0o120000,
+        }
+    }
+
+    fn known_ids() -> KnownIds {
+        KnownIds {
+            users: HashSet::from([0, 1000]),
+            groups: HashSet::from([0]),
+        }
+    }
+
+    fn violated(rule: &Rule, entry: &FsEntry) -> bool {
+        rule.is_violated_by(entry, &known_ids())
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules: Rules = toml::from_str(
+            r#"
+            [[rule]]
+            name = "shared"
+            check = "world-writable"
+            paths = ["srv/shared/"]
+            exclude = ["srv/shared/tmp"]
+
+            [[rule]]
+            name = "sticky"
+            check = "missing-sticky-bit"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(rules.rules[0].check, Check::WorldWritable);
+        assert_eq!(rules.rules[0].paths, ["srv/shared/"]);
+        assert_eq!(rules.rules[0].exclude, ["srv/shared/tmp"]);
+        assert_eq!(rules.rules[1].check, Check::MissingStickyBit);
+        assert!(rules.rules[1].paths.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let unknown_check = "[[rule]]\nname = \"r\"\ncheck = \"executable\"\n";
+        assert!(toml::from_str::<Rules>(unknown_check).is_err());
+        let unknown_field = "[[rule]]\nname = \"r\"\ncheck = \"setuid\"\npath = [\"a\"]\n";
+        assert!(toml::from_str::<Rules>(unknown_field).is_err());
+        let missing_check = "[[rule]]\nname = \"r\"\n";
+        assert!(toml::from_str::<Rules>(missing_check).is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("fs_tools_rules_{}_rules.toml", std::process::id()));
+        fs::write(&path, unknown_check).unwrap();
+        let err = Rules::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.starts_with("Failed to parse rules file"), "{}", err);
+    }
+
+    #[test]
+    fn matches_subtrees() {
+        assert!(is_within(OsStr::new("a/b"), ""));
+        assert!(is_within(OsStr::new("a/b"), "/"));
+        assert!(is_within(OsStr::new("a/b"), "a"));
+        assert!(is_within(OsStr::new("a/b"), "/a/b/"));
+        assert!(is_within(OsStr::new("a/b/c"), "a/b"));
+        assert!(!is_within(OsStr::new("a/bc"), "a/b"));
+        assert!(!is_within(OsStr::new("a"), "a/b"));
+        assert!(!is_within(OsStr::new("b/a"), "a"));
+    }
+
+    #[test]
+    fn applies_to_paths_but_not_excluded_ones() {
+        let rule = Rule {
+            paths: vec![String::from("srv"), String::from("home")],
+            exclude: vec![String::from("srv/tmp")],
+            ..Rule::default_rule("world-writable", Check::WorldWritable)
+        };
+        assert!(violated(&rule, &entry("srv/f", 0o100666)));
+        assert!(violated(&rule, &entry("home/u/f", 0o100666)));
+        assert!(!violated(&rule, &entry("srv/tmp/f", 0o100666)));
+        assert!(!violated(&rule, &entry("srvx/f", 0o100666)));
+        assert!(!violated(&rule, &entry("etc/f", 0o100666)));
+    }
+
+    #[test]
+    fn checks_modes() {
+        let world_writable = Rule::default_rule("world-writable", Check::WorldWritable);
+        assert!(violated(&world_writable, &entry("f", 0o100666)));
+        assert!(!violated(&world_writable, &entry("f", 0o100664)));
+        assert!(violated(&world_writable, &entry("d", 0o040777)));
+        assert!(!violated(&world_writable, &entry("tmp", 0o041777)));
+        assert!(!violated(&world_writable, &entry("l", 0o120777)));
+
+        let setuid = Rule::default_rule("setuid", Check::Setuid);
+        assert!(violated(&setuid, &entry("f", 0o104755)));
+        assert!(!violated(&setuid, &entry("f", 0o102755)));
+        assert!(!violated(&setuid, &entry("d", 0o044755)));
+
+        let setgid = Rule::default_rule("setgid", Check::Setgid);
+        assert!(violated(&setgid, &entry("f", 0o102755)));
+        assert!(!violated(&setgid, &entry("f", 0o104755)));
+        assert!(!violated(&setgid, &entry("d", 0o042755)));
+
+        let sticky = Rule::default_rule("sticky", Check::MissingStickyBit);
+        assert!(violated(&sticky, &entry("d", 0o040777)));
+        assert!(!violated(&sticky, &entry("d", 0o041777)));
+        assert!(!violated(&sticky, &entry("f", 0o100777)));
+    }
+
+    #[test]
+    fn checks_owners_and_groups() {
+        let unknown_owner = Rule::default_rule("unknown-owner", Check::UnknownOwner);
+        let unknown_group = Rule::default_rule("unknown-group", Check::UnknownGroup);
+        let known = entry("f", 0o100644);
+        let unknown = FsEntry {
+            owner: 1001,
+            group: 0,
+            ..entry("f", 0o100644)
+        };
+        assert!(!violated(&unknown_owner, &known));
+        assert!(violated(&unknown_owner, &unknown));
+        assert!(violated(&unknown_group, &known));
+        assert!(!violated(&unknown_group, &unknown));
+    }
+}
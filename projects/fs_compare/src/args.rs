@@ -1,6 +1,11 @@
 use clap::Parser;
 use std::{num::NonZeroUsize, path::PathBuf};
 use utils::arg_parsers::{check_if_file_exists, check_if_parent_path_exists};
+use utils::encoding::Compression;
+
+use crate::pairing::Normalization;
+use utils::logging::LogArgs;
+use utils::metrics::MetricsArgs;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -23,19 +28,37 @@ pub(crate) struct Args {
         long = "state-source",
         short = 's',
         value_parser = check_if_file_exists(),
+        required_unless_present = "source state command",
         help = "",
         long_help = "Path to the source filesystem state file"
     )]
-    pub src_state: PathBuf,
+    pub src_state: Option<PathBuf>,
+    #[arg(
+        id = "source state command",
+        long = "command-source",
+        conflicts_with = "source filesystem state file",
+        help = "",
+        long_help = "Command run by sh whose output is the source filesystem state, such as ssh host fs_state_gen --stdout -s /data, instead of a state file. Fails when the command fails"
+    )]
+    pub src_command: Option<String>,
     #[arg(
         id = "destination filesystem state file",
         long = "state-destination",
         short = 'd',
         value_parser = check_if_file_exists(),
+        required_unless_present = "destination state command",
         help = "",
         long_help = "Path to the destination filesystem state file"
     )]
-    pub dst_state: PathBuf,
+    pub dst_state: Option<PathBuf>,
+    #[arg(
+        id = "destination state command",
+        long = "command-destination",
+        conflicts_with = "destination filesystem state file",
+        help = "",
+        long_help = "Command run by sh whose output is the destination filesystem state, instead of a state file. Runs alongside the source command"
+    )]
+    pub dst_command: Option<String>,
     #[arg(
         id = "threads",
         long,
@@ -48,18 +71,196 @@ pub(crate) struct Args {
         id = "write differences to file",
         long = "output",
         short = 'o',
+        required_unless_present_any = ["write report to file", "base filesystem state file"],
         value_parser = check_if_parent_path_exists(),
         help = "",
-        long_help="Path to write the differences between the source and destination filesystem states"
+        long_help="Path to write the differences between the source and destination filesystem states. In three-way comparisons, the changes made on the source (A) to apply to the destination (B)"
+    )]
+    pub write_changes_to: Option<PathBuf>,
+    #[arg(
+        id = "base filesystem state file",
+        long = "state-base",
+        short = 'b',
+        value_parser = check_if_file_exists(),
+        conflicts_with_all = ["write report to file", "source subtree", "destination subtree", "rewrite", "id map file", "source passwd file", "source group file", "destination passwd file", "destination group file"],
+        help = "",
+        long_help = "Path to the filesystem state the source (A) had after the last sync, also used for the destination (B) unless its own base state is given. Compares the source and destination states three-way, classifying each path as unchanged, changed on A, changed on B or conflicting"
     )]
-    pub write_changes_to: PathBuf,
+    pub base_state: Option<PathBuf>,
+    #[arg(
+        id = "destination base filesystem state file",
+        long = "state-base-destination",
+        requires = "base filesystem state file",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the filesystem state the destination (B) had after the last sync, as written by run_rsync --update-base-destination. Without it, owners, groups or modes the destination keeps different from the source are seen as changes made on B"
+    )]
+    pub dst_base_state: Option<PathBuf>,
+    #[arg(
+        id = "write reverse differences to file",
+        long = "output-reverse",
+        requires = "base filesystem state file",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the changes made on the destination (B) to apply to the source (A) in three-way comparisons"
+    )]
+    pub write_reverse_changes_to: Option<PathBuf>,
+    #[arg(
+        id = "write conflicts to file",
+        long = "conflicts",
+        requires = "base filesystem state file",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the paths changed on both sides in three-way comparisons, printed to stderr otherwise"
+    )]
+    pub write_conflicts_to: Option<PathBuf>,
+    #[arg(
+        id = "write conflicting entries to file",
+        long = "output-conflicts",
+        requires = "base filesystem state file",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the entries changed on both sides in three-way comparisons, in the differences format, for run_rsync to resolve with a conflict policy"
+    )]
+    pub write_conflicting_to: Option<PathBuf>,
+    #[arg(
+        id = "write report to file",
+        long = "report",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write a growth and churn report of the source filesystem state relative to the destination filesystem state"
+    )]
+    pub write_report_to: Option<PathBuf>,
+    #[arg(
+        id = "report depth",
+        long = "report-depth",
+        default_value = "1",
+        help = "",
+        long_help = "Number of leading path components used to group entries into subtrees in the report"
+    )]
+    pub report_depth: usize,
+    #[arg(
+        id = "report top files",
+        long = "report-top",
+        default_value = "10",
+        help = "",
+        long_help = "Number of biggest new files to list in the report"
+    )]
+    pub report_top: usize,
+    #[arg(
+        id = "source subtree",
+        long = "source-subtree",
+        help = "",
+        long_help = "Directory of the source filesystem state to compare, relative to its root. Paths in the differences and the report are relative to this directory"
+    )]
+    pub src_subtree: Option<PathBuf>,
+    #[arg(
+        id = "destination subtree",
+        long = "destination-subtree",
+        help = "",
+        long_help = "Directory of the destination filesystem state to compare, relative to its root. Paths in the differences and the report are relative to this directory"
+    )]
+    pub dst_subtree: Option<PathBuf>,
+    #[arg(
+        id = "rewrite",
+        long = "rewrite",
+        value_parser = parse_rewrite,
+        help = "",
+        long_help = "Compare the source directory FROM with the destination directory TO, given as FROM=TO relative to the compared directories, for directories relocated on the destination. Can be repeated. The differences of the Nth rule are written next to the differences file with .N appended to its name, with paths relative to FROM and TO, and run_rsync syncs them along with the differences file"
+    )]
+    pub rewrites: Vec<(PathBuf, PathBuf)>,
+    #[arg(
+        id = "case insensitive",
+        long = "case-insensitive",
+        help = "",
+        long_help = "Match paths differing only by case, for destinations on a case-insensitive file system. Entries whose names collide are reported and only one of them is compared"
+    )]
+    pub case_insensitive: bool,
+    #[arg(
+        id = "unicode normalization",
+        long = "normalization",
+        value_enum,
+        help = "",
+        long_help = "Match paths differing only by their Unicode normalization, for destinations on a file system that normalizes names to this form. Entries whose names collide are reported and only one of them is compared"
+    )]
+    pub normalization: Option<Normalization>,
+    #[arg(
+        id = "id map file",
+        long = "id-map",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to a TOML file mapping source user and group ids or names to destination ones, in [users] and [groups] tables"
+    )]
+    pub id_map: Option<PathBuf>,
+    #[arg(
+        id = "source passwd file",
+        long = "source-passwd",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the passwd file of the source system, used to resolve user names. Users with the same name in both passwd files are mapped automatically"
+    )]
+    pub src_passwd: Option<PathBuf>,
+    #[arg(
+        id = "source group file",
+        long = "source-group",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the group file of the source system, used to resolve group names. Groups with the same name in both group files are mapped automatically"
+    )]
+    pub src_group: Option<PathBuf>,
+    #[arg(
+        id = "destination passwd file",
+        long = "destination-passwd",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the passwd file of the destination system, used to resolve user names"
+    )]
+    pub dst_passwd: Option<PathBuf>,
+    #[arg(
+        id = "destination group file",
+        long = "destination-group",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the group file of the destination system, used to resolve group names"
+    )]
+    pub dst_group: Option<PathBuf>,
+    #[arg(
+        id = "compression",
+        long = "compression",
+        value_enum,
+        default_value_t = Compression::None,
+        help = "",
+        long_help = "Compression to apply to the written differences file, compressed files are detected automatically when read"
+    )]
+    pub compression: Compression,
+    #[arg(
+        id = "compression level",
+        long = "compression-level",
+        default_value = "0",
+        allow_negative_numbers = true,
+        help = "",
+        long_help = "Compression level to use with zstd, 0 selects the default level"
+    )]
+    pub compression_level: i32,
+    #[command(flatten)]
+    pub metrics: MetricsArgs,
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+fn parse_rewrite(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((PathBuf::from(from), PathBuf::from(to)))
+        }
+        _ => Err(format!("Expected FROM=TO, got '{}'", s)),
+    }
 }
 
 impl Args {
     pub fn threads(&self) -> usize {
         let cpus = num_cpus::get();
         self.threads
-            .clone()
             .unwrap_or_else(|| {
                 if cfg!(target_vendor = "apple") {
                     NonZeroUsize::new(cpus).unwrap()
@@ -1,127 +1,405 @@
 pub(crate) mod args;
+pub(crate) mod pairing;
+pub(crate) mod report;
+pub(crate) mod three_way;
 
-use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufReader, BufWriter},
-};
+use std::{ffi::OsString, path::Path, process, time::Instant};
 
 use args::Args;
-use clap::Parser;
+use pairing::EntryPair;
 use rayon::{
     iter::{IntoParallelRefIterator, ParallelIterator},
     ThreadPoolBuilder,
 };
-use utils::fs::{ChangedFsEntries, ChangedFsEntry, FsEntries, FsEntry};
-
-fn main() {
-    let args = Args::parse();
-
-    let src_state = args.src_state.clone();
-    let dst_state = args.dst_state.clone();
-    let write_changes_to = args.write_changes_to.clone();
-
-    ThreadPoolBuilder::new()
-        .num_threads(args.threads())
-        .build_global()
-        .unwrap();
+use tracing::{error, warn};
+use utils::{
+    encoding::write_to_file,
+    fs::{write_relocated_changes, ChangedFsEntries, ChangedFsEntry, FsEntry, RelocatedChanges},
+    ids::{IdMapping, IdNames},
+    metrics::{unix_time, Metrics},
+    state::{read_state, read_state_from_command, FsState},
+};
 
-    let src_map_data: HashMap<String, FsEntry>;
-    {
-        let mut reader = BufReader::new(File::open(src_state).unwrap());
-        let decoded: FsEntries =
-            bincode::decode_from_std_read(&mut reader, bincode::config::standard()).unwrap();
-        src_map_data = decoded
-            .entries
-            .into_iter()
-            .map(|entry| (entry.name.clone(), entry))
-            .collect::<HashMap<String, FsEntry>>();
-    }
+/// Returns true when the destination entry has to be synced from the source entry.
+pub(crate) fn has_changed(src_fsentry: &FsEntry, dst_fsentry: &FsEntry) -> bool {
+    dst_fsentry.owner != src_fsentry.owner
+        || dst_fsentry.group != src_fsentry.group
+        || dst_fsentry.mode != src_fsentry.mode
+        || dst_fsentry.mtime < src_fsentry.mtime
+        || dst_fsentry.inode != src_fsentry.inode
+        || dst_fsentry.size != src_fsentry.size
+        || dst_fsentry.is_dir != src_fsentry.is_dir
+        || dst_fsentry.is_symlink != src_fsentry.is_symlink
+        || dst_fsentry.is_file != src_fsentry.is_file
+}
 
-    let dst_map_data: HashMap<String, FsEntry>;
-    {
-        let mut reader = BufReader::new(File::open(dst_state).unwrap());
-        let decoded: FsEntries =
-            bincode::decode_from_std_read(&mut reader, bincode::config::standard()).unwrap();
-        dst_map_data = decoded
-            .entries
-            .into_iter()
-            .map(|entry| (entry.name.clone(), entry))
-            .collect::<HashMap<String, FsEntry>>();
+fn write_changes(entries: Vec<ChangedFsEntry>, path: &Path, args: &Args) {
+    let entries = ChangedFsEntries { entries };
+    if let Err(err) = write_to_file(&entries, path, args.compression, args.compression_level) {
+        error!("{}", err);
+        process::exit(1);
     }
+}
 
+/// Returns the changes syncing the destination entries of `pairs` from their source entries,
+/// naming entries with `src_path` and `dst_path`.
+fn changes(
+    pairs: &[EntryPair],
+    src_state: &FsState,
+    dst_state: &FsState,
+    src_path: &(dyn Fn(u32) -> OsString + Sync),
+    dst_path: &(dyn Fn(u32) -> OsString + Sync),
+) -> Vec<ChangedFsEntry> {
     let mut changed_fs_entries: Vec<ChangedFsEntry> = Vec::new();
 
-    let value: Vec<ChangedFsEntry> = dst_map_data
+    let value: Vec<ChangedFsEntry> = pairs
         .par_iter()
-        .map(|(name, fsentry)| {
-            let entry_name = name.clone();
-            let entry1 = src_map_data.get_key_value(entry_name.as_str());
-            if entry1.is_some() {
-                let entry2 = entry1.unwrap();
-                let src_fsentry = entry2.1;
-                if fsentry.owner != src_fsentry.owner
-                    || fsentry.group != src_fsentry.group
-                    || fsentry.mode != src_fsentry.mode
-                    || fsentry.mtime < src_fsentry.mtime
-                    || fsentry.inode != src_fsentry.inode
-                    || fsentry.size != src_fsentry.size
-                    || fsentry.is_dir != src_fsentry.is_dir
-                    || fsentry.is_symlink != src_fsentry.is_symlink
-                    || fsentry.is_file != src_fsentry.is_file
-                {
-                    return Ok(ChangedFsEntry {
-                        name: entry_name,
+        .filter_map(|pair| match *pair {
+            (Some(src_index), Some(dst_index)) => {
+                let fsentry = &dst_state.entries[dst_index as usize];
+                if has_changed(&src_state.entries[src_index as usize], fsentry) {
+                    // The source spelling of the path, which rsync reads from.
+                    return Some(ChangedFsEntry {
+                        name: src_path(src_index),
                         is_deleted: false,
                         is_dir: fsentry.is_dir,
                         is_file: fsentry.is_file,
                         is_symlink: fsentry.is_symlink,
                     });
                 }
-            } else {
-                return Ok(ChangedFsEntry {
-                    name: entry_name,
+                None
+            }
+            (None, Some(dst_index)) => {
+                let fsentry = &dst_state.entries[dst_index as usize];
+                // The destination spelling of the path, which is what has to be removed there.
+                Some(ChangedFsEntry {
+                    name: dst_path(dst_index),
                     is_deleted: true,
                     is_dir: fsentry.is_dir,
                     is_file: fsentry.is_file,
                     is_symlink: fsentry.is_symlink,
-                });
+                })
             }
-            Err(())
+            _ => None,
         })
-        .filter(|entry| entry.is_ok())
-        .map(|entry| entry.unwrap())
         .collect();
 
-    let value1: Vec<ChangedFsEntry> = src_map_data
+    let value1: Vec<ChangedFsEntry> = pairs
         .par_iter()
-        .map(|(name, fsentry)| {
-            let entry_name = name.clone();
-            let entry1 = dst_map_data.get_key_value(entry_name.as_str());
-            if !entry1.is_some() {
-                return Ok(ChangedFsEntry {
-                    name: entry_name,
+        .filter_map(|pair| match *pair {
+            (Some(src_index), None) => {
+                let fsentry = &src_state.entries[src_index as usize];
+                Some(ChangedFsEntry {
+                    name: src_path(src_index),
                     is_deleted: false,
                     is_dir: fsentry.is_dir,
                     is_file: fsentry.is_file,
                     is_symlink: fsentry.is_symlink,
-                });
+                })
             }
-            Err(())
+            _ => None,
         })
-        .filter(|entry| entry.is_ok())
-        .map(|entry| entry.unwrap())
         .collect();
 
     changed_fs_entries.extend(value);
     changed_fs_entries.extend(value1);
-    changed_fs_entries.dedup_by(|a, b| a.name.eq_ignore_ascii_case(&b.name));
+    changed_fs_entries
+}
+
+/// Records the duration of the comparison and writes the metrics file when one is given.
+fn write_metrics(args: &Args, metrics: &Metrics, started: Instant) {
+    let Some(metrics_file) = &args.metrics.metrics_file else {
+        return;
+    };
+    metrics.set(
+        "duration_seconds",
+        "Duration of the last comparison",
+        &[],
+        started.elapsed().as_secs_f64(),
+    );
+    metrics.set(
+        "last_run_timestamp_seconds",
+        "Time the last comparison ended at",
+        &[],
+        unix_time(),
+    );
+    if let Err(err) = metrics.write_to_file(metrics_file) {
+        error!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn compare_three_way(
+    args: &Args,
+    metrics: &Metrics,
+    a_base_state: &FsState,
+    b_base_state: &FsState,
+    a_state: &FsState,
+    b_state: &FsState,
+    matching: pairing::NameMatching,
+) {
+    let three_way = three_way::compare(a_base_state, b_base_state, a_state, b_state, matching)
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        });
+    println!("Unchanged:    {:>12}", three_way.unchanged);
+    println!("Changed on A: {:>12}", three_way.a_to_b.len());
+    println!("Changed on B: {:>12}", three_way.b_to_a.len());
+    println!("Conflicts:    {:>12}", three_way.conflicts.len());
+    metrics.set(
+        "unchanged_entries",
+        "Entries unchanged on both sides",
+        &[],
+        three_way.unchanged as f64,
+    );
+    metrics.set(
+        "changed_on_a_entries",
+        "Entries changed on side A only",
+        &[],
+        three_way.a_to_b.len() as f64,
+    );
+    metrics.set(
+        "changed_on_b_entries",
+        "Entries changed on side B only",
+        &[],
+        three_way.b_to_a.len() as f64,
+    );
+    metrics.set(
+        "conflicts",
+        "Entries changed on both sides",
+        &[],
+        three_way.conflicts.len() as f64,
+    );
+
+    if let Some(write_conflicts_to) = &args.write_conflicts_to {
+        if let Err(err) = three_way::write_conflicts(&three_way.conflicts, write_conflicts_to) {
+            error!(
+                "Failed to write conflicts to '{}'. Error : {}",
+                write_conflicts_to.display(),
+                err
+            );
+            process::exit(1);
+        }
+    } else {
+        for conflict in three_way.conflicts.iter() {
+            warn!("Conflict: {}", conflict);
+        }
+    }
+    if let Some(write_changes_to) = &args.write_changes_to {
+        write_changes(three_way.a_to_b, write_changes_to, args);
+    }
+    if let Some(write_reverse_changes_to) = &args.write_reverse_changes_to {
+        write_changes(three_way.b_to_a, write_reverse_changes_to, args);
+    }
+    if let Some(write_conflicting_to) = &args.write_conflicting_to {
+        write_changes(three_way.conflicting, write_conflicting_to, args);
+    }
+}
+
+/// Reads a state from its file or from the output of its command.
+fn load_state(path: Option<&Path>, command: Option<&str>) -> FsState {
+    let state = match (path, command) {
+        (Some(path), _) => read_state(path),
+        (None, Some(command)) => read_state_from_command(command),
+        (None, None) => unreachable!("clap requires a state file or command"),
+    };
+    state.unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let args: Args = utils::config::parse();
+    utils::logging::init(&args.log, None);
+
+    let write_changes_to = args.write_changes_to.clone();
+    let write_report_to = args.write_report_to.clone();
+    let metrics = Metrics::new("fs_compare");
+    let started = Instant::now();
+
+    ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build_global()
+        .unwrap();
+
+    // Commands scanning remote trees run at the same time.
+    let (mut src_state, dst_state) = rayon::join(
+        || load_state(args.src_state.as_deref(), args.src_command.as_deref()),
+        || load_state(args.dst_state.as_deref(), args.dst_command.as_deref()),
+    );
+
+    let matching = pairing::NameMatching {
+        case_insensitive: args.case_insensitive,
+        normalization: args.normalization,
+    };
+
+    if let Some(base_state) = &args.base_state {
+        let (base_state, dst_base_state) = read_state(base_state)
+            .and_then(|base_state| {
+                let dst_base_state = args.dst_base_state.as_deref().map(read_state);
+                Ok((base_state, dst_base_state.transpose()?))
+            })
+            .unwrap_or_else(|err| {
+                error!("{}", err);
+                process::exit(1);
+            });
+        compare_three_way(
+            &args,
+            &metrics,
+            &base_state,
+            dst_base_state.as_ref().unwrap_or(&base_state),
+            &src_state,
+            &dst_state,
+            matching,
+        );
+        write_metrics(&args, &metrics, started);
+        return;
+    }
+
+    let (user_names, group_names) = match (
+        IdNames::read(args.src_passwd.as_deref(), args.dst_passwd.as_deref()),
+        IdNames::read(args.src_group.as_deref(), args.dst_group.as_deref()),
+    ) {
+        (Ok(user_names), Ok(group_names)) => (user_names, group_names),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+    let id_mapping = IdMapping::read(args.id_map.as_deref(), &user_names, &group_names)
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        });
+    let mut unmapped_ids: Vec<String> = Vec::new();
+    if args.id_map.is_some() || !id_mapping.is_empty() {
+        unmapped_ids = id_mapping.unmapped(&src_state.entries, &user_names, &group_names);
+        for unmapped in unmapped_ids.iter() {
+            warn!("Unmapped {}", unmapped);
+        }
+        id_mapping.apply(&mut src_state.entries);
+    }
+
+    let selection = pairing::Selection {
+        src_subtree: args.src_subtree.clone(),
+        dst_subtree: args.dst_subtree.clone(),
+        rewrites: args.rewrites.clone(),
+    };
+    let pairing = pairing::pair_entries(&src_state, &dst_state, matching, &selection)
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        });
+    let conflicts: Vec<String> = pairing
+        .conflicts
+        .iter()
+        .map(|conflict| conflict.describe(&pairing, &src_state, &dst_state))
+        .collect();
+    for conflict in conflicts.iter() {
+        warn!("Name collision: {}", conflict);
+    }
+    metrics.set(
+        "source_entries",
+        "Entries in the source state",
+        &[],
+        src_state.entries.len() as f64,
+    );
+    metrics.set(
+        "destination_entries",
+        "Entries in the destination state",
+        &[],
+        dst_state.entries.len() as f64,
+    );
+    metrics.set(
+        "name_collisions",
+        "Names matching several entries on the other side",
+        &[],
+        conflicts.len() as f64,
+    );
+
+    if let Some(write_report_to) = write_report_to {
+        let mut report = report::generate_report(
+            &src_state,
+            &dst_state,
+            &pairing,
+            args.report_depth,
+            args.report_top,
+        );
+        report.conflicts = conflicts;
+        report.unmapped_ids = unmapped_ids;
+        if let Err(err) = report::write_report(report, args.report_depth, &write_report_to) {
+            error!(
+                "Failed to write report to '{}'. Error : {}",
+                write_report_to.display(),
+                err
+            );
+            process::exit(1);
+        }
+    }
+
+    let Some(write_changes_to) = write_changes_to else {
+        write_metrics(&args, &metrics, started);
+        return;
+    };
+
+    let changed_fs_entries = changes(
+        pairing.unrelocated_pairs(),
+        &src_state,
+        &dst_state,
+        &|index| pairing.src_path(&src_state, index),
+        &|index| pairing.dst_path(&dst_state, index),
+    );
+    // rsync copies entries to the same path on the destination, so relocated directories are
+    // synced from their own diffs, with paths relative to them.
+    let relocated: Vec<RelocatedChanges> = pairing
+        .relocated
+        .iter()
+        .map(|relocated| RelocatedChanges {
+            src_dir: pairing.src_path(&src_state, relocated.src_dir),
+            dst_dir: pairing.dst_path(&dst_state, relocated.dst_dir),
+            // The first pair is the relocated directories themselves, the roots of the diff.
+            entries: changes(
+                &pairing.pairs[relocated.pairs.start + 1..relocated.pairs.end],
+                &src_state,
+                &dst_state,
+                &|index| src_state.path_below(index, relocated.src_dir),
+                &|index| dst_state.path_below(index, relocated.dst_dir),
+            ),
+        })
+        .collect();
 
-    let entries = ChangedFsEntries {
-        entries: changed_fs_entries,
+    let all_entries = || {
+        changed_fs_entries.iter().chain(
+            relocated
+                .iter()
+                .flat_map(|relocated| relocated.entries.iter()),
+        )
     };
-    {
-        let mut writer = BufWriter::new(File::create(write_changes_to).unwrap());
-        bincode::encode_into_std_write(&entries, &mut writer, bincode::config::standard()).unwrap();
+    let changed = all_entries().count();
+    let deleted = all_entries().filter(|entry| entry.is_deleted).count();
+    metrics.set(
+        "changed_entries",
+        "Entries to sync from the source to the destination",
+        &[],
+        (changed - deleted) as f64,
+    );
+    metrics.set(
+        "deleted_entries",
+        "Entries to delete from the destination",
+        &[],
+        deleted as f64,
+    );
+    write_changes(changed_fs_entries, &write_changes_to, &args);
+    if let Err(err) = write_relocated_changes(
+        &relocated,
+        &write_changes_to,
+        args.compression,
+        args.compression_level,
+    ) {
+        error!("{}", err);
+        process::exit(1);
     }
+    write_metrics(&args, &metrics, started);
 }
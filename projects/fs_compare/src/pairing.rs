@@ -0,0 +1,792 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    ops::Range,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use unicode_normalization::UnicodeNormalization;
+use utils::{
+    fs::escape_name,
+    state::{ChildIndex, FsState, ROOT},
+};
+
+/// Indexes of the entries found at the same path in the source and destination states.
+pub(crate) type EntryPair = (Option<u32>, Option<u32>);
+
+/// Unicode normalization form a destination file system stores names in.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+/// How file names are compared when matching source and destination entries.
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct NameMatching {
+    /// Names differing only by case match, as on a case-insensitive destination.
+    pub case_insensitive: bool,
+    /// Names differing only by their Unicode normalization match, as on a normalizing destination.
+    pub normalization: Option<Normalization>,
+}
+
+impl NameMatching {
+    fn is_exact(&self) -> bool {
+        !self.case_insensitive && self.normalization.is_none()
+    }
+
+    fn key<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        if self.is_exact() {
+            return Cow::Borrowed(name);
+        }
+        let mut key: Vec<u8> = Vec::with_capacity(name.len());
+        for chunk in name.as_bytes().utf8_chunks() {
+            let valid = if self.case_insensitive {
+                Cow::Owned(chunk.valid().to_lowercase())
+            } else {
+                Cow::Borrowed(chunk.valid())
+            };
+            match self.normalization {
+                Some(Normalization::Nfc) => key.extend(valid.nfc().collect::<String>().bytes()),
+                Some(Normalization::Nfd) => key.extend(valid.nfd().collect::<String>().bytes()),
+                None => key.extend_from_slice(valid.as_bytes()),
+            }
+            // Bytes that are not valid UTF-8 are kept as is.
+            key.extend_from_slice(chunk.invalid());
+        }
+        Cow::Owned(OsString::from_vec(key))
+    }
+}
+
+/// Entries of a directory whose names match each other, so only one of them can exist on the destination.
+///
+/// Only the `kept` entries (and their descendants) are paired, the others are left out of the comparison.
+#[derive(Debug)]
+pub(crate) struct Conflict {
+    pub src: Vec<u32>,
+    pub dst: Vec<u32>,
+    pub kept: EntryPair,
+}
+
+/// Directory of the source state paired with a directory of the destination state.
+type Relocation = (u32, u32);
+
+/// Pairs of a directory of the source state compared with a directory at another path in the
+/// destination state.
+pub(crate) struct Relocated {
+    pub src_dir: u32,
+    pub dst_dir: u32,
+    /// Range in [`Pairing::pairs`] of the pairs of the two directories and of their descendants.
+    pub pairs: Range<usize>,
+}
+
+pub(crate) struct Pairing {
+    /// Pairs of the compared directories, followed by the pairs of each relocated directory.
+    pub pairs: Vec<EntryPair>,
+    pub relocated: Vec<Relocated>,
+    pub conflicts: Vec<Conflict>,
+    /// Directory of the source state the paths are relative to, [`ROOT`] for the root.
+    pub src_root: u32,
+    /// Directory of the destination state the paths are relative to, [`ROOT`] for the root.
+    pub dst_root: u32,
+}
+
+impl Pairing {
+    pub(crate) fn src_path(&self, src_state: &FsState, index: u32) -> OsString {
+        src_state.path_below(index, self.src_root)
+    }
+
+    pub(crate) fn dst_path(&self, dst_state: &FsState, index: u32) -> OsString {
+        dst_state.path_below(index, self.dst_root)
+    }
+
+    /// Returns the pairs outside the relocated directories.
+    pub(crate) fn unrelocated_pairs(&self) -> &[EntryPair] {
+        let end = self
+            .relocated
+            .first()
+            .map_or(self.pairs.len(), |relocated| relocated.pairs.start);
+        &self.pairs[..end]
+    }
+}
+
+#[derive(Default)]
+struct Matched {
+    pairs: Vec<EntryPair>,
+    conflicts: Vec<Conflict>,
+}
+
+impl Matched {
+    fn extend(&mut self, other: Matched) {
+        self.pairs.extend(other.pairs);
+        self.conflicts.extend(other.conflicts);
+    }
+}
+
+struct Side<'a> {
+    state: &'a FsState,
+    child_index: ChildIndex,
+    /// Relocated directories, which are only paired with their relocation target.
+    relocated: HashSet<u32>,
+}
+
+impl<'a> Side<'a> {
+    fn new(state: &'a FsState) -> Side<'a> {
+        Side {
+            state,
+            child_index: state.child_index(),
+            relocated: HashSet::new(),
+        }
+    }
+
+    fn children(&self, dir: u32) -> impl Iterator<Item = u32> + '_ {
+        self.child_index
+            .children(dir)
+            .iter()
+            .copied()
+            .filter(|index| !self.relocated.contains(index))
+    }
+
+    /// Returns the index of the directory at `path` relative to the directory `base`.
+    fn find_dir(&self, base: u32, path: &Path) -> Option<u32> {
+        let mut current = base;
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => {
+                    current = self
+                        .child_index
+                        .children(current)
+                        .iter()
+                        .copied()
+                        .find(|index| self.name(*index) == name)?;
+                }
+                _ => return None,
+            }
+        }
+        if current == ROOT || self.is_dir(current) {
+            Some(current)
+        } else {
+            None
+        }
+    }
+
+    fn is_dir(&self, index: u32) -> bool {
+        self.state.entries[index as usize].is_dir
+    }
+
+    fn name(&self, index: u32) -> &OsStr {
+        self.state.entries[index as usize].name.as_os_str()
+    }
+
+    fn descendants(&self, dir: u32, out: &mut Vec<u32>) {
+        for child in self.children(dir) {
+            out.push(child);
+            if self.is_dir(child) {
+                self.descendants(child, out);
+            }
+        }
+    }
+}
+
+fn only_src(src: &Side, index: u32, pairs: &mut Vec<EntryPair>) {
+    let mut descendants = Vec::new();
+    src.descendants(index, &mut descendants);
+    pairs.extend(descendants.into_iter().map(|index| (Some(index), None)));
+}
+
+fn only_dst(dst: &Side, index: u32, pairs: &mut Vec<EntryPair>) {
+    let mut descendants = Vec::new();
+    dst.descendants(index, &mut descendants);
+    pairs.extend(descendants.into_iter().map(|index| (None, Some(index))));
+}
+
+/// Finds the children of two directories whose names match more than one other name, keeps one
+/// entry per side for each of them and returns the indexes of the entries left out.
+fn resolve_conflicts(
+    src: &Side,
+    dst: &Side,
+    matching: NameMatching,
+    src_dir: u32,
+    dst_dir: u32,
+    conflicts: &mut Vec<Conflict>,
+) -> (HashSet<u32>, HashSet<u32>) {
+    let mut groups: HashMap<Cow<OsStr>, (Vec<u32>, Vec<u32>)> = HashMap::new();
+    for index in src.children(src_dir) {
+        groups
+            .entry(matching.key(src.name(index)))
+            .or_default()
+            .0
+            .push(index);
+    }
+    for index in dst.children(dst_dir) {
+        groups
+            .entry(matching.key(dst.name(index)))
+            .or_default()
+            .1
+            .push(index);
+    }
+
+    let mut src_excluded: HashSet<u32> = HashSet::new();
+    let mut dst_excluded: HashSet<u32> = HashSet::new();
+    let mut found: Vec<Conflict> = Vec::new();
+    for (_, (mut src_indexes, mut dst_indexes)) in groups {
+        if src_indexes.len() <= 1 && dst_indexes.len() <= 1 {
+            continue;
+        }
+        src_indexes.sort_unstable_by_key(|index| src.name(*index));
+        dst_indexes.sort_unstable_by_key(|index| dst.name(*index));
+        // Prefer the spelling already present on the destination.
+        let kept_src = src_indexes
+            .iter()
+            .find(|src_index| {
+                dst_indexes
+                    .iter()
+                    .any(|dst_index| src.name(**src_index) == dst.name(*dst_index))
+            })
+            .or(src_indexes.first())
+            .copied();
+        let kept_dst = dst_indexes
+            .iter()
+            .find(|dst_index| kept_src.is_some_and(|kept| src.name(kept) == dst.name(**dst_index)))
+            .or(dst_indexes.first())
+            .copied();
+        src_excluded.extend(src_indexes.iter().filter(|index| Some(**index) != kept_src));
+        dst_excluded.extend(dst_indexes.iter().filter(|index| Some(**index) != kept_dst));
+        found.push(Conflict {
+            src: src_indexes,
+            dst: dst_indexes,
+            kept: (kept_src, kept_dst),
+        });
+    }
+    found.sort_unstable_by(|a, b| {
+        let first_name = |conflict: &Conflict| match conflict.src.first() {
+            Some(index) => src.name(*index).to_os_string(),
+            None => dst.name(conflict.dst[0]).to_os_string(),
+        };
+        first_name(a).cmp(&first_name(b))
+    });
+    conflicts.extend(found);
+    (src_excluded, dst_excluded)
+}
+
+fn pair_dirs(
+    src: &Side,
+    dst: &Side,
+    matching: NameMatching,
+    src_dir: u32,
+    dst_dir: u32,
+) -> Matched {
+    let mut pairing = Matched::default();
+    let (src_excluded, dst_excluded) = if matching.is_exact() {
+        (HashSet::new(), HashSet::new())
+    } else {
+        resolve_conflicts(src, dst, matching, src_dir, dst_dir, &mut pairing.conflicts)
+    };
+    let pairs = &mut pairing.pairs;
+    let mut dst_by_name: HashMap<Cow<OsStr>, u32> = dst
+        .children(dst_dir)
+        .filter(|index| !dst_excluded.contains(index))
+        .map(|index| (matching.key(dst.name(index)), index))
+        .collect();
+    let mut matched_dirs: Vec<(u32, u32)> = Vec::new();
+
+    for src_index in src.children(src_dir) {
+        if src_excluded.contains(&src_index) {
+            continue;
+        }
+        match dst_by_name.remove(&matching.key(src.name(src_index))) {
+            Some(dst_index) => {
+                pair_matched(src, dst, src_index, dst_index, pairs, &mut matched_dirs)
+            }
+            None => {
+                pairs.push((Some(src_index), None));
+                if src.is_dir(src_index) {
+                    only_src(src, src_index, pairs);
+                }
+            }
+        }
+    }
+    for dst_index in dst_by_name.into_values() {
+        pairs.push((None, Some(dst_index)));
+        if dst.is_dir(dst_index) {
+            only_dst(dst, dst_index, pairs);
+        }
+    }
+
+    pairing.extend(pair_nested(src, dst, matching, matched_dirs));
+    pairing
+}
+
+fn pair_matched(
+    src: &Side,
+    dst: &Side,
+    src_index: u32,
+    dst_index: u32,
+    pairs: &mut Vec<EntryPair>,
+    matched_dirs: &mut Vec<(u32, u32)>,
+) {
+    pairs.push((Some(src_index), Some(dst_index)));
+    match (src.is_dir(src_index), dst.is_dir(dst_index)) {
+        (true, true) => matched_dirs.push((src_index, dst_index)),
+        (true, false) => only_src(src, src_index, pairs),
+        (false, true) => only_dst(dst, dst_index, pairs),
+        (false, false) => {}
+    }
+}
+
+fn pair_nested(
+    src: &Side,
+    dst: &Side,
+    matching: NameMatching,
+    matched_dirs: Vec<(u32, u32)>,
+) -> Matched {
+    let nested: Vec<Matched> = matched_dirs
+        .par_iter()
+        .map(|(src_index, dst_index)| pair_dirs(src, dst, matching, *src_index, *dst_index))
+        .collect();
+    let mut pairing = Matched::default();
+    for nested_pairing in nested {
+        pairing.extend(nested_pairing);
+    }
+    pairing
+}
+
+/// Selects the directories compared in each state and the directories relocated between them.
+#[derive(Default)]
+pub(crate) struct Selection {
+    /// Directory of the source state to compare, relative to its root.
+    pub src_subtree: Option<PathBuf>,
+    /// Directory of the destination state to compare, relative to its root.
+    pub dst_subtree: Option<PathBuf>,
+    /// Source directories paired with destination directories at another path, both relative
+    /// to the compared directories.
+    pub rewrites: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Matches the entries of two states by path, walking both directory trees together.
+pub(crate) fn pair_entries(
+    src_state: &FsState,
+    dst_state: &FsState,
+    matching: NameMatching,
+    selection: &Selection,
+) -> Result<Pairing, String> {
+    let mut src = Side::new(src_state);
+    let mut dst = Side::new(dst_state);
+    let find_dir = |side: &Side, base: u32, path: &Path, state_name: &str| {
+        side.find_dir(base, path).ok_or_else(|| {
+            format!(
+                "Directory '{}' not found in the {} state",
+                path.display(),
+                state_name
+            )
+        })
+    };
+    let src_root = match &selection.src_subtree {
+        Some(path) => find_dir(&src, ROOT, path, "source")?,
+        None => ROOT,
+    };
+    let dst_root = match &selection.dst_subtree {
+        Some(path) => find_dir(&dst, ROOT, path, "destination")?,
+        None => ROOT,
+    };
+    let mut relocations: Vec<Relocation> = Vec::new();
+    for (src_path, dst_path) in selection.rewrites.iter() {
+        let src_dir = find_dir(&src, src_root, src_path, "source")?;
+        let dst_dir = find_dir(&dst, dst_root, dst_path, "destination")?;
+        if src_dir == src_root || dst_dir == dst_root {
+            return Err(format!(
+                "Cannot rewrite '{}' to '{}', use the subtree options to compare whole states",
+                src_path.display(),
+                dst_path.display()
+            ));
+        }
+        relocations.push((src_dir, dst_dir));
+    }
+    src.relocated
+        .extend(relocations.iter().map(|(src_dir, _)| *src_dir));
+    dst.relocated
+        .extend(relocations.iter().map(|(_, dst_dir)| *dst_dir));
+
+    let mut matched = pair_dirs(&src, &dst, matching, src_root, dst_root);
+    let mut relocated: Vec<Relocated> = Vec::new();
+    for (src_dir, dst_dir) in relocations {
+        let start = matched.pairs.len();
+        let mut matched_dirs: Vec<(u32, u32)> = Vec::new();
+        pair_matched(
+            &src,
+            &dst,
+            src_dir,
+            dst_dir,
+            &mut matched.pairs,
+            &mut matched_dirs,
+        );
+        matched.extend(pair_nested(&src, &dst, matching, matched_dirs));
+        relocated.push(Relocated {
+            src_dir,
+            dst_dir,
+            pairs: start..matched.pairs.len(),
+        });
+    }
+    Ok(Pairing {
+        pairs: matched.pairs,
+        relocated,
+        conflicts: matched.conflicts,
+        src_root,
+        dst_root,
+    })
+}
+
+impl Conflict {
+    /// Describes the conflicting entries by their full path and which of them are kept.
+    pub(crate) fn describe(
+        &self,
+        pairing: &Pairing,
+        src_state: &FsState,
+        dst_state: &FsState,
+    ) -> String {
+        let src_path = |index: u32| {
+            format!(
+                "'{}' (source)",
+                escape_name(&pairing.src_path(src_state, index))
+            )
+        };
+        let dst_path = |index: u32| {
+            format!(
+                "'{}' (destination)",
+                escape_name(&pairing.dst_path(dst_state, index))
+            )
+        };
+        let entries: Vec<String> = self
+            .src
+            .iter()
+            .map(|index| src_path(*index))
+            .chain(self.dst.iter().map(|index| dst_path(*index)))
+            .collect();
+        let kept: Vec<String> = self
+            .kept
+            .0
+            .map(src_path)
+            .into_iter()
+            .chain(self.kept.1.map(dst_path))
+            .collect();
+        format!("{}, kept {}", entries.join(", "), kept.join(" and "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::fs::FsEntry;
+
+    fn entry(name: &str, is_dir: bool) -> FsEntry {
+        FsEntry {
+            name: OsString::from(name),
+            owner: 0,
+            group: 0,
+            mode: if is_dir { 0o40755 } else { 0o100644 },
+            mtime: 1,
+            ctime: 0,
+            inode: 0,
+            size: 1,
+            is_dir,
+            is_file: !is_dir,
+            is_symlink: false,
+        }
+    }
+
+    /// Builds a state from paths, directories ending with a slash.
+    fn state(paths: &[&str]) -> FsState {
+        FsState::from_entries(
+            paths
+                .iter()
+                .map(|path| match path.strip_suffix('/') {
+                    Some(dir) => entry(dir, true),
+                    None => entry(path, false),
+                })
+                .collect(),
+        )
+    }
+
+    /// Full paths of the entries of `pairs`, sorted.
+    fn paths(pairs: &[EntryPair], src: &FsState, dst: &FsState) -> Vec<(String, String)> {
+        let path = |state: &FsState, index: Option<u32>| {
+            index.map_or(String::from("-"), |index| {
+                state.path(index).to_string_lossy().into_owned()
+            })
+        };
+        let mut paths: Vec<(String, String)> = pairs
+            .iter()
+            .map(|(src_index, dst_index)| (path(src, *src_index), path(dst, *dst_index)))
+            .collect();
+        paths.sort_unstable();
+        paths
+    }
+
+    fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(src, dst)| (src.to_string(), dst.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn pairs_entries_by_path() {
+        let src = state(&["d/", "d/a", "d/b", "f"]);
+        let dst = state(&["d/", "d/a", "d/c", "f/", "f/x"]);
+        let pairing =
+            pair_entries(&src, &dst, NameMatching::default(), &Selection::default()).unwrap();
+        assert_eq!(
+            paths(&pairing.pairs, &src, &dst),
+            owned(&[
+                ("-", "d/c"),
+                ("-", "f/x"),
+                ("d", "d"),
+                ("d/a", "d/a"),
+                ("d/b", "-"),
+                ("f", "f"),
+            ])
+        );
+        assert!(pairing.relocated.is_empty());
+    }
+
+    #[test]
+    fn selects_subtrees() {
+        let src = state(&[
+            "projects/",
+            "projects/a/",
+            "projects/a/x",
+            "projects/a/y",
+            "other",
+        ]);
+        let dst = state(&["x", "z"]);
+        let selection = Selection {
+            src_subtree: Some(PathBuf::from("projects/a")),
+            ..Selection::default()
+        };
+        let pairing = pair_entries(&src, &dst, NameMatching::default(), &selection).unwrap();
+        assert_eq!(
+            paths(&pairing.pairs, &src, &dst),
+            owned(&[("-", "z"), ("projects/a/x", "x"), ("projects/a/y", "-")])
+        );
+        // Paths are relative to the compared directories.
+        let (src_index, _) = pairing.pairs[0];
+        assert_eq!(pairing.src_path(&src, src_index.unwrap()), "x");
+
+        let selection = Selection {
+            src_subtree: Some(PathBuf::from("./projects/a/")),
+            dst_subtree: Some(PathBuf::from("missing")),
+            ..Selection::default()
+        };
+        assert!(pair_entries(&src, &dst, NameMatching::default(), &selection).is_err());
+        for subtree in ["other", "projects/b", "../projects"] {
+            let selection = Selection {
+                src_subtree: Some(PathBuf::from(subtree)),
+                ..Selection::default()
+            };
+            assert!(
+                pair_entries(&src, &dst, NameMatching::default(), &selection).is_err(),
+                "{}",
+                subtree
+            );
+        }
+    }
+
+    #[test]
+    fn pairs_relocated_directories() {
+        let src = state(&["home/", "home/u/", "home/u/f", "home/u/g", "etc/", "etc/c"]);
+        let dst = state(&[
+            "users/",
+            "users/u/",
+            "users/u/f",
+            "etc/",
+            "etc/c",
+            "home/",
+            "home/h",
+        ]);
+        let selection = Selection {
+            rewrites: vec![(PathBuf::from("home"), PathBuf::from("users"))],
+            ..Selection::default()
+        };
+        let pairing = pair_entries(&src, &dst, NameMatching::default(), &selection).unwrap();
+        // The relocated directories are left out of the comparison of the compared directories,
+        // the destination home only holds entries to delete.
+        assert_eq!(
+            paths(pairing.unrelocated_pairs(), &src, &dst),
+            owned(&[
+                ("-", "home"),
+                ("-", "home/h"),
+                ("etc", "etc"),
+                ("etc/c", "etc/c"),
+            ])
+        );
+        assert_eq!(pairing.relocated.len(), 1);
+        let relocated = &pairing.relocated[0];
+        assert_eq!(src.path(relocated.src_dir), "home");
+        assert_eq!(dst.path(relocated.dst_dir), "users");
+        assert_eq!(
+            paths(&pairing.pairs[relocated.pairs.clone()], &src, &dst),
+            owned(&[
+                ("home", "users"),
+                ("home/u", "users/u"),
+                ("home/u/f", "users/u/f"),
+                ("home/u/g", "-"),
+            ])
+        );
+    }
+
+    #[test]
+    fn relocates_below_subtrees() {
+        let src = state(&["a/", "a/old/", "a/old/f"]);
+        let dst = state(&["b/", "b/new/", "b/new/f"]);
+        let selection = Selection {
+            src_subtree: Some(PathBuf::from("a")),
+            dst_subtree: Some(PathBuf::from("b")),
+            rewrites: vec![(PathBuf::from("old"), PathBuf::from("new"))],
+        };
+        let pairing = pair_entries(&src, &dst, NameMatching::default(), &selection).unwrap();
+        assert!(pairing.unrelocated_pairs().is_empty());
+        let relocated = &pairing.relocated[0];
+        assert_eq!(pairing.src_path(&src, relocated.src_dir), "old");
+        assert_eq!(pairing.dst_path(&dst, relocated.dst_dir), "new");
+        assert_eq!(
+            paths(&pairing.pairs[relocated.pairs.clone()], &src, &dst),
+            owned(&[("a/old", "b/new"), ("a/old/f", "b/new/f")])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rewrites() {
+        let src = state(&["a/", "f"]);
+        let dst = state(&["b/"]);
+        for (from, to) in [(".", "b"), ("a", "."), ("missing", "b"), ("f", "b")] {
+            let selection = Selection {
+                rewrites: vec![(PathBuf::from(from), PathBuf::from(to))],
+                ..Selection::default()
+            };
+            assert!(
+                pair_entries(&src, &dst, NameMatching::default(), &selection).is_err(),
+                "{}={}",
+                from,
+                to
+            );
+        }
+    }
+
+    const CASE_INSENSITIVE: NameMatching = NameMatching {
+        case_insensitive: true,
+        normalization: None,
+    };
+
+    /// Paths of the entries of each side kept for each conflict.
+    fn kept(pairing: &Pairing, src: &FsState, dst: &FsState) -> Vec<(String, String)> {
+        pairing
+            .conflicts
+            .iter()
+            .map(|conflict| paths(&[conflict.kept], src, dst).remove(0))
+            .collect()
+    }
+
+    #[test]
+    fn matches_names_case_insensitively() {
+        let src = state(&["Docs/", "Docs/Readme", "x"]);
+        let dst = state(&["docs/", "docs/README", "X"]);
+        let pairing = pair_entries(&src, &dst, CASE_INSENSITIVE, &Selection::default()).unwrap();
+        assert_eq!(
+            paths(&pairing.pairs, &src, &dst),
+            owned(&[("Docs", "docs"), ("Docs/Readme", "docs/README"), ("x", "X")])
+        );
+        assert!(pairing.conflicts.is_empty());
+
+        let pairing =
+            pair_entries(&src, &dst, NameMatching::default(), &Selection::default()).unwrap();
+        assert!(pairing
+            .pairs
+            .iter()
+            .all(|pair| pair.0.is_none() || pair.1.is_none()));
+
+        // Bytes that are not valid UTF-8 are compared as they are.
+        let key = CASE_INSENSITIVE.key(OsStr::from_bytes(b"\xffAB\xfe"));
+        assert_eq!(key.as_bytes(), b"\xffab\xfe");
+    }
+
+    #[test]
+    fn reports_case_collisions() {
+        let src = state(&["d/", "d/a", "d/A", "d/b"]);
+        let dst = state(&["d/", "d/A"]);
+        let pairing = pair_entries(&src, &dst, CASE_INSENSITIVE, &Selection::default()).unwrap();
+        // The spelling already on the destination is kept, the other one is left out.
+        assert_eq!(
+            paths(&pairing.pairs, &src, &dst),
+            owned(&[("d", "d"), ("d/A", "d/A"), ("d/b", "-")])
+        );
+        assert_eq!(kept(&pairing, &src, &dst), owned(&[("d/A", "d/A")]));
+        let description = pairing.conflicts[0].describe(&pairing, &src, &dst);
+        assert!(description.contains("'d/a' (source)"), "{}", description);
+
+        // Names colliding on the destination too keep a single pair.
+        let src = state(&["b", "B"]);
+        let dst = state(&["b", "B"]);
+        let pairing = pair_entries(&src, &dst, CASE_INSENSITIVE, &Selection::default()).unwrap();
+        assert_eq!(paths(&pairing.pairs, &src, &dst), owned(&[("B", "B")]));
+        assert_eq!(pairing.conflicts[0].src.len(), 2);
+        assert_eq!(pairing.conflicts[0].dst.len(), 2);
+    }
+
+    #[test]
+    fn matches_names_across_normalizations() {
+        // "café" precomposed on the source, decomposed on the destination.
+        let src = state(&["caf\u{e9}/", "caf\u{e9}/f"]);
+        let dst = state(&["cafe\u{301}/", "cafe\u{301}/f"]);
+        for normalization in [Normalization::Nfc, Normalization::Nfd] {
+            let matching = NameMatching {
+                case_insensitive: false,
+                normalization: Some(normalization),
+            };
+            let pairing = pair_entries(&src, &dst, matching, &Selection::default()).unwrap();
+            assert_eq!(
+                paths(&pairing.pairs, &src, &dst),
+                owned(&[
+                    ("caf\u{e9}", "cafe\u{301}"),
+                    ("caf\u{e9}/f", "cafe\u{301}/f")
+                ])
+            );
+        }
+        let pairing =
+            pair_entries(&src, &dst, NameMatching::default(), &Selection::default()).unwrap();
+        assert_eq!(pairing.pairs.len(), 4);
+
+        let matching = NameMatching {
+            case_insensitive: true,
+            normalization: Some(Normalization::Nfd),
+        };
+        let src = state(&["CAF\u{c9}"]);
+        let pairing = pair_entries(&src, &dst, matching, &Selection::default()).unwrap();
+        assert!(paths(&pairing.pairs, &src, &dst)
+            .contains(&(String::from("CAF\u{c9}"), String::from("cafe\u{301}"))));
+    }
+
+    #[test]
+    fn reports_normalization_collisions() {
+        let src = state(&["caf\u{e9}", "cafe\u{301}", "other"]);
+        let dst = state(&["cafe\u{301}"]);
+        let matching = NameMatching {
+            case_insensitive: false,
+            normalization: Some(Normalization::Nfd),
+        };
+        let pairing = pair_entries(&src, &dst, matching, &Selection::default()).unwrap();
+        assert_eq!(
+            paths(&pairing.pairs, &src, &dst),
+            owned(&[("cafe\u{301}", "cafe\u{301}"), ("other", "-")])
+        );
+        assert_eq!(
+            kept(&pairing, &src, &dst),
+            owned(&[("cafe\u{301}", "cafe\u{301}")])
+        );
+        assert_eq!(pairing.conflicts[0].src.len(), 2);
+    }
+}
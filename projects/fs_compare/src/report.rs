@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use utils::{
+    fs::{escape_name, split_name, FsEntry},
+    state::FsState,
+};
+
+use crate::{has_changed, pairing::Pairing};
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Churn {
+    pub added: u64,
+    pub deleted: u64,
+    pub modified: u64,
+    pub growth: i64,
+}
+
+impl Churn {
+    fn merge(&mut self, other: &Churn) {
+        self.added += other.added;
+        self.deleted += other.deleted;
+        self.modified += other.modified;
+        self.growth += other.growth;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Report {
+    pub total: Churn,
+    pub subtrees: HashMap<String, Churn>,
+    pub owners: HashMap<u32, Churn>,
+    pub new_files: Vec<(OsString, u64)>,
+    /// Descriptions of the entries left out of the comparison because their names collide.
+    pub conflicts: Vec<String>,
+    /// Source owners and groups missing from the id mapping.
+    pub unmapped_ids: Vec<String>,
+}
+
+impl Report {
+    fn record(&mut self, subtree: String, owner: u32, churn: Churn) {
+        self.total.merge(&churn);
+        self.subtrees.entry(subtree).or_default().merge(&churn);
+        self.owners.entry(owner).or_default().merge(&churn);
+    }
+
+    fn merge(mut self, other: Report) -> Report {
+        self.total.merge(&other.total);
+        for (subtree, churn) in other.subtrees {
+            self.subtrees.entry(subtree).or_default().merge(&churn);
+        }
+        for (owner, churn) in other.owners {
+            self.owners.entry(owner).or_default().merge(&churn);
+        }
+        self.new_files.extend(other.new_files);
+        self
+    }
+}
+
+/// Returns the first `depth` components of the directory containing `name`,
+/// or "." for entries at the root of the state.
+fn subtree_of(name: &OsStr, depth: usize) -> String {
+    let (parent, _) = split_name(name);
+    if parent.is_empty() || depth == 0 {
+        return String::from(".");
+    }
+    let components: Vec<&[u8]> = parent
+        .as_bytes()
+        .split(|byte| *byte == b'/')
+        .take(depth)
+        .collect();
+    escape_name(OsStr::from_bytes(&components.join(&b'/')))
+}
+
+/// Size in bytes contributed by an entry, only regular files are accounted for.
+fn file_bytes(entry: &FsEntry) -> i64 {
+    if entry.is_file {
+        entry.size as i64
+    } else {
+        0
+    }
+}
+
+pub(crate) fn generate_report(
+    src_state: &FsState,
+    dst_state: &FsState,
+    pairing: &Pairing,
+    depth: usize,
+    top: usize,
+) -> Report {
+    let mut report = pairing
+        .pairs
+        .par_iter()
+        .fold(Report::default, |mut report, pair| {
+            match *pair {
+                (Some(src_index), None) => {
+                    let src_fsentry = &src_state.entries[src_index as usize];
+                    let name = pairing.src_path(src_state, src_index);
+                    report.record(
+                        subtree_of(&name, depth),
+                        src_fsentry.owner,
+                        Churn {
+                            added: 1,
+                            growth: file_bytes(src_fsentry),
+                            ..Default::default()
+                        },
+                    );
+                    if src_fsentry.is_file {
+                        report.new_files.push((name, src_fsentry.size));
+                    }
+                }
+                (None, Some(dst_index)) => {
+                    let dst_fsentry = &dst_state.entries[dst_index as usize];
+                    report.record(
+                        subtree_of(&pairing.dst_path(dst_state, dst_index), depth),
+                        dst_fsentry.owner,
+                        Churn {
+                            deleted: 1,
+                            growth: -file_bytes(dst_fsentry),
+                            ..Default::default()
+                        },
+                    );
+                }
+                (Some(src_index), Some(dst_index)) => {
+                    let src_fsentry = &src_state.entries[src_index as usize];
+                    let dst_fsentry = &dst_state.entries[dst_index as usize];
+                    if has_changed(src_fsentry, dst_fsentry) {
+                        report.record(
+                            subtree_of(&pairing.src_path(src_state, src_index), depth),
+                            src_fsentry.owner,
+                            Churn {
+                                modified: 1,
+                                growth: file_bytes(src_fsentry) - file_bytes(dst_fsentry),
+                                ..Default::default()
+                            },
+                        );
+                    }
+                }
+                (None, None) => {}
+            }
+            report
+        })
+        .reduce(Report::default, Report::merge);
+
+    report
+        .new_files
+        .sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    report.new_files.truncate(top);
+    report
+}
+
+fn write_churn_table<K: std::fmt::Display>(
+    writer: &mut impl Write,
+    heading: &str,
+    rows: Vec<(K, Churn)>,
+) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "{:<48} {:>12} {:>12} {:>12} {:>20}",
+        heading, "ADDED", "DELETED", "MODIFIED", "GROWTH (BYTES)"
+    )?;
+    for (key, churn) in rows {
+        writeln!(
+            writer,
+            "{:<48} {:>12} {:>12} {:>12} {:>+20}",
+            key.to_string(),
+            churn.added,
+            churn.deleted,
+            churn.modified,
+            churn.growth
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_report(report: Report, depth: usize, path: &PathBuf) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writeln!(writer, "Summary")?;
+    writeln!(writer, "  Added:    {:>12}", report.total.added)?;
+    writeln!(writer, "  Deleted:  {:>12}", report.total.deleted)?;
+    writeln!(writer, "  Modified: {:>12}", report.total.modified)?;
+    writeln!(writer, "  Growth:   {:>+12} bytes", report.total.growth)?;
+    writeln!(writer)?;
+
+    let mut subtrees = report
+        .subtrees
+        .into_iter()
+        .collect::<Vec<(String, Churn)>>();
+    subtrees.sort_unstable_by(|a, b| {
+        b.1.growth
+            .unsigned_abs()
+            .cmp(&a.1.growth.unsigned_abs())
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    write_churn_table(
+        &mut writer,
+        format!("DIRECTORY (DEPTH {})", depth).as_str(),
+        subtrees,
+    )?;
+    writeln!(writer)?;
+
+    let mut owners = report.owners.into_iter().collect::<Vec<(u32, Churn)>>();
+    owners.sort_unstable_by(|a, b| {
+        b.1.growth
+            .unsigned_abs()
+            .cmp(&a.1.growth.unsigned_abs())
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    write_churn_table(&mut writer, "OWNER", owners)?;
+    writeln!(writer)?;
+
+    writeln!(writer, "{:<48} {:>20}", "BIGGEST NEW FILES", "SIZE (BYTES)")?;
+    for (name, size) in report.new_files {
+        writeln!(writer, "{:<48} {:>20}", escape_name(&name), size)?;
+    }
+
+    if !report.conflicts.is_empty() {
+        writeln!(writer)?;
+        writeln!(writer, "NAME COLLISIONS")?;
+        for conflict in report.conflicts {
+            writeln!(writer, "{}", conflict)?;
+        }
+    }
+
+    if !report.unmapped_ids.is_empty() {
+        writeln!(writer)?;
+        writeln!(writer, "UNMAPPED IDS")?;
+        for unmapped in report.unmapped_ids {
+            writeln!(writer, "{}", unmapped)?;
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pairing::{pair_entries, NameMatching, Selection};
+
+    fn file(name: &str, owner: u32, size: u64) -> FsEntry {
+        FsEntry {
+            name: OsString::from(name),
+            owner,
+            group: 0,
+            mode: 0o100644,
+            mtime: 1,
+            ctime: 0,
+            inode: 0,
+            size,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+        }
+    }
+
+    fn dir(name: &str) -> FsEntry {
+        FsEntry {
+            mode: 0o40755,
+            size: 4096,
+            is_dir: true,
+            is_file: false,
+            ..file(name, 0, 0)
+        }
+    }
+
+    fn report(src: Vec<FsEntry>, dst: Vec<FsEntry>, depth: usize, top: usize) -> Report {
+        let src_state = FsState::from_entries(src);
+        let dst_state = FsState::from_entries(dst);
+        let pairing = pair_entries(
+            &src_state,
+            &dst_state,
+            NameMatching::default(),
+            &Selection::default(),
+        )
+        .unwrap();
+        generate_report(&src_state, &dst_state, &pairing, depth, top)
+    }
+
+    fn churn(churn: Option<&Churn>) -> (u64, u64, u64, i64) {
+        let churn = churn.unwrap();
+        (churn.added, churn.deleted, churn.modified, churn.growth)
+    }
+
+    #[test]
+    fn finds_subtrees() {
+        assert_eq!(subtree_of(OsStr::new("f"), 2), ".");
+        assert_eq!(subtree_of(OsStr::new("a/f"), 0), ".");
+        assert_eq!(subtree_of(OsStr::new("a/f"), 2), "a");
+        assert_eq!(subtree_of(OsStr::new("a/b/c/f"), 2), "a/b");
+    }
+
+    #[test]
+    fn sums_growth_and_churn() {
+        let src = vec![
+            dir("a"),
+            dir("a/b"),
+            file("a/b/new", 1, 100),
+            file("a/grown", 1, 30),
+            file("a/same", 2, 5),
+            dir("c"),
+            file("c/big", 2, 1000),
+        ];
+        let dst = vec![
+            dir("a"),
+            file("a/grown", 1, 10),
+            file("a/same", 2, 5),
+            file("a/gone", 2, 50),
+            file("top", 3, 7),
+        ];
+        let report = report(src, dst, 1, 10);
+        assert_eq!(
+            churn(Some(&report.total)),
+            (4, 2, 1, 100 + 20 + 1000 - 50 - 7)
+        );
+        assert_eq!(churn(report.subtrees.get("a")), (2, 1, 1, 100 + 20 - 50));
+        assert_eq!(churn(report.subtrees.get(".")), (1, 1, 0, -7));
+        assert_eq!(churn(report.subtrees.get("c")), (1, 0, 0, 1000));
+        assert_eq!(churn(report.owners.get(&1)), (1, 0, 1, 120));
+        assert_eq!(churn(report.owners.get(&2)), (1, 1, 0, 950));
+        assert_eq!(churn(report.owners.get(&3)), (0, 1, 0, -7));
+        // Directories are counted, but their sizes are not.
+        assert_eq!(churn(report.owners.get(&0)), (2, 0, 0, 0));
+    }
+
+    #[test]
+    fn keeps_the_biggest_new_files() {
+        let src = vec![
+            file("small", 0, 1),
+            file("big", 0, 100),
+            file("b", 0, 10),
+            file("a", 0, 10),
+        ];
+        let report = report(src, Vec::new(), 1, 3);
+        let new_files: Vec<(&str, u64)> = report
+            .new_files
+            .iter()
+            .map(|(name, size)| (name.to_str().unwrap(), *size))
+            .collect();
+        assert_eq!(new_files, [("big", 100), ("a", 10), ("b", 10)]);
+    }
+
+    #[test]
+    fn writes_reports() {
+        let src = vec![dir("a"), file("a/new", 0, 100), file("shrunk", 1, 1)];
+        let dst = vec![file("shrunk", 1, 11), file("gone", 1, 5)];
+        let mut report = report(src, dst, 1, 10);
+        report.unmapped_ids.push(String::from("user 1"));
+
+        let path =
+            std::env::temp_dir().join(format!("fs_tools_report_{}_report.txt", std::process::id()));
+        write_report(report, 1, &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = written.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines[..6],
+            [
+                "Summary",
+                "  Added:               2",
+                "  Deleted:             1",
+                "  Modified:            1",
+                "  Growth:            +85 bytes",
+                ""
+            ]
+        );
+        // Directories and owners are sorted by the size of their growth, either way.
+        let rows = |heading: &str| -> Vec<String> {
+            let start = lines
+                .iter()
+                .position(|line| line.starts_with(heading))
+                .unwrap();
+            lines[start + 1..]
+                .iter()
+                .take_while(|line| !line.is_empty())
+                .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+                .collect()
+        };
+        assert_eq!(rows("DIRECTORY (DEPTH 1)"), ["a 1 0 0 +100", ". 1 1 1 -15"]);
+        assert_eq!(rows("OWNER"), ["0 2 0 0 +100", "1 0 1 1 -15"]);
+        assert_eq!(rows("BIGGEST NEW FILES"), ["a/new 100"]);
+        assert_eq!(rows("UNMAPPED IDS"), ["user 1"]);
+        assert!(!written.contains("NAME COLLISIONS"));
+    }
+}
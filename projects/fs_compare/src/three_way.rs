@@ -0,0 +1,541 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    ptr,
+};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use utils::{
+    fs::{escape_name, ChangedFsEntry, FsEntry},
+    state::{FsState, ROOT},
+};
+
+use crate::pairing::{pair_entries, EntryPair, NameMatching, Selection};
+
+/// Change of an entry on one side since the base state.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Change {
+    Unchanged,
+    Added,
+    Deleted,
+    Modified,
+}
+
+impl Change {
+    fn label(&self) -> &'static str {
+        match self {
+            Change::Unchanged => "unchanged",
+            Change::Added => "added",
+            Change::Deleted => "deleted",
+            Change::Modified => "modified",
+        }
+    }
+}
+
+/// Returns true when the contents or attributes of an entry differ between two states.
+///
+/// Unlike [`crate::has_changed`] inodes are not compared, as the base state may have been taken on
+/// one side only.
+fn has_diverged(entry: &FsEntry, other: &FsEntry) -> bool {
+    entry.owner != other.owner
+        || entry.group != other.group
+        || entry.mode != other.mode
+        || entry.mtime != other.mtime
+        || entry.size != other.size
+        || entry.is_dir != other.is_dir
+        || entry.is_symlink != other.is_symlink
+        || entry.is_file != other.is_file
+}
+
+fn change(base: Option<&FsEntry>, side: Option<&FsEntry>) -> Change {
+    match (base, side) {
+        (None, None) => Change::Unchanged,
+        (None, Some(_)) => Change::Added,
+        (Some(_), None) => Change::Deleted,
+        (Some(base), Some(side)) if has_diverged(base, side) => Change::Modified,
+        (Some(_), Some(_)) => Change::Unchanged,
+    }
+}
+
+/// Returns true when both sides ended up with the same entry, directories are considered equal
+/// as their modification times change with their contents.
+fn has_converged(a: Option<&FsEntry>, b: Option<&FsEntry>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => (a.is_dir && b.is_dir) || !has_diverged(a, b),
+        _ => false,
+    }
+}
+
+enum Classified {
+    Unchanged,
+    ChangedOnA(ChangedFsEntry),
+    ChangedOnB(ChangedFsEntry),
+    Conflict(String, ChangedFsEntry),
+}
+
+/// Result of a three-way comparison of two sides A and B with their base states.
+#[derive(Default)]
+pub(crate) struct ThreeWay {
+    pub unchanged: usize,
+    /// Changes made on A since the base state, to apply to B.
+    pub a_to_b: Vec<ChangedFsEntry>,
+    /// Changes made on B since the base state, to apply to A.
+    pub b_to_a: Vec<ChangedFsEntry>,
+    /// Descriptions of the paths changed differently on both sides, left out of both differences.
+    pub conflicts: Vec<String>,
+    /// Entries changed differently on both sides, named as on A when present there.
+    pub conflicting: Vec<ChangedFsEntry>,
+}
+
+/// Returns the index in `base_state` of the entry at the same path as each entry of `side_state`.
+fn base_indexes(
+    side_state: &FsState,
+    base_state: &FsState,
+    matching: NameMatching,
+) -> Result<Vec<Option<u32>>, String> {
+    let pairing = pair_entries(side_state, base_state, matching, &Selection::default())?;
+    let mut indexes: Vec<Option<u32>> = vec![None; side_state.len()];
+    for pair in pairing.pairs {
+        if let (Some(side_index), Some(base_index)) = pair {
+            indexes[side_index as usize] = Some(base_index);
+        }
+    }
+    Ok(indexes)
+}
+
+/// Base state of one side, with the index of the entry at the same path as each entry of A and B.
+struct Base<'a> {
+    state: &'a FsState,
+    of_a: Vec<Option<u32>>,
+    of_b: Vec<Option<u32>>,
+}
+
+impl<'a> Base<'a> {
+    fn new(
+        state: &'a FsState,
+        a_state: &FsState,
+        b_state: &FsState,
+        matching: NameMatching,
+    ) -> Result<Base<'a>, String> {
+        Ok(Base {
+            state,
+            of_a: base_indexes(a_state, state, matching)?,
+            of_b: base_indexes(b_state, state, matching)?,
+        })
+    }
+
+    /// Returns the base entry of a pair of entries of A and B, looked up from either side as
+    /// the entry may have been deleted on one of them.
+    fn entry(&self, a_index: Option<u32>, b_index: Option<u32>) -> Option<&'a FsEntry> {
+        a_index
+            .and_then(|index| self.of_a[index as usize])
+            .or_else(|| b_index.and_then(|index| self.of_b[index as usize]))
+            .map(|index| &self.state.entries[index as usize])
+    }
+}
+
+fn changed_entry(state: &FsState, index: u32, is_deleted: bool) -> ChangedFsEntry {
+    let fsentry = &state.entries[index as usize];
+    ChangedFsEntry {
+        name: state.path(index),
+        is_deleted,
+        is_dir: fsentry.is_dir,
+        is_file: fsentry.is_file,
+        is_symlink: fsentry.is_symlink,
+    }
+}
+
+fn conflict(entry: ChangedFsEntry, a_label: &str, b_label: &str) -> Classified {
+    let description = format!(
+        "{}\tA: {}\tB: {}",
+        escape_name(&entry.name),
+        a_label,
+        b_label
+    );
+    Classified::Conflict(description, entry)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    A,
+    B,
+}
+
+/// Turns the deletions of directories on `deleted_on` into conflicts when an entry below them
+/// changed on the other side, as removing them would remove that change too.
+fn keep_changed_directories(
+    pairs: &[EntryPair],
+    classified: &mut [Classified],
+    a_state: &FsState,
+    b_state: &FsState,
+    deleted_on: Side,
+) {
+    // The deleted directories are only present on the other side, and so are the changes below
+    // them.
+    let (other_state, other_index): (&FsState, fn(&EntryPair) -> Option<u32>) = match deleted_on {
+        Side::A => (b_state, |pair| pair.1),
+        Side::B => (a_state, |pair| pair.0),
+    };
+    let mut deleted_dirs: Vec<Option<usize>> = vec![None; other_state.len()];
+    let mut any_deleted = false;
+    for (position, (pair, class)) in pairs.iter().zip(classified.iter()).enumerate() {
+        let deletion = match (deleted_on, class) {
+            (Side::A, Classified::ChangedOnA(entry)) | (Side::B, Classified::ChangedOnB(entry)) => {
+                entry.is_deleted && entry.is_dir
+            }
+            _ => false,
+        };
+        if let (true, Some(index)) = (deletion, other_index(pair)) {
+            deleted_dirs[index as usize] = Some(position);
+            any_deleted = true;
+        }
+    }
+    if !any_deleted {
+        return;
+    }
+    let mut kept: Vec<usize> = Vec::new();
+    for (pair, class) in pairs.iter().zip(classified.iter()) {
+        let changed_on_other = matches!(
+            (deleted_on, class),
+            (_, Classified::Conflict(..))
+                | (Side::A, Classified::ChangedOnB(_))
+                | (Side::B, Classified::ChangedOnA(_))
+        );
+        let Some(index) = other_index(pair).filter(|_| changed_on_other) else {
+            continue;
+        };
+        let mut parent = other_state.parents[index as usize];
+        while parent != ROOT {
+            if let Some(position) = deleted_dirs[parent as usize].take() {
+                kept.push(position);
+            }
+            parent = other_state.parents[parent as usize];
+        }
+    }
+    for position in kept {
+        let Some(index) = other_index(&pairs[position]) else {
+            continue;
+        };
+        let entry = changed_entry(other_state, index, false);
+        classified[position] = match deleted_on {
+            Side::A => conflict(entry, "deleted", "modified below"),
+            Side::B => conflict(entry, "modified below", "deleted"),
+        };
+    }
+}
+
+/// Classifies every path of the sides `a_state` and `b_state` as unchanged, changed on one side
+/// or changed on both sides since their base states. `b_base_state` is the state B had after the
+/// last sync, so the differences B keeps from A, like mapped owners, are not seen as changes. It is
+/// `a_base_state` again when only the state of A was recorded.
+pub(crate) fn compare(
+    a_base_state: &FsState,
+    b_base_state: &FsState,
+    a_state: &FsState,
+    b_state: &FsState,
+    matching: NameMatching,
+) -> Result<ThreeWay, String> {
+    let a_base = Base::new(a_base_state, a_state, b_state, matching)?;
+    let b_base = match ptr::eq(a_base_state, b_base_state) {
+        true => None,
+        false => Some(Base::new(b_base_state, a_state, b_state, matching)?),
+    };
+    let b_base = b_base.as_ref().unwrap_or(&a_base);
+    let pairing = pair_entries(a_state, b_state, matching, &Selection::default())?;
+
+    let mut classified: Vec<Classified> = pairing
+        .pairs
+        .par_iter()
+        .map(|(a_index, b_index)| {
+            let a = a_index.map(|index| &a_state.entries[index as usize]);
+            let b = b_index.map(|index| &b_state.entries[index as usize]);
+            // Either the entry from one side replaces the other, or it is deleted there.
+            let apply = |from: (&FsState, Option<u32>), to: (&FsState, Option<u32>)| match from {
+                (state, Some(index)) => changed_entry(state, index, false),
+                (_, None) => changed_entry(to.0, to.1.unwrap(), true),
+            };
+            match (
+                change(a_base.entry(*a_index, *b_index), a),
+                change(b_base.entry(*a_index, *b_index), b),
+            ) {
+                (Change::Unchanged, Change::Unchanged) => Classified::Unchanged,
+                (_, Change::Unchanged) => {
+                    Classified::ChangedOnA(apply((a_state, *a_index), (b_state, *b_index)))
+                }
+                (Change::Unchanged, _) => {
+                    Classified::ChangedOnB(apply((b_state, *b_index), (a_state, *a_index)))
+                }
+                _ if has_converged(a, b) => Classified::Unchanged,
+                (a_change, b_change) => {
+                    let entry = match (a_index, b_index) {
+                        (Some(index), _) => changed_entry(a_state, *index, false),
+                        (None, Some(index)) => changed_entry(b_state, *index, false),
+                        (None, None) => unreachable!(),
+                    };
+                    conflict(entry, a_change.label(), b_change.label())
+                }
+            }
+        })
+        .collect();
+
+    keep_changed_directories(&pairing.pairs, &mut classified, a_state, b_state, Side::A);
+    keep_changed_directories(&pairing.pairs, &mut classified, a_state, b_state, Side::B);
+
+    let mut three_way = ThreeWay::default();
+    for class in classified {
+        match class {
+            Classified::Unchanged => three_way.unchanged += 1,
+            Classified::ChangedOnA(entry) => three_way.a_to_b.push(entry),
+            Classified::ChangedOnB(entry) => three_way.b_to_a.push(entry),
+            Classified::Conflict(conflict, entry) => {
+                three_way.conflicts.push(conflict);
+                three_way.conflicting.push(entry);
+            }
+        }
+    }
+    three_way
+        .conflicts
+        .extend(pairing.conflicts.iter().map(|conflict| {
+            format!(
+                "Name collision: {}",
+                conflict.describe(&pairing, a_state, b_state)
+            )
+        }));
+    three_way.conflicts.sort_unstable();
+    Ok(three_way)
+}
+
+pub(crate) fn write_conflicts(conflicts: &[String], path: &PathBuf) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for conflict in conflicts {
+        writeln!(writer, "{}", conflict)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn file(name: &str, mtime: i64) -> FsEntry {
+        FsEntry {
+            name: OsString::from(name),
+            owner: 0,
+            group: 0,
+            mode: 0o100644,
+            mtime,
+            ctime: 0,
+            inode: 0,
+            size: 1,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+        }
+    }
+
+    fn dir(name: &str, mtime: i64) -> FsEntry {
+        FsEntry {
+            mode: 0o40755,
+            is_dir: true,
+            is_file: false,
+            ..file(name, mtime)
+        }
+    }
+
+    fn state(entries: &[FsEntry]) -> FsState {
+        FsState::from_entries(entries.to_vec())
+    }
+
+    fn names(changes: &[ChangedFsEntry]) -> Vec<(&str, bool)> {
+        changes
+            .iter()
+            .map(|change| (change.name.to_str().unwrap(), change.is_deleted))
+            .collect()
+    }
+
+    fn compare_states(base: &[FsEntry], a: &[FsEntry], b: &[FsEntry]) -> ThreeWay {
+        let base = state(base);
+        compare(&base, &base, &state(a), &state(b), NameMatching::default()).unwrap()
+    }
+
+    #[test]
+    fn classifies_one_sided_changes() {
+        let base = [
+            dir("d", 1),
+            file("d/kept", 1),
+            file("d/edited", 1),
+            file("d/gone", 1),
+        ];
+        let a = [
+            dir("d", 1),
+            file("d/kept", 1),
+            file("d/edited", 2),
+            file("d/gone", 1),
+        ];
+        let b = [
+            dir("d", 2),
+            file("d/kept", 1),
+            file("d/edited", 1),
+            file("d/new", 2),
+        ];
+        let three_way = compare_states(&base, &a, &b);
+        assert_eq!(names(&three_way.a_to_b), [("d/edited", false)]);
+        assert_eq!(
+            names(&three_way.b_to_a),
+            [("d", false), ("d/gone", true), ("d/new", false)]
+        );
+        assert!(three_way.conflicts.is_empty());
+        assert_eq!(three_way.unchanged, 1);
+    }
+
+    #[test]
+    fn skips_converged_changes() {
+        let base = [dir("d", 1), file("d/same", 1), file("d/gone", 1)];
+        let a = [dir("d", 2), file("d/same", 2), file("d/new", 2)];
+        let b = [dir("d", 3), file("d/same", 2), file("d/new", 2)];
+        let three_way = compare_states(&base, &a, &b);
+        assert!(three_way.a_to_b.is_empty());
+        assert!(three_way.b_to_a.is_empty());
+        assert!(three_way.conflicts.is_empty());
+        // Entries deleted on both sides are in neither of them.
+        assert_eq!(three_way.unchanged, 3);
+    }
+
+    #[test]
+    fn reports_conflicts() {
+        let base = [file("both", 1), file("removed", 1)];
+        let a = [file("both", 2), file("added", 2)];
+        let b = [file("both", 3), file("added", 3), file("removed", 2)];
+        let three_way = compare_states(&base, &a, &b);
+        assert!(three_way.a_to_b.is_empty());
+        assert!(three_way.b_to_a.is_empty());
+        assert_eq!(
+            three_way.conflicts,
+            [
+                "added\tA: added\tB: added",
+                "both\tA: modified\tB: modified",
+                "removed\tA: deleted\tB: modified",
+            ]
+        );
+        // Named as on B when deleted on A.
+        let mut conflicting = names(&three_way.conflicting);
+        conflicting.sort_unstable();
+        assert_eq!(
+            conflicting,
+            [("added", false), ("both", false), ("removed", false)]
+        );
+    }
+
+    #[test]
+    fn keeps_deleted_directories_changed_below() {
+        // d is deleted on A while d/sub/f is edited in place on B, leaving the mtimes of its
+        // parents as they were. Deleting d from B would lose that edit.
+        let base = [
+            dir("d", 1),
+            file("d/g", 1),
+            dir("d/sub", 1),
+            file("d/sub/f", 1),
+            dir("e", 1),
+            file("e/h", 1),
+        ];
+        let a = [dir("e", 1), file("e/h", 1)];
+        let b = [
+            dir("d", 1),
+            file("d/g", 1),
+            dir("d/sub", 1),
+            file("d/sub/f", 2),
+            dir("e", 1),
+            file("e/h", 1),
+        ];
+        let three_way = compare_states(&base, &a, &b);
+        assert_eq!(names(&three_way.a_to_b), [("d/g", true)]);
+        assert!(three_way.b_to_a.is_empty());
+        assert_eq!(
+            three_way.conflicts,
+            [
+                "d\tA: deleted\tB: modified below",
+                "d/sub\tA: deleted\tB: modified below",
+                "d/sub/f\tA: deleted\tB: modified",
+            ]
+        );
+
+        // The same with the sides swapped, with a file added below the directory on A.
+        let a = [
+            dir("d", 1),
+            file("d/g", 1),
+            dir("d/sub", 1),
+            file("d/sub/f", 1),
+            file("d/sub/new", 2),
+            dir("e", 1),
+            file("e/h", 1),
+        ];
+        let b = [dir("e", 1)];
+        let three_way = compare_states(&base, &a, &b);
+        assert_eq!(names(&three_way.a_to_b), [("d/sub/new", false)]);
+        let mut b_to_a = names(&three_way.b_to_a);
+        b_to_a.sort_unstable();
+        assert_eq!(b_to_a, [("d/g", true), ("d/sub/f", true), ("e/h", true)]);
+        assert_eq!(
+            three_way.conflicts,
+            [
+                "d\tA: modified below\tB: deleted",
+                "d/sub\tA: modified below\tB: deleted",
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_each_side_with_its_base() {
+        let owned = |entry: FsEntry| FsEntry {
+            owner: 1000,
+            ..entry
+        };
+        let a_base = [dir("d", 1), file("d/kept", 1), file("d/edited", 1)];
+        let b_base = a_base.clone().map(owned);
+        let a = [dir("d", 1), file("d/kept", 1), file("d/edited", 1)];
+        let b = [dir("d", 1), file("d/kept", 1), file("d/edited", 2)].map(owned);
+
+        let a_base = state(&a_base);
+        let three_way = compare(
+            &a_base,
+            &state(&b_base),
+            &state(&a),
+            &state(&b),
+            NameMatching::default(),
+        )
+        .unwrap();
+        assert_eq!(three_way.unchanged, 2);
+        assert!(three_way.a_to_b.is_empty());
+        assert_eq!(names(&three_way.b_to_a), [("d/edited", false)]);
+
+        // Compared with the base of A, the owners B keeps different look changed.
+        let three_way = compare(
+            &a_base,
+            &a_base,
+            &state(&a),
+            &state(&b),
+            NameMatching::default(),
+        )
+        .unwrap();
+        assert_eq!(three_way.b_to_a.len(), 3);
+    }
+
+    #[test]
+    fn matches_names_with_the_name_matching() {
+        let base = [file("Readme", 1)];
+        let a = [file("readme", 1)];
+        let b = [file("README", 2)];
+        let matching = NameMatching {
+            case_insensitive: true,
+            normalization: None,
+        };
+        let base = state(&base);
+        let three_way = compare(&base, &base, &state(&a), &state(&b), matching).unwrap();
+        assert_eq!(names(&three_way.b_to_a), [("README", false)]);
+        assert!(three_way.a_to_b.is_empty());
+        assert!(three_way.conflicts.is_empty());
+    }
+}
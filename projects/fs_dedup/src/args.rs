@@ -0,0 +1,114 @@
+use clap::{Parser, ValueEnum};
+use std::{num::NonZeroUsize, path::PathBuf};
+use utils::arg_parsers::{
+    check_if_directory_exists, check_if_file_exists, check_if_parent_path_exists,
+};
+use utils::logging::LogArgs;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LinkMode {
+    Hardlink,
+    Reflink,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    author,
+    version,
+    about,
+    help_template = "{before-help}{name} {version}
+
+Author: {author}
+
+{about-with-newline}
+{usage-heading} {usage}
+
+{all-args}{after-help}
+"
+)]
+pub(crate) struct Args {
+    #[arg(
+        id = "filesystem state file",
+        long = "state",
+        short = 's',
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the filesystem state file to find duplicate candidates in"
+    )]
+    pub state: PathBuf,
+    #[arg(
+        id = "path the state was generated from",
+        long = "path",
+        short = 'p',
+        value_parser = check_if_directory_exists(),
+        help = "",
+        long_help = "Path to the directory the filesystem state file was generated from, used to read file contents"
+    )]
+    pub path: PathBuf,
+    #[arg(
+        id = "threads",
+        long,
+        short = 't',
+        help = "",
+        long_help = "Number of threads to use, defaults to CPU count"
+    )]
+    pub threads: Option<NonZeroUsize>,
+    #[arg(
+        id = "minimum size",
+        long = "min-size",
+        default_value = "1",
+        help = "",
+        long_help = "Skip files smaller than this many bytes"
+    )]
+    pub min_size: u64,
+    #[arg(
+        id = "partial hash size",
+        long = "partial-hash-size",
+        default_value = "65536",
+        help = "",
+        long_help = "Number of leading bytes hashed to discard candidates before hashing full contents"
+    )]
+    pub partial_hash_size: u64,
+    #[arg(
+        id = "write report to file",
+        long = "output",
+        short = 'o',
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the duplicate groups to, defaults to standard output"
+    )]
+    pub write_report_to: Option<PathBuf>,
+    #[arg(
+        id = "link duplicates",
+        long = "link",
+        value_enum,
+        help = "",
+        long_help = "Replace duplicates with links to the first file of each group, only lists the planned replacements unless --apply is given. Hardlinks skip duplicates whose owner, group or mode differ from the first file"
+    )]
+    pub link: Option<LinkMode>,
+    #[arg(
+        id = "apply",
+        long = "apply",
+        requires = "link duplicates",
+        help = "",
+        long_help = "Actually replace duplicates, each file is compared byte by byte with the kept file right before it is replaced"
+    )]
+    pub apply: bool,
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+impl Args {
+    pub fn threads(&self) -> usize {
+        let cpus = num_cpus::get();
+        self.threads
+            .unwrap_or_else(|| {
+                if cfg!(target_vendor = "apple") {
+                    NonZeroUsize::new(cpus).unwrap()
+                } else {
+                    std::thread::available_parallelism().unwrap_or(NonZeroUsize::new(cpus).unwrap())
+                }
+            })
+            .get()
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    os::unix::fs::{fchown, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::args::LinkMode;
+
+/// Compares two files byte by byte.
+fn contents_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut reader_a = BufReader::new(File::open(a)?);
+    let mut reader_b = BufReader::new(File::open(b)?);
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let read_a = read_full(&mut reader_a, &mut buf_a)?;
+        let read_b = read_full(&mut reader_b, &mut buf_b)?;
+        if read_a != read_b || buf_a[..read_a] != buf_b[..read_b] {
+            return Ok(false);
+        }
+        if read_a == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let src_file = File::open(src)?;
+    let dst_file = File::options().write(true).create_new(true).open(dst)?;
+    let result = unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        let _ = fs::remove_file(dst);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+/// Result of [`replace_with_link`].
+#[derive(Debug, PartialEq)]
+pub(crate) enum Replaced {
+    Linked,
+    /// A hardlink would change the owner, group or mode of the duplicate, it is left as is.
+    AttributesDiffer,
+}
+
+/// Replaces `duplicate` with a link to `keep`.
+///
+/// The duplicate is re-checked against `keep` right before replacing it, and the
+/// link is created next to it and renamed over it so the duplicate is never missing.
+/// The temporary name must be free, an existing file with that name is never replaced.
+pub(crate) fn replace_with_link(
+    keep: &Path,
+    duplicate: &Path,
+    mode: LinkMode,
+) -> io::Result<Replaced> {
+    let keep_metadata = fs::symlink_metadata(keep)?;
+    let duplicate_metadata = fs::symlink_metadata(duplicate)?;
+    if !keep_metadata.is_file() || !duplicate_metadata.is_file() {
+        return Err(io::Error::other("not a regular file"));
+    }
+    if keep_metadata.dev() == duplicate_metadata.dev()
+        && keep_metadata.ino() == duplicate_metadata.ino()
+    {
+        return Ok(Replaced::Linked);
+    }
+    if mode == LinkMode::Hardlink
+        && (keep_metadata.uid() != duplicate_metadata.uid()
+            || keep_metadata.gid() != duplicate_metadata.gid()
+            || keep_metadata.mode() != duplicate_metadata.mode())
+    {
+        return Ok(Replaced::AttributesDiffer);
+    }
+    if keep_metadata.len() != duplicate_metadata.len() || !contents_equal(keep, duplicate)? {
+        return Err(io::Error::other("contents changed since they were hashed"));
+    }
+
+    let mut tmp_name = duplicate.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".fs_dedup.tmp");
+    let tmp_path: PathBuf = duplicate.with_file_name(tmp_name);
+
+    match mode {
+        LinkMode::Hardlink => fs::hard_link(keep, &tmp_path)?,
+        LinkMode::Reflink => {
+            reflink(keep, &tmp_path)?;
+            let restore_attributes = || -> io::Result<()> {
+                let tmp_file = File::options().write(true).open(&tmp_path)?;
+                fchown(
+                    &tmp_file,
+                    Some(duplicate_metadata.uid()),
+                    Some(duplicate_metadata.gid()),
+                )?;
+                tmp_file.set_permissions(duplicate_metadata.permissions())?;
+                tmp_file.set_modified(duplicate_metadata.modified()?)?;
+                Ok(())
+            };
+            if let Err(err) = restore_attributes() {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+        }
+    }
+
+    if let Err(err) = fs::rename(&tmp_path, duplicate) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    Ok(Replaced::Linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("fs_tools_link_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("keep"), "duplicate").unwrap();
+            fs::write(path.join("duplicate"), "duplicate").unwrap();
+            TempDir(path)
+        }
+
+        fn keep(&self) -> PathBuf {
+            self.0.join("keep")
+        }
+
+        fn duplicate(&self) -> PathBuf {
+            self.0.join("duplicate")
+        }
+
+        fn linked(&self) -> bool {
+            let keep = fs::metadata(self.keep()).unwrap();
+            let duplicate = fs::metadata(self.duplicate()).unwrap();
+            keep.ino() == duplicate.ino()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn hardlinks_duplicates() {
+        let dir = TempDir::new("hardlink");
+        let replaced = replace_with_link(&dir.keep(), &dir.duplicate(), LinkMode::Hardlink);
+        assert_eq!(replaced.unwrap(), Replaced::Linked);
+        assert!(dir.linked());
+        assert!(!dir.0.join("duplicate.fs_dedup.tmp").exists());
+
+        // Already linked files are left as they are.
+        let replaced = replace_with_link(&dir.keep(), &dir.duplicate(), LinkMode::Hardlink);
+        assert_eq!(replaced.unwrap(), Replaced::Linked);
+    }
+
+    #[test]
+    fn keeps_attributes_of_hardlinked_duplicates() {
+        let dir = TempDir::new("attributes");
+        fs::set_permissions(dir.duplicate(), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(dir.keep(), fs::Permissions::from_mode(0o644)).unwrap();
+        let replaced = replace_with_link(&dir.keep(), &dir.duplicate(), LinkMode::Hardlink);
+        assert_eq!(replaced.unwrap(), Replaced::AttributesDiffer);
+        assert!(!dir.linked());
+        let mode = fs::metadata(dir.duplicate()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn never_replaces_temporary_files() {
+        let dir = TempDir::new("temporary");
+        let tmp_path = dir.0.join("duplicate.fs_dedup.tmp");
+        for mode in [LinkMode::Hardlink, LinkMode::Reflink] {
+            fs::write(&tmp_path, "someone else's").unwrap();
+            let err = replace_with_link(&dir.keep(), &dir.duplicate(), mode).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            assert_eq!(fs::read(&tmp_path).unwrap(), b"someone else's");
+            assert!(!dir.linked());
+        }
+    }
+
+    #[test]
+    fn keeps_the_original_when_linking_fails() {
+        let dir = TempDir::new("failure");
+        fs::write(dir.duplicate(), "different").unwrap();
+        assert!(replace_with_link(&dir.keep(), &dir.duplicate(), LinkMode::Hardlink).is_err());
+        assert_eq!(fs::read(dir.duplicate()).unwrap(), b"different");
+
+        // Most temporary directories do not support reflinks, the duplicate must then be kept.
+        fs::write(dir.duplicate(), "duplicate").unwrap();
+        match replace_with_link(&dir.keep(), &dir.duplicate(), LinkMode::Reflink) {
+            Ok(replaced) => assert_eq!(replaced, Replaced::Linked),
+            Err(_) => {
+                assert_eq!(fs::read(dir.duplicate()).unwrap(), b"duplicate");
+                assert!(!dir.linked());
+            }
+        }
+        assert!(!dir.0.join("duplicate.fs_dedup.tmp").exists());
+    }
+}
@@ -0,0 +1,363 @@
+pub(crate) mod args;
+pub(crate) mod link;
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+use args::Args;
+use rayon::{
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
+use tracing::{error, warn};
+use utils::{
+    fs::{escape_name, FsEntries, FsEntry},
+    state::read_state,
+};
+use xxhash_rust::xxh3::Xxh3;
+
+struct DuplicateGroup {
+    size: u64,
+    names: Vec<OsString>,
+}
+
+impl DuplicateGroup {
+    fn reclaimable(&self) -> u64 {
+        self.size * (self.names.len() as u64 - 1)
+    }
+}
+
+/// Hashes the first `limit` bytes of a file, or the whole file when `limit` is `None`.
+fn hash_file(path: &Path, limit: Option<u64>) -> io::Result<u128> {
+    let file = File::open(path)?;
+    let mut reader: Box<dyn Read> = match limit {
+        Some(limit) => Box::new(file.take(limit)),
+        None => Box::new(file),
+    };
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.digest128())
+}
+
+/// Splits `names` into groups of files with identical hashes, dropping unique files.
+fn split_by_hash(root_path: &Path, names: Vec<OsString>, limit: Option<u64>) -> Vec<Vec<OsString>> {
+    let mut by_hash: HashMap<u128, Vec<OsString>> = HashMap::new();
+    for name in names {
+        match hash_file(&root_path.join(&name), limit) {
+            Ok(hash) => by_hash.entry(hash).or_default().push(name),
+            Err(err) => error!(path = %escape_name(&name), "Failed to read file. Error : {}", err),
+        }
+    }
+    by_hash
+        .into_values()
+        .filter(|names| names.len() > 1)
+        .collect()
+}
+
+/// Splits names sharing an inode number by device, since states do not record the device and a
+/// walk can cross mount points. Names that cannot be read are dropped.
+fn split_by_device(root_path: &Path, names: Vec<OsString>) -> Vec<Vec<OsString>> {
+    if names.len() == 1 {
+        return vec![names];
+    }
+    let mut by_device: HashMap<u64, Vec<OsString>> = HashMap::new();
+    for name in names {
+        match root_path.join(&name).symlink_metadata() {
+            Ok(metadata) => by_device.entry(metadata.dev()).or_default().push(name),
+            Err(err) => error!(path = %escape_name(&name), "Failed to read file. Error : {}", err),
+        }
+    }
+    by_device.into_values().collect()
+}
+
+/// Names of files by size, with the other names of the files that have several.
+type Grouped = (
+    HashMap<u64, Vec<OsString>>,
+    HashMap<OsString, Vec<OsString>>,
+);
+
+/// Groups the files of `entries` of at least `min_size` bytes by size. Entries sharing an inode on
+/// the same device are already hardlinks of each other, only their first name is grouped, to be
+/// hashed and relinked along with the other names.
+fn group_by_inode(root_path: &Path, entries: Vec<FsEntry>, min_size: u64) -> Grouped {
+    let mut by_inode: HashMap<u64, (u64, Vec<OsString>)> = HashMap::new();
+    for entry in entries {
+        if entry.is_file && entry.size >= min_size {
+            by_inode
+                .entry(entry.inode)
+                .or_insert_with(|| (entry.size, Vec::new()))
+                .1
+                .push(entry.name);
+        }
+    }
+    let mut by_size: HashMap<u64, Vec<OsString>> = HashMap::new();
+    let mut aliases: HashMap<OsString, Vec<OsString>> = HashMap::new();
+    for (size, mut names) in by_inode.into_values().flat_map(|(size, names)| {
+        split_by_device(root_path, names)
+            .into_iter()
+            .map(move |names| (size, names))
+    }) {
+        names.sort_unstable();
+        let name = names.remove(0);
+        if !names.is_empty() {
+            aliases.insert(name.clone(), names);
+        }
+        by_size.entry(size).or_default().push(name);
+    }
+    (by_size, aliases)
+}
+
+fn write_report(
+    writer: &mut impl Write,
+    groups: &[DuplicateGroup],
+    aliases: &HashMap<OsString, Vec<OsString>>,
+    plan_only: bool,
+) -> io::Result<()> {
+    for (index, group) in groups.iter().enumerate() {
+        writeln!(
+            writer,
+            "GROUP {:>8}: {} files of {} bytes, {} bytes reclaimable",
+            index + 1,
+            group.names.len(),
+            group.size,
+            group.reclaimable()
+        )?;
+        for (position, name) in group.names.iter().enumerate() {
+            let names = std::iter::once(name).chain(aliases.get(name).into_iter().flatten());
+            for name in names {
+                if plan_only && position > 0 {
+                    writeln!(
+                        writer,
+                        "  {} -> {}",
+                        escape_name(name),
+                        escape_name(&group.names[0])
+                    )?;
+                } else {
+                    writeln!(writer, "  {}", escape_name(name))?;
+                }
+            }
+        }
+    }
+    writeln!(
+        writer,
+        "Total: {} groups, {} duplicate files, {} bytes reclaimable",
+        groups.len(),
+        groups
+            .iter()
+            .map(|group| group.names.len() - 1)
+            .sum::<usize>(),
+        groups.iter().map(DuplicateGroup::reclaimable).sum::<u64>()
+    )?;
+    writer.flush()
+}
+
+fn main() {
+    let args: Args = utils::config::parse();
+    utils::logging::init(&args.log, None);
+
+    let state = args.state.clone();
+    let root_path = args.path.clone();
+    let min_size = args.min_size;
+    let partial_hash_size = args.partial_hash_size;
+
+    ThreadPoolBuilder::new()
+        .num_threads(args.threads())
+        .build_global()
+        .unwrap();
+
+    let decoded: FsEntries = read_state(&state)
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        })
+        .into_fs_entries();
+
+    let (by_size, aliases) = group_by_inode(&root_path, decoded.entries, min_size);
+
+    let mut groups: Vec<DuplicateGroup> = by_size
+        .into_par_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .flat_map_iter(|(size, names)| {
+            split_by_hash(&root_path, names, Some(partial_hash_size))
+                .into_iter()
+                .flat_map(|names| {
+                    if size <= partial_hash_size {
+                        vec![names]
+                    } else {
+                        split_by_hash(&root_path, names, None)
+                    }
+                })
+                .map(|mut names| {
+                    names.sort_unstable();
+                    DuplicateGroup { size, names }
+                })
+                .collect::<Vec<DuplicateGroup>>()
+        })
+        .collect();
+    groups.sort_unstable_by(|a, b| {
+        b.reclaimable()
+            .cmp(&a.reclaimable())
+            .then_with(|| a.names[0].cmp(&b.names[0]))
+    });
+
+    let plan_only = args.link.is_some() && !args.apply;
+    let report_result = match &args.write_report_to {
+        Some(path) => File::create(path)
+            .and_then(|file| write_report(&mut BufWriter::new(file), &groups, &aliases, plan_only)),
+        None => write_report(&mut io::stdout().lock(), &groups, &aliases, plan_only),
+    };
+    if let Err(err) = report_result {
+        error!("Failed to write report. Error : {}", err);
+        process::exit(1);
+    }
+
+    if let (Some(mode), true) = (args.link, args.apply) {
+        let failures: usize = groups
+            .par_iter()
+            .map(|group| {
+                let keep: PathBuf = root_path.join(&group.names[0]);
+                group.names[1..]
+                    .iter()
+                    .flat_map(|name| {
+                        std::iter::once(name).chain(aliases.get(name).into_iter().flatten())
+                    })
+                    .filter(|name| {
+                        let duplicate = root_path.join(name);
+                        match link::replace_with_link(&keep, &duplicate, mode) {
+                            Ok(link::Replaced::Linked) => false,
+                            Ok(link::Replaced::AttributesDiffer) => {
+                                warn!(
+                                    path = %escape_name(name),
+                                    "Skipped file, its owner, group or mode differ from '{}'",
+                                    escape_name(&group.names[0])
+                                );
+                                false
+                            }
+                            Err(err) => {
+                                error!(
+                                    path = %escape_name(name),
+                                    "Failed to replace file. Error : {}",
+                                    err
+                                );
+                                true
+                            }
+                        }
+                    })
+                    .count()
+            })
+            .sum();
+        if failures > 0 {
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "fs_tools_dedup_{}_{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry(root_path: &Path, name: &str) -> FsEntry {
+        let metadata = root_path.join(name).symlink_metadata().unwrap();
+        FsEntry::from_metadata(OsString::from(name), &metadata)
+    }
+
+    fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+        items.sort_unstable();
+        items
+    }
+
+    #[test]
+    fn groups_hardlinks_by_inode() {
+        let dir = TempDir::new("inode");
+        fs::write(dir.0.join("a"), "duplicate").unwrap();
+        fs::hard_link(dir.0.join("a"), dir.0.join("b")).unwrap();
+        fs::write(dir.0.join("c"), "duplicate").unwrap();
+        fs::write(dir.0.join("small"), "s").unwrap();
+        let entries = ["b", "small", "c", "a"]
+            .map(|name| entry(&dir.0, name))
+            .to_vec();
+
+        let (by_size, aliases) = group_by_inode(&dir.0, entries, 2);
+        assert_eq!(by_size.len(), 1);
+        assert_eq!(sorted(by_size[&9].clone()), ["a", "c"]);
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases[&OsString::from("a")], ["b"]);
+    }
+
+    #[test]
+    fn splits_inodes_by_device() {
+        // Names sharing an inode number on different devices are different files.
+        let root_path = Path::new("/");
+        let entries = ["proc", "etc"]
+            .map(|name| FsEntry {
+                inode: 1,
+                size: 1,
+                is_dir: false,
+                is_file: true,
+                ..entry(root_path, name)
+            })
+            .to_vec();
+        let (by_size, aliases) = group_by_inode(root_path, entries, 0);
+        assert_eq!(sorted(by_size[&1].clone()), ["etc", "proc"]);
+        assert!(aliases.is_empty());
+
+        let names = ["etc", "missing", "proc", "usr"]
+            .map(OsString::from)
+            .to_vec();
+        let groups = sorted(split_by_device(root_path, names));
+        assert_eq!(groups, [vec!["etc", "usr"], vec!["proc"]]);
+    }
+
+    #[test]
+    fn splits_names_by_hash() {
+        let dir = TempDir::new("hash");
+        fs::write(dir.0.join("a"), "head tail a").unwrap();
+        fs::write(dir.0.join("b"), "head tail b").unwrap();
+        fs::write(dir.0.join("c"), "head tail a").unwrap();
+        fs::write(dir.0.join("d"), "other").unwrap();
+        let names = ["a", "b", "c", "d"].map(OsString::from).to_vec();
+
+        let groups = split_by_hash(&dir.0, names.clone(), Some(4));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(sorted(groups[0].clone()), ["a", "b", "c"]);
+        let groups = split_by_hash(&dir.0, names, None);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(sorted(groups[0].clone()), ["a", "c"]);
+    }
+}
@@ -0,0 +1,57 @@
+use clap::Parser;
+use std::path::PathBuf;
+use utils::arg_parsers::check_if_file_exists;
+use utils::logging::LogArgs;
+
+#[derive(Parser, Debug)]
+#[command(
+    author,
+    version,
+    about,
+    help_template = "{before-help}{name} {version}
+
+Author: {author}
+
+{about-with-newline}
+{usage-heading} {usage}
+
+{all-args}{after-help}
+"
+)]
+pub(crate) struct Args {
+    #[arg(
+        id = "config file",
+        long = "config",
+        short = 'c',
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the TOML file defining the replication pairs, in [[pair]] tables"
+    )]
+    pub config: PathBuf,
+    #[arg(
+        id = "pairs",
+        long = "pair",
+        short = 'p',
+        num_args = 1..,
+        help = "",
+        long_help = "Names of the pairs to replicate, defaults to all the pairs of the config file"
+    )]
+    pub pairs: Vec<String>,
+    #[arg(
+        id = "once",
+        long = "once",
+        help = "",
+        long_help = "Replicate each pair once right away and exit instead of running on schedule, exits with an error when a run failed"
+    )]
+    pub once: bool,
+    #[arg(
+        id = "history",
+        long = "history",
+        conflicts_with = "once",
+        help = "",
+        long_help = "Print the history of the runs of each pair and exit"
+    )]
+    pub history: bool,
+    #[command(flatten)]
+    pub log: LogArgs,
+}
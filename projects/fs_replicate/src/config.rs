@@ -0,0 +1,99 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+fn default_keep() -> usize {
+    5
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Pair {
+    pub name: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Seconds between the starts of two scheduled runs.
+    pub interval: u64,
+    /// Number of runs whose states, diff and logs are kept.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    #[serde(default)]
+    pub delete_destination: bool,
+    /// Scans the source with the state of the last successful run as previous state.
+    #[serde(default)]
+    pub incremental: bool,
+    /// Extra arguments of each tool of the pipeline.
+    #[serde(default)]
+    pub state_gen_args: Vec<String>,
+    #[serde(default)]
+    pub compare_args: Vec<String>,
+    #[serde(default)]
+    pub rsync_args: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Directory holding the runs, history and lock of each pair.
+    pub state_dir: PathBuf,
+    /// Directory of the fs_state_gen, fs_compare and run_rsync binaries. Defaults to the
+    /// directory of this binary when they are installed along it, then to PATH.
+    pub bin_dir: Option<PathBuf>,
+    #[serde(rename = "pair", default)]
+    pub pairs: Vec<Pair>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "Failed to read config file '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })?;
+        let config: Config = toml::from_str(&contents).map_err(|err| {
+            format!(
+                "Failed to parse config file '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })?;
+        let mut names = HashSet::new();
+        for pair in config.pairs.iter() {
+            if pair.name.is_empty() || pair.name.contains('/') || pair.name.starts_with('.') {
+                return Err(format!("Invalid pair name '{}'", pair.name));
+            }
+            if !names.insert(pair.name.as_str()) {
+                return Err(format!("Duplicate pair name '{}'", pair.name));
+            }
+            if pair.interval == 0 {
+                return Err(format!("Interval of pair '{}' must not be 0", pair.name));
+            }
+            if pair.keep == 0 {
+                return Err(format!("Keep of pair '{}' must not be 0", pair.name));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the path of the binary `name` of the pipeline.
+    pub fn tool(&self, name: &str) -> PathBuf {
+        if let Some(bin_dir) = &self.bin_dir {
+            return bin_dir.join(name);
+        }
+        env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join(name)))
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from(name))
+    }
+
+    pub fn pair_dir(&self, pair: &Pair) -> PathBuf {
+        self.state_dir.join(&pair.name)
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Outcome of a run of the pipeline of a pair.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Run {
+    /// Name of the run directory.
+    pub id: String,
+    /// Unix time in seconds at which the run started.
+    pub started: i64,
+    /// Duration of the run in seconds.
+    pub duration: u64,
+    pub succeeded: bool,
+    /// Entries created or modified on the source since the destination was synced.
+    pub changed: usize,
+    /// Entries deleted on the source since the destination was synced.
+    pub deleted: usize,
+    pub error: Option<String>,
+}
+
+/// Returns the key ordering run ids by start, the timestamp then the suffix of the runs started
+/// within the same second, compared as a number so `-10` comes after `-2`.
+pub(crate) fn run_order(id: &str) -> (&str, u64) {
+    match id.split_once('-') {
+        Some((timestamp, suffix)) => (timestamp, suffix.parse().unwrap_or(u64::MAX)),
+        None => (id, 1),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct History {
+    #[serde(rename = "run", default)]
+    pub runs: Vec<Run>,
+}
+
+impl History {
+    pub fn load(path: &Path) -> Result<History, String> {
+        if !path.exists() {
+            return Ok(History::default());
+        }
+        let contents = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "Failed to read history file '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })?;
+        toml::from_str(&contents).map_err(|err| {
+            format!(
+                "Failed to parse history file '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })
+    }
+
+    /// Appends `run` to the history file as a [[run]] table, keeping the earlier runs as written.
+    pub fn append(path: &Path, run: &Run) -> Result<(), String> {
+        let contents = toml::to_string(&History {
+            runs: vec![run.clone()],
+        })
+        .map_err(|err| format!("Failed to encode run '{}'. Error : {}", run.id, err))?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| write!(file, "\n{}", contents))
+            .map_err(|err| {
+                format!(
+                    "Failed to write history file '{}'. Error : {}",
+                    path.display(),
+                    err
+                )
+            })
+    }
+
+    pub fn last_run(&self) -> Option<&Run> {
+        self.runs.last()
+    }
+
+    pub fn last_success(&self) -> Option<&Run> {
+        self.runs.iter().rev().find(|run| run.succeeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(id: &str, succeeded: bool) -> Run {
+        Run {
+            id: String::from(id),
+            started: 1,
+            duration: 2,
+            succeeded,
+            changed: 3,
+            deleted: 4,
+            error: (!succeeded).then(|| String::from("'run_rsync' failed")),
+        }
+    }
+
+    #[test]
+    fn appends_runs() {
+        let path = std::env::temp_dir().join(format!(
+            "fs_tools_history_{}_history.toml",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let history = History::load(&path).unwrap();
+        assert!(history.last_run().is_none());
+
+        for (id, succeeded) in [("a", true), ("b", true), ("c", false)] {
+            History::append(&path, &run(id, succeeded)).unwrap();
+        }
+        let history = History::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(history.runs.len(), 3);
+        assert_eq!(history.last_run().unwrap().id, "c");
+        assert_eq!(
+            history.last_run().unwrap().error.as_deref(),
+            Some("'run_rsync' failed")
+        );
+        assert_eq!(history.last_success().unwrap().id, "b");
+        assert_eq!(history.last_success().unwrap().changed, 3);
+    }
+
+    #[test]
+    fn orders_runs_by_start() {
+        let mut ids = vec![
+            "20240102T000000Z",
+            "20240101T000000Z-10",
+            "20240101T000000Z-2",
+            "20240101T000000Z",
+        ];
+        ids.sort_by(|a, b| run_order(a).cmp(&run_order(b)));
+        assert_eq!(
+            ids,
+            [
+                "20240101T000000Z",
+                "20240101T000000Z-2",
+                "20240101T000000Z-10",
+                "20240102T000000Z",
+            ]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+pub(crate) mod args;
+pub(crate) mod config;
+pub(crate) mod history;
+pub(crate) mod pipeline;
+
+use std::{process, thread, time::Duration};
+
+use args::Args;
+use chrono::{DateTime, Utc};
+use config::{Config, Pair};
+use history::{History, Run};
+use tracing::{error, info};
+
+/// Logs the outcome of a run, returns false when it failed.
+fn report(pair: &Pair, result: Result<Run, String>) -> bool {
+    match result {
+        Ok(run) if run.succeeded => {
+            info!(
+                pair = pair.name,
+                run = run.id,
+                duration = run.duration,
+                changed = run.changed,
+                deleted = run.deleted,
+                "Run succeeded"
+            );
+            true
+        }
+        Ok(run) => {
+            error!(
+                pair = pair.name,
+                run = run.id,
+                duration = run.duration,
+                "Run failed. Error : {}",
+                run.error.unwrap_or_default()
+            );
+            false
+        }
+        Err(err) => {
+            error!(pair = pair.name, "{}", err);
+            false
+        }
+    }
+}
+
+/// Runs the pipeline of `pair` every `interval` seconds, counted from the start of its last run.
+fn run_on_schedule(config: &Config, pair: &Pair) {
+    let interval = pair.interval as i64;
+    loop {
+        let history_path = pipeline::history_path(config, pair);
+        let last_started = History::load(&history_path)
+            .unwrap_or_else(|err| {
+                error!(pair = pair.name, "{}", err);
+                History::default()
+            })
+            .last_run()
+            .map(|run| run.started);
+        let now = Utc::now().timestamp();
+        let due = last_started
+            .map(|started| started + interval)
+            .unwrap_or(now);
+        if due > now {
+            thread::sleep(Duration::from_secs((due - now) as u64));
+            continue;
+        }
+        let result = pipeline::replicate(config, pair);
+        let started = result.is_ok();
+        report(pair, result);
+        // Runs that could not start are not in the history, wait before trying again.
+        if !started {
+            thread::sleep(Duration::from_secs(pair.interval));
+        }
+    }
+}
+
+fn print_history(config: &Config, pairs: &[&Pair]) -> Result<(), String> {
+    println!(
+        "{:<24} {:<18} {:<20} {:>10} {:>8} {:>12} {:>12}",
+        "PAIR", "RUN", "STARTED", "DURATION", "STATUS", "CHANGED", "DELETED"
+    );
+    for pair in pairs {
+        let history = History::load(&pipeline::history_path(config, pair))?;
+        for run in history.runs.iter() {
+            let started = DateTime::<Utc>::from_timestamp(run.started, 0)
+                .map(|started| started.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+            println!(
+                "{:<24} {:<18} {:<20} {:>9}s {:>8} {:>12} {:>12}",
+                pair.name,
+                run.id,
+                started,
+                run.duration,
+                if run.succeeded { "OK" } else { "FAILED" },
+                run.changed,
+                run.deleted
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Args = utils::config::parse();
+    utils::logging::init(&args.log, None);
+
+    let config = Config::load(&args.config).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    });
+    let pairs: Vec<&Pair> = if args.pairs.is_empty() {
+        config.pairs.iter().collect()
+    } else {
+        args.pairs
+            .iter()
+            .map(|name| {
+                config
+                    .pairs
+                    .iter()
+                    .find(|pair| &pair.name == name)
+                    .unwrap_or_else(|| {
+                        error!("Unknown pair '{}'", name);
+                        process::exit(1);
+                    })
+            })
+            .collect()
+    };
+    if pairs.is_empty() {
+        error!("No pair to replicate in '{}'", args.config.display());
+        process::exit(1);
+    }
+
+    if args.history {
+        if let Err(err) = print_history(&config, &pairs) {
+            error!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.once {
+        let succeeded = thread::scope(|scope| {
+            let handles: Vec<_> = pairs
+                .iter()
+                .map(|pair| scope.spawn(|| report(pair, pipeline::replicate(&config, pair))))
+                .collect();
+            handles
+                .into_iter()
+                .all(|handle| handle.join().unwrap_or(false))
+        });
+        if !succeeded {
+            process::exit(1);
+        }
+        return;
+    }
+
+    thread::scope(|scope| {
+        for pair in pairs.iter() {
+            let config = &config;
+            scope.spawn(move || run_on_schedule(config, pair));
+        }
+    });
+}
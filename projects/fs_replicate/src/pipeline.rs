@@ -0,0 +1,380 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
+
+use chrono::Utc;
+use utils::{
+    encoding::read_from_file,
+    fs::{read_relocated_changes, ChangedFsEntries, ChangedFsEntry},
+};
+
+use crate::{
+    config::{Config, Pair},
+    history::{run_order, History, Run},
+};
+
+/// Runs `program` with `args`, appending the command line and its output to `log`.
+fn run_stage(log: &mut File, program: &Path, args: &[OsString]) -> Result<(), String> {
+    let command_line = args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let log_error = |err: std::io::Error| format!("Failed to write pipeline log. Error : {}", err);
+    writeln!(log, "$ {} {}", program.display(), command_line).map_err(log_error)?;
+    let status = Command::new(program)
+        .args(args)
+        .stdout(log.try_clone().map_err(log_error)?)
+        .stderr(log.try_clone().map_err(log_error)?)
+        .status()
+        .map_err(|err| format!("Failed to run '{}'. Error : {}", program.display(), err))?;
+    if !status.success() {
+        return Err(format!("'{}' failed with {}", program.display(), status));
+    }
+    Ok(())
+}
+
+fn args_of<I: IntoIterator<Item = S>, S: Into<OsString>>(args: I) -> Vec<OsString> {
+    args.into_iter().map(Into::into).collect()
+}
+
+/// Returns the arguments of fs_state_gen scanning `root` into `state`.
+fn state_gen_args(
+    pair: &Pair,
+    root: &Path,
+    state: &Path,
+    previous_state: Option<PathBuf>,
+) -> Vec<OsString> {
+    let mut args = args_of([OsString::from("-s"), root.into(), "-o".into(), state.into()]);
+    if let Some(previous_state) = previous_state {
+        args.extend(args_of([
+            OsString::from("--previous"),
+            previous_state.into(),
+        ]));
+    }
+    args.extend(args_of(pair.state_gen_args.iter()));
+    args
+}
+
+fn compare_args(
+    pair: &Pair,
+    source_state: &Path,
+    destination_state: &Path,
+    diff: &Path,
+) -> Vec<OsString> {
+    let mut args = args_of([
+        OsString::from("-s"),
+        source_state.into(),
+        "-d".into(),
+        destination_state.into(),
+        "-o".into(),
+        diff.into(),
+    ]);
+    args.extend(args_of(pair.compare_args.iter()));
+    args
+}
+
+fn rsync_args(pair: &Pair, diff: &Path, run_dir: &Path) -> Vec<OsString> {
+    let mut args = args_of([
+        OsString::from("-s"),
+        pair.source.clone().into(),
+        "-d".into(),
+        pair.destination.clone().into(),
+        "--diff".into(),
+        diff.into(),
+        "--tmp-dir".into(),
+        run_dir.into(),
+    ]);
+    if pair.delete_destination {
+        args.extend(args_of(["--delete-destination", "true"]));
+    }
+    args.extend(args_of(pair.rsync_args.iter()));
+    args
+}
+
+/// Scans both sides of `pair` into `run_dir`, compares them and syncs the differences.
+/// Returns the number of changed and deleted entries.
+fn run_pipeline(
+    config: &Config,
+    pair: &Pair,
+    run_dir: &Path,
+    previous_source_state: Option<PathBuf>,
+    log: &mut File,
+) -> Result<(usize, usize), String> {
+    let source_state = run_dir.join("source.st");
+    let destination_state = run_dir.join("destination.st");
+    let diff = run_dir.join("changes.diff");
+
+    run_stage(
+        log,
+        &config.tool("fs_state_gen"),
+        &state_gen_args(pair, &pair.source, &source_state, previous_source_state),
+    )?;
+    run_stage(
+        log,
+        &config.tool("fs_state_gen"),
+        &state_gen_args(pair, &pair.destination, &destination_state, None),
+    )?;
+    run_stage(
+        log,
+        &config.tool("fs_compare"),
+        &compare_args(pair, &source_state, &destination_state, &diff),
+    )?;
+
+    let changes: ChangedFsEntries = read_from_file(&diff)?;
+    let relocated = read_relocated_changes(&diff)?;
+    let entries: Vec<&ChangedFsEntry> = changes
+        .entries
+        .iter()
+        .chain(
+            relocated
+                .iter()
+                .flat_map(|relocated| relocated.entries.iter()),
+        )
+        .collect();
+    let deleted = entries.iter().filter(|entry| entry.is_deleted).count();
+    let changed = entries.len() - deleted;
+    if changed == 0 && (deleted == 0 || !pair.delete_destination) {
+        return Ok((changed, deleted));
+    }
+
+    run_stage(
+        log,
+        &config.tool("run_rsync"),
+        &rsync_args(pair, &diff, run_dir),
+    )?;
+    Ok((changed, deleted))
+}
+
+/// Removes the oldest run directories of `runs_dir` beyond the `keep` newest ones, always
+/// keeping `last_success` whose source state is the previous state of incremental scans.
+fn prune_runs(runs_dir: &Path, keep: usize, last_success: Option<&str>) -> Result<(), String> {
+    let mut ids: Vec<String> = fs::read_dir(runs_dir)
+        .map_err(|err| format!("Failed to list '{}'. Error : {}", runs_dir.display(), err))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    ids.sort_by(|a, b| run_order(a).cmp(&run_order(b)));
+    let old_count = ids.len().saturating_sub(keep);
+    for id in ids.into_iter().take(old_count) {
+        if Some(id.as_str()) == last_success {
+            continue;
+        }
+        let run_dir = runs_dir.join(&id);
+        fs::remove_dir_all(&run_dir)
+            .map_err(|err| format!("Failed to remove '{}'. Error : {}", run_dir.display(), err))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn history_path(config: &Config, pair: &Pair) -> PathBuf {
+    config.pair_dir(pair).join("history.toml")
+}
+
+/// Runs the pipeline of `pair` in a new run directory, records it in the history of the pair and
+/// removes the runs beyond its retention.
+///
+/// Fails without running when another run of the pair holds its lock.
+pub(crate) fn replicate(config: &Config, pair: &Pair) -> Result<Run, String> {
+    let pair_dir = config.pair_dir(pair);
+    let runs_dir = pair_dir.join("runs");
+    fs::create_dir_all(&runs_dir)
+        .map_err(|err| format!("Failed to create '{}'. Error : {}", runs_dir.display(), err))?;
+
+    let lock_path = pair_dir.join("lock");
+    let lock = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|err| format!("Failed to open '{}'. Error : {}", lock_path.display(), err))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(format!(
+                "A run of pair '{}' is already in progress",
+                pair.name
+            ))
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(format!(
+                "Failed to lock '{}'. Error : {}",
+                lock_path.display(),
+                err
+            ))
+        }
+    }
+
+    let history_path = history_path(config, pair);
+    let history = History::load(&history_path)?;
+    let last_success = history.last_success().map(|run| run.id.clone());
+    let previous_source_state = last_success
+        .as_ref()
+        .map(|id| runs_dir.join(id).join("source.st"))
+        .filter(|path| pair.incremental && path.exists());
+
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let timestamp = started_at.format("%Y%m%dT%H%M%SZ").to_string();
+    // Runs started within the same second get a suffix, keeping ids sorted by start.
+    let mut id = timestamp.clone();
+    let mut suffix = 1;
+    while runs_dir.join(&id).exists() {
+        suffix += 1;
+        id = format!("{}-{}", timestamp, suffix);
+    }
+    let run_dir = runs_dir.join(&id);
+    fs::create_dir_all(&run_dir)
+        .map_err(|err| format!("Failed to create '{}'. Error : {}", run_dir.display(), err))?;
+    let log_path = run_dir.join("pipeline.log");
+    let mut log = File::create(&log_path)
+        .map_err(|err| format!("Failed to create '{}'. Error : {}", log_path.display(), err))?;
+
+    let result = run_pipeline(config, pair, &run_dir, previous_source_state, &mut log);
+    let (changed, deleted) = result.as_ref().copied().unwrap_or((0, 0));
+    let run = Run {
+        id: id.clone(),
+        started: started_at.timestamp(),
+        duration: started.elapsed().as_secs(),
+        succeeded: result.is_ok(),
+        changed,
+        deleted,
+        error: result.err(),
+    };
+    History::append(&history_path, &run)?;
+
+    let last_success = if run.succeeded {
+        Some(id)
+    } else {
+        last_success
+    };
+    prune_runs(&runs_dir, pair.keep, last_success.as_deref())?;
+    Ok(run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(delete_destination: bool) -> Pair {
+        Pair {
+            name: String::from("pair"),
+            source: PathBuf::from("/src"),
+            destination: PathBuf::from("/dst"),
+            interval: 60,
+            keep: 2,
+            delete_destination,
+            incremental: true,
+            state_gen_args: vec![String::from("-t"), String::from("4")],
+            compare_args: vec![String::from("--case-insensitive")],
+            rsync_args: vec![String::from("--rsync-args=-z")],
+        }
+    }
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn builds_stage_arguments() {
+        let pair = pair(false);
+        let args = state_gen_args(
+            &pair,
+            &pair.source,
+            Path::new("/run/source.st"),
+            Some(PathBuf::from("/old/source.st")),
+        );
+        assert_eq!(
+            strings(args),
+            [
+                "-s",
+                "/src",
+                "-o",
+                "/run/source.st",
+                "--previous",
+                "/old/source.st",
+                "-t",
+                "4"
+            ]
+        );
+        let args = state_gen_args(&pair, &pair.destination, Path::new("/run/d.st"), None);
+        assert_eq!(strings(args), ["-s", "/dst", "-o", "/run/d.st", "-t", "4"]);
+
+        let args = compare_args(
+            &pair,
+            Path::new("/run/s.st"),
+            Path::new("/run/d.st"),
+            Path::new("/run/changes.diff"),
+        );
+        assert_eq!(
+            strings(args),
+            [
+                "-s",
+                "/run/s.st",
+                "-d",
+                "/run/d.st",
+                "-o",
+                "/run/changes.diff",
+                "--case-insensitive"
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_rsync_arguments() {
+        let diff = Path::new("/run/changes.diff");
+        let args = rsync_args(&pair(false), diff, Path::new("/run"));
+        assert_eq!(
+            strings(args),
+            [
+                "-s",
+                "/src",
+                "-d",
+                "/dst",
+                "--diff",
+                "/run/changes.diff",
+                "--tmp-dir",
+                "/run",
+                "--rsync-args=-z"
+            ]
+        );
+        let args = strings(rsync_args(&pair(true), diff, Path::new("/run")));
+        assert_eq!(
+            args[8..],
+            ["--delete-destination", "true", "--rsync-args=-z"]
+        );
+    }
+
+    #[test]
+    fn prunes_the_oldest_runs() {
+        let runs_dir =
+            std::env::temp_dir().join(format!("fs_tools_pipeline_{}_runs", std::process::id()));
+        let _ = fs::remove_dir_all(&runs_dir);
+        let ids = [
+            "20240101T000000Z",
+            "20240101T000000Z-2",
+            "20240101T000000Z-10",
+            "20240102T000000Z",
+        ];
+        for id in ids {
+            fs::create_dir_all(runs_dir.join(id)).unwrap();
+        }
+        prune_runs(&runs_dir, 2, Some("20240101T000000Z")).unwrap();
+        let mut kept: Vec<String> = fs::read_dir(&runs_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        fs::remove_dir_all(&runs_dir).unwrap();
+        kept.sort_by(|a, b| run_order(a).cmp(&run_order(b)));
+        // The last successful run is kept even though it is the oldest.
+        assert_eq!(kept, [ids[0], ids[2], ids[3]]);
+    }
+}
@@ -1,7 +1,13 @@
 use clap::Parser;
 use jwalk::Parallelism;
-use std::{num::NonZeroUsize, path::PathBuf};
-use utils::arg_parsers::{check_if_directory_exists, check_if_parent_path_exists};
+use std::{num::NonZeroUsize, path::PathBuf, sync::Arc};
+use utils::arg_parsers::{
+    check_if_directory_exists, check_if_file_exists, check_if_parent_path_exists,
+};
+use utils::encoding::Compression;
+use utils::logging::LogArgs;
+use utils::metrics::MetricsArgs;
+use utils::throttle::{RateLimiter, Schedule};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -42,10 +48,19 @@ pub(crate) struct Args {
         long = "output",
         short = 'o',
         value_parser = check_if_parent_path_exists(),
+        required_unless_present = "stdout",
         help = "",
         long_help="Path to write the filesystem state file to"
     )]
-    pub write_state_to: PathBuf,
+    pub write_state_to: Option<PathBuf>,
+    #[arg(
+        id = "stdout",
+        long = "stdout",
+        conflicts_with_all = ["write filesystem state to file", "watch"],
+        help = "",
+        long_help = "Write the filesystem state to the standard output instead of a file, for fs_compare to read from a command such as ssh host fs_state_gen --stdout -s /data. Logs still go to the standard error"
+    )]
+    pub stdout: bool,
     #[arg(
         id = "folders to ignore",
         long="ignore-folders",
@@ -56,13 +71,84 @@ pub(crate) struct Args {
         long_help = "Folders to skip when generating the state file"
     )]
     pub folders_to_ignore: Vec<String>,
+    #[arg(
+        id = "previous filesystem state file",
+        long = "previous",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to a previous filesystem state file of the same directory. Directories whose inode, mtime and ctime are unchanged since then are not listed again, their entries are taken from the previous state and stat'ed. States written before ctimes were recorded have all their directories listed"
+    )]
+    pub previous_state: Option<PathBuf>,
+    #[arg(
+        id = "watch",
+        long = "watch",
+        conflicts_with = "previous filesystem state file",
+        help = "",
+        long_help = "Keep running after the initial scan, following changes to the directory with inotify and writing the state file again at every checkpoint. Directories that cannot be watched once the inotify watch limit is reached are rescanned at each checkpoint instead. Only supported on Linux"
+    )]
+    pub watch: bool,
+    #[arg(
+        id = "checkpoint interval",
+        long = "checkpoint-interval",
+        default_value = "60",
+        requires = "watch",
+        help = "",
+        long_help = "Number of seconds between two writes of the state file in watch mode, the file is only written when the state changed"
+    )]
+    pub checkpoint_interval: u64,
+    #[arg(
+        id = "journal file",
+        long = "journal",
+        value_parser = check_if_parent_path_exists(),
+        requires = "watch",
+        help = "",
+        long_help = "Path to a change journal to append every change seen in watch mode to, with a sequence number and a timestamp. run_rsync replays it with --journal and removes the synced records with --truncate-journal. When the state file already exists, the changes made since it was written are journaled at startup"
+    )]
+    pub journal: Option<PathBuf>,
+    #[arg(
+        id = "compression",
+        long = "compression",
+        value_enum,
+        default_value_t = Compression::None,
+        help = "",
+        long_help = "Compression to apply to the written state file, compressed files are detected automatically when read"
+    )]
+    pub compression: Compression,
+    #[arg(
+        id = "compression level",
+        long = "compression-level",
+        default_value = "0",
+        allow_negative_numbers = true,
+        help = "",
+        long_help = "Compression level to use with zstd, 0 selects the default level"
+    )]
+    pub compression_level: i32,
+    #[arg(
+        id = "max entries per second",
+        long = "max-entries-per-second",
+        value_parser = Schedule::parse,
+        help = "",
+        long_help = "Maximum number of entries to stat per second while scanning, to spare busy filers. Either a single limit or comma separated time of day windows such as 08:00-18:00=2000, optionally followed by the limit for the rest of the day, as in 08:00-18:00=2000,20000. Windows use the local time and can wrap around midnight"
+    )]
+    pub max_entries_per_second: Option<Schedule>,
+    #[arg(
+        id = "metrics address",
+        long = "metrics-address",
+        requires = "watch",
+        help = "",
+        long_help = "Address to serve the metrics at in watch mode, as http://ADDRESS/metrics in the Prometheus text format, for example 127.0.0.1:9101"
+    )]
+    pub metrics_address: Option<String>,
+    #[command(flatten)]
+    pub metrics: MetricsArgs,
+    #[command(flatten)]
+    pub log: LogArgs,
 }
 
 impl Args {
     pub fn threads(&self) -> usize {
         let cpus = num_cpus::get();
         self.threads
-            .clone()
             .unwrap_or_else(|| {
                 if cfg!(target_vendor = "apple") {
                     NonZeroUsize::new(cpus).unwrap()
@@ -79,4 +165,10 @@ impl Args {
             n => Parallelism::RayonNewPool(n),
         }
     }
+
+    pub fn limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.max_entries_per_second
+            .clone()
+            .map(|schedule| Arc::new(RateLimiter::new(schedule)))
+    }
 }
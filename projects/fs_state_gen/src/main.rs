@@ -1,50 +1,148 @@
 pub(crate) mod args;
+#[cfg(target_os = "linux")]
+pub(crate) mod watch;
 
-use std::{fs::File, io::BufWriter};
+use std::{
+    io,
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
 
 use args::Args;
-use clap::Parser;
 use rayon::{
-    iter::{IntoParallelRefIterator, ParallelIterator},
+    iter::{IntoParallelIterator, ParallelIterator},
     ThreadPoolBuilder,
 };
-use utils::fs::{self as utils_fs, FsEntries, FsEntry};
+use tracing::error;
+use utils::{
+    fs::{self as utils_fs, FsEntries, FsEntry},
+    metrics::{unix_time, Metrics},
+    state::{read_state, write_state, write_state_to_writer, FsState},
+};
+
+/// Records the metrics of a scan of `entries` entries that took `duration`.
+pub(crate) fn record_scan(metrics: &Metrics, entries: usize, duration: Duration) {
+    metrics.set(
+        "scan_duration_seconds",
+        "Duration of the last scan",
+        &[],
+        duration.as_secs_f64(),
+    );
+    metrics.set(
+        "entries",
+        "Number of entries in the state",
+        &[],
+        entries as f64,
+    );
+    metrics.set(
+        "entries_per_second",
+        "Entries scanned per second in the last scan",
+        &[],
+        entries as f64 / duration.as_secs_f64().max(f64::EPSILON),
+    );
+    metrics.set(
+        "last_scan_timestamp_seconds",
+        "Time the last scan ended at",
+        &[],
+        unix_time(),
+    );
+}
+
+#[cfg(target_os = "linux")]
+use watch::watch;
+
+/// Watch mode follows changes with inotify, which only exists on Linux.
+#[cfg(not(target_os = "linux"))]
+fn watch(_args: &Args) -> Result<(), String> {
+    Err(String::from(
+        "Watch mode needs inotify and is only supported on Linux",
+    ))
+}
 
 fn main() {
-    let args = Args::parse();
+    let args: Args = utils::config::parse();
+    utils::logging::init(&args.log, None);
 
     let parallelism = args.parallelism();
     let root_path = args.path.clone();
-    let root_path_str = root_path.to_str().unwrap();
     let write_state_to = args.write_state_to.clone();
     let folders_to_ignore = args.folders_to_ignore.clone();
+    let previous_state = args.previous_state.clone();
 
     ThreadPoolBuilder::new()
         .num_threads(args.threads())
         .build_global()
         .unwrap();
 
-    let value: Vec<FsEntry> = utils_fs::walk_dir(
-        root_path.clone(),
-        parallelism,
-        false,
-        false,
-        false,
-        folders_to_ignore,
-    )
-    .par_iter()
-    .map(|entry| {
-        let mut entry1 = entry.clone();
-        entry1.name = entry1
-            .name
-            .trim_start_matches(root_path_str)
-            .trim_start_matches("/")
-            .to_string();
-        entry1
-    })
-    .collect();
-
-    let entries = FsEntries { entries: value };
-    let mut writer = BufWriter::new(File::create(write_state_to).unwrap());
-    bincode::encode_into_std_write(&entries, &mut writer, bincode::config::standard()).unwrap();
+    if args.watch {
+        if let Err(err) = watch(&args) {
+            error!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let started = Instant::now();
+    let value: Vec<FsEntry> = if let Some(previous_state) = previous_state {
+        let previous: FsEntries = read_state(&previous_state)
+            .unwrap_or_else(|err| {
+                error!("{}", err);
+                process::exit(1);
+            })
+            .into_fs_entries();
+        utils_fs::walk_dir_incremental(
+            root_path.clone(),
+            &previous,
+            folders_to_ignore,
+            args.limiter(),
+        )
+    } else {
+        utils_fs::walk_dir(
+            root_path.clone(),
+            parallelism,
+            false,
+            false,
+            false,
+            folders_to_ignore,
+            args.limiter(),
+        )
+        .into_par_iter()
+        .map(|mut entry| {
+            entry.name = utils_fs::relative_name(Path::new(&entry.name), &root_path);
+            entry
+        })
+        .collect()
+    };
+
+    let scan_duration = started.elapsed();
+    let entries_count = value.len();
+    let state = FsState::from_entries(value);
+    let written = match &write_state_to {
+        Some(write_state_to) => write_state(
+            &state,
+            write_state_to,
+            args.compression,
+            args.compression_level,
+        ),
+        None => write_state_to_writer(
+            &state,
+            io::stdout().lock(),
+            args.compression,
+            args.compression_level,
+        )
+        .map_err(|err| format!("Failed to write state to stdout. Error : {}", err)),
+    };
+    if let Err(err) = written {
+        error!("{}", err);
+        process::exit(1);
+    }
+    if let Some(metrics_file) = &args.metrics.metrics_file {
+        let metrics = Metrics::new("fs_state_gen");
+        record_scan(&metrics, entries_count, scan_duration);
+        if let Err(err) = metrics.write_to_file(metrics_file) {
+            error!("{}", err);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,643 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{args::Args, record_scan};
+use inotify::{Event, EventMask, Inotify, WatchDescriptor, WatchMask};
+use jwalk::Parallelism;
+use rayon::ThreadPoolBuilder;
+use tracing::{error, info, warn};
+use utils::{
+    encoding::Compression,
+    fs::{self as utils_fs, join_name, names_below, ChangedFsEntry, FsEntry},
+    journal::JournalWriter,
+    metrics::{unix_time, Metrics},
+    state::{read_state, write_state, FsState},
+    throttle::RateLimiter,
+};
+
+/// Error returned by `inotify_add_watch` once `fs.inotify.max_user_watches` is reached.
+const ENOSPC: i32 = 28;
+
+/// Delay between two reads of the inotify queue when it is empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MODIFY
+        | WatchMask::ATTRIB
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::ONLYDIR
+        | WatchMask::DONT_FOLLOW
+}
+
+/// State of a directory tree kept up to date from inotify events.
+pub(crate) struct LiveState {
+    root_path: PathBuf,
+    parallelism: Parallelism,
+    folders_to_ignore: Vec<String>,
+    /// Entries named relative to `root_path`.
+    entries: BTreeMap<OsString, FsEntry>,
+    inotify: Inotify,
+    watches: HashMap<WatchDescriptor, OsString>,
+    watched_dirs: BTreeMap<OsString, WatchDescriptor>,
+    /// Subtrees that could not be watched, rescanned at every checkpoint instead.
+    unwatched: BTreeSet<OsString>,
+    journal: Option<JournalWriter>,
+    changed: bool,
+    metrics: Metrics,
+    metrics_file: Option<PathBuf>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl LiveState {
+    fn is_ignored(&self, file_name: &OsStr) -> bool {
+        self.folders_to_ignore
+            .iter()
+            .any(|s| OsStr::new(s) == file_name)
+    }
+
+    fn add_watch(&mut self, name: &OsStr) {
+        if self.unwatched.contains(name) {
+            return;
+        }
+        match self
+            .inotify
+            .watches()
+            .add(self.root_path.join(name), watch_mask())
+        {
+            Ok(wd) => {
+                self.watches.insert(wd.clone(), name.to_os_string());
+                self.watched_dirs.insert(name.to_os_string(), wd);
+            }
+            Err(err) if err.raw_os_error() == Some(ENOSPC) => {
+                if self.unwatched.is_empty() {
+                    warn!(
+                        "Inotify watch limit reached, directories that cannot be watched are rescanned at each checkpoint"
+                    );
+                }
+                self.unwatched.insert(name.to_os_string());
+            }
+            // The directory was removed since it was listed, its deletion event follows.
+            Err(_) => {}
+        }
+    }
+
+    fn remove_watches(&mut self, name: &OsStr) {
+        let mut names: Vec<OsString> = self
+            .watched_dirs
+            .range(names_below(name))
+            .map(|(dir_name, _)| dir_name.clone())
+            .collect();
+        names.push(name.to_os_string());
+        for dir_name in names {
+            if let Some(wd) = self.watched_dirs.remove(&dir_name) {
+                self.watches.remove(&wd);
+                let _ = self.inotify.watches().remove(wd);
+            }
+        }
+    }
+
+    fn names_below(&self, name: &OsStr) -> Vec<OsString> {
+        self.entries
+            .range(names_below(name))
+            .map(|(entry_name, _)| entry_name.clone())
+            .collect()
+    }
+
+    /// Replaces the entry `name`, or removes it when `entry` is `None`, and journals the change.
+    fn set(&mut self, name: &OsStr, entry: Option<FsEntry>) {
+        let previous = match &entry {
+            Some(entry) => self.entries.insert(name.to_os_string(), entry.clone()),
+            None => self.entries.remove(name),
+        };
+        if previous == entry {
+            return;
+        }
+        self.changed = true;
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let (entry, is_deleted) = match (entry, previous) {
+            (Some(entry), _) => (entry, false),
+            (None, Some(previous)) => (previous, true),
+            (None, None) => return,
+        };
+        let result = journal.append(ChangedFsEntry {
+            name: entry.name,
+            is_deleted,
+            is_dir: entry.is_dir,
+            is_file: entry.is_file,
+            is_symlink: entry.is_symlink,
+        });
+        match result {
+            Ok(()) => self.metrics.increment(
+                "journal_records_total",
+                "Changes appended to the journal",
+                &[],
+                1.0,
+            ),
+            Err(err) => error!("{}", err),
+        }
+    }
+
+    /// Removes the entry `name` and everything below it, journaled as the deletion of `name` only.
+    fn remove_subtree(&mut self, name: &OsStr) {
+        for entry_name in self.names_below(name) {
+            self.entries.remove(&entry_name);
+        }
+        self.set(name, None);
+        self.remove_watches(name);
+        self.unwatched
+            .retain(|unwatched| !Path::new(unwatched).starts_with(name));
+    }
+
+    /// Refreshes the entry `name` from the file system, removing it when it no longer exists.
+    fn update(&mut self, name: &OsStr) {
+        if name.is_empty() {
+            return;
+        }
+        let entry = fs::symlink_metadata(self.root_path.join(name))
+            .ok()
+            .map(|metadata| FsEntry::from_metadata(name.to_os_string(), &metadata));
+        self.set(name, entry);
+    }
+
+    /// Lists the directory `name` (the root when empty) again and watches all directories below it.
+    /// Only the entries that differ from the previous listing are journaled.
+    fn scan_subtree(&mut self, name: &OsStr) {
+        let previous = self.names_below(name);
+        self.remove_watches(name);
+        self.unwatched
+            .retain(|unwatched| !Path::new(unwatched).starts_with(name));
+        self.update(name);
+        let mut scanned_names: HashSet<OsString> = HashSet::new();
+        let is_dir = name.is_empty()
+            || self
+                .entries
+                .get(name)
+                .map(|entry| entry.is_dir)
+                .unwrap_or(false);
+        if is_dir {
+            self.add_watch(name);
+            let scanned = utils_fs::walk_dir(
+                self.root_path.join(name),
+                self.parallelism.clone(),
+                false,
+                false,
+                false,
+                self.folders_to_ignore.clone(),
+                self.limiter.clone(),
+            );
+            for mut entry in scanned {
+                entry.name = utils_fs::relative_name(Path::new(&entry.name), &self.root_path);
+                if entry.is_dir {
+                    self.add_watch(&entry.name);
+                }
+                scanned_names.insert(entry.name.clone());
+                self.set(&entry.name.clone(), Some(entry));
+            }
+        }
+        for entry_name in previous {
+            if !scanned_names.contains(&entry_name) {
+                self.set(&entry_name, None);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event<&OsStr>) {
+        self.metrics
+            .increment("events_total", "Inotify events handled", &[], 1.0);
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            warn!("Inotify event queue overflowed, rescanning");
+            self.metrics.increment(
+                "queue_overflows_total",
+                "Inotify event queue overflows, each followed by a full rescan",
+                &[],
+                1.0,
+            );
+            self.scan_subtree(OsStr::new(""));
+            return;
+        }
+        let Some(dir_name) = self.watches.get(&event.wd).cloned() else {
+            return;
+        };
+        if event.mask.contains(EventMask::IGNORED) {
+            self.watches.remove(&event.wd);
+            self.watched_dirs.remove(&dir_name);
+            return;
+        }
+        let Some(file_name) = event.name else {
+            return;
+        };
+        if self.is_ignored(file_name) {
+            return;
+        }
+        let name = join_name(&dir_name, file_name);
+        if event
+            .mask
+            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+        {
+            if event.mask.contains(EventMask::ISDIR) {
+                self.scan_subtree(&name);
+            } else {
+                self.update(&name);
+            }
+            self.update(&dir_name);
+        } else if event
+            .mask
+            .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+        {
+            self.remove_subtree(&name);
+            self.update(&dir_name);
+        } else {
+            self.update(&name);
+        }
+    }
+
+    fn flush_journal(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            if let Err(err) = journal.flush() {
+                error!("{}", err);
+            }
+        }
+    }
+
+    /// Rescans the unwatched subtrees and writes the state to `path` when it changed, through a
+    /// temporary file so readers never see a partially written state.
+    fn checkpoint(&mut self, path: &Path, compression: Compression, level: i32) {
+        let unwatched: Vec<OsString> = std::mem::take(&mut self.unwatched).into_iter().collect();
+        for name in unwatched.iter() {
+            let covered = unwatched
+                .iter()
+                .any(|other| other != name && Path::new(name).starts_with(other));
+            if !covered {
+                self.scan_subtree(name);
+            }
+        }
+        self.record_checkpoint();
+        if !self.changed {
+            return;
+        }
+        let state = FsState::from_entries(self.entries.values().cloned().collect());
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        let result = write_state(&state, Path::new(&tmp_path), compression, level).and_then(|_| {
+            fs::rename(&tmp_path, path)
+                .map_err(|err| format!("Failed to rename to '{}'. Error : {}", path.display(), err))
+        });
+        match result {
+            Ok(()) => {
+                self.changed = false;
+                self.metrics.increment(
+                    "checkpoints_total",
+                    "State files written in watch mode",
+                    &[],
+                    1.0,
+                );
+                info!(
+                    entries = self.entries.len(),
+                    path = %path.display(),
+                    "Wrote checkpoint"
+                );
+            }
+            Err(err) => error!("{}", err),
+        }
+    }
+
+    /// Updates the gauges of the live state and writes the metrics file.
+    fn record_checkpoint(&self) {
+        self.metrics.set(
+            "entries",
+            "Number of entries in the state",
+            &[],
+            self.entries.len() as f64,
+        );
+        self.metrics.set(
+            "unwatched_directories",
+            "Subtrees rescanned at each checkpoint as they cannot be watched",
+            &[],
+            self.unwatched.len() as f64,
+        );
+        self.metrics.set(
+            "last_checkpoint_timestamp_seconds",
+            "Time of the last checkpoint",
+            &[],
+            unix_time(),
+        );
+        if let Some(metrics_file) = &self.metrics_file {
+            if let Err(err) = self.metrics.write_to_file(metrics_file) {
+                error!("{}", err);
+            }
+        }
+    }
+}
+
+/// Scans the directory, then keeps its state up to date from inotify events and writes it to the
+/// output file at every checkpoint. Never returns unless inotify fails.
+///
+/// Changes are appended to the journal as they are seen. When the output file already exists, the
+/// initial scan is compared to it so the changes made while not watching are journaled too.
+pub(crate) fn watch(args: &Args) -> Result<(), String> {
+    let Some(write_state_to) = args.write_state_to.as_deref() else {
+        unreachable!("clap requires a state file in watch mode");
+    };
+    let checkpoint_interval = Duration::from_secs(args.checkpoint_interval);
+    let inotify =
+        Inotify::init().map_err(|err| format!("Failed to initialize inotify. Error : {}", err))?;
+    // Every event rescans a subtree, they share one pool rather than building one each.
+    let parallelism = match args.parallelism() {
+        Parallelism::RayonNewPool(threads) => Parallelism::RayonExistingPool {
+            pool: Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|err| format!("Failed to build thread pool. Error : {}", err))?,
+            ),
+            busy_timeout: None,
+        },
+        parallelism => parallelism,
+    };
+    let mut live_state = LiveState {
+        root_path: args.path.clone(),
+        parallelism,
+        folders_to_ignore: args.folders_to_ignore.clone(),
+        entries: BTreeMap::new(),
+        inotify,
+        watches: HashMap::new(),
+        watched_dirs: BTreeMap::new(),
+        unwatched: BTreeSet::new(),
+        journal: None,
+        changed: true,
+        metrics: Metrics::new("fs_state_gen"),
+        metrics_file: args.metrics.metrics_file.clone(),
+        limiter: args.limiter(),
+    };
+    if let Some(metrics_address) = &args.metrics_address {
+        let address = live_state.metrics.serve(metrics_address)?;
+        info!(%address, "Serving metrics");
+    }
+    let mut journal = args
+        .journal
+        .as_deref()
+        .map(JournalWriter::open)
+        .transpose()?;
+    if let Some(journal) = &journal {
+        info!(sequence = journal.next_sequence(), "Opened journal");
+        if write_state_to.exists() {
+            live_state.entries = read_state(write_state_to)?
+                .into_fs_entries()
+                .entries
+                .into_iter()
+                .map(|entry| (entry.name.clone(), entry))
+                .collect();
+        }
+    }
+    if !live_state.entries.is_empty() {
+        live_state.journal = journal.take();
+    }
+    let started = Instant::now();
+    live_state.scan_subtree(OsStr::new(""));
+    record_scan(
+        &live_state.metrics,
+        live_state.entries.len(),
+        started.elapsed(),
+    );
+    live_state.journal = live_state.journal.take().or(journal);
+    live_state.flush_journal();
+    live_state.checkpoint(write_state_to, args.compression, args.compression_level);
+
+    let mut buffer = [0; 64 * 1024];
+    let mut last_checkpoint = Instant::now();
+    loop {
+        match live_state.inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                let events: Vec<Event<OsString>> = events.map(|event| event.to_owned()).collect();
+                for event in events {
+                    live_state.handle(Event {
+                        wd: event.wd,
+                        mask: event.mask,
+                        cookie: event.cookie,
+                        name: event.name.as_deref(),
+                    });
+                }
+                live_state.flush_journal();
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => return Err(format!("Failed to read inotify events. Error : {}", err)),
+        }
+        if last_checkpoint.elapsed() >= checkpoint_interval {
+            live_state.checkpoint(write_state_to, args.compression, args.compression_level);
+            last_checkpoint = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use utils::journal::JournalReader;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "fs_tools_watch_{}_{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("tree/d")).unwrap();
+            fs::write(path.join("tree/d/a"), "a").unwrap();
+            TempDir(path)
+        }
+
+        fn tree(&self) -> PathBuf {
+            self.0.join("tree")
+        }
+
+        /// Scans the tree into a new live state, journaling the changes seen after the scan to
+        /// `journal` when given.
+        fn live_state(&self, journal: Option<&str>) -> LiveState {
+            let mut live_state = LiveState {
+                root_path: self.tree(),
+                parallelism: Parallelism::Serial,
+                folders_to_ignore: Vec::new(),
+                entries: BTreeMap::new(),
+                inotify: Inotify::init().unwrap(),
+                watches: HashMap::new(),
+                watched_dirs: BTreeMap::new(),
+                unwatched: BTreeSet::new(),
+                journal: None,
+                changed: true,
+                metrics: Metrics::new("fs_state_gen"),
+                metrics_file: None,
+                limiter: None,
+            };
+            live_state.scan_subtree(OsStr::new(""));
+            live_state.journal =
+                journal.map(|journal| JournalWriter::open(&self.0.join(journal)).unwrap());
+            live_state
+        }
+
+        fn journaled(&self, journal: &str) -> Vec<(String, bool)> {
+            JournalReader::open(&self.0.join(journal))
+                .unwrap()
+                .map(|record| {
+                    let entry = record.unwrap().entry;
+                    (entry.name.into_string().unwrap(), entry.is_deleted)
+                })
+                .collect()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Handles the events queued so far, they are queued by the changes themselves.
+    fn handle_events(live_state: &mut LiveState) {
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let events: Vec<Event<OsString>> = match live_state.inotify.read_events(&mut buffer) {
+                Ok(events) => events.map(|event| event.to_owned()).collect(),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{}", err),
+            };
+            for event in events {
+                live_state.handle(Event {
+                    wd: event.wd,
+                    mask: event.mask,
+                    cookie: event.cookie,
+                    name: event.name.as_deref(),
+                });
+            }
+        }
+        live_state.flush_journal();
+    }
+
+    /// Drops the events queued so far without handling them.
+    fn drop_events(live_state: &mut LiveState) {
+        let mut buffer = [0; 64 * 1024];
+        while live_state.inotify.read_events(&mut buffer).is_ok() {}
+    }
+
+    fn names(live_state: &LiveState) -> Vec<&str> {
+        live_state
+            .entries
+            .keys()
+            .map(|name| name.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn tracks_changes_from_events() {
+        let dir = TempDir::new("events");
+        let mut live_state = dir.live_state(Some("journal"));
+        assert_eq!(names(&live_state), ["d", "d/a"]);
+
+        fs::write(dir.tree().join("d/b"), "b").unwrap();
+        fs::create_dir(dir.tree().join("e")).unwrap();
+        fs::write(dir.tree().join("e/f"), "f").unwrap();
+        fs::rename(dir.tree().join("d/a"), dir.tree().join("e/g")).unwrap();
+        handle_events(&mut live_state);
+
+        assert_eq!(names(&live_state), ["d", "d/b", "e", "e/f", "e/g"]);
+        assert_eq!(live_state.entries, dir.live_state(None).entries);
+        let journaled = dir.journaled("journal");
+        for change in [
+            ("d/b", false),
+            ("e/f", false),
+            ("d/a", true),
+            ("e/g", false),
+        ] {
+            assert!(
+                journaled.contains(&(String::from(change.0), change.1)),
+                "{:?}",
+                journaled
+            );
+        }
+
+        fs::remove_dir_all(dir.tree().join("e")).unwrap();
+        handle_events(&mut live_state);
+        assert_eq!(names(&live_state), ["d", "d/b"]);
+        assert!(live_state.watched_dirs.keys().all(|name| name != "e"));
+    }
+
+    #[test]
+    fn writes_checkpoints_only_after_changes() {
+        let dir = TempDir::new("checkpoints");
+        let state_path = dir.0.join("state");
+        let mut live_state = dir.live_state(Some("journal"));
+        live_state.checkpoint(&state_path, Compression::None, 0);
+        assert!(state_path.exists());
+
+        // Events that leave the entries as they were are neither journaled nor written.
+        fs::remove_file(&state_path).unwrap();
+        drop(
+            File::options()
+                .append(true)
+                .open(dir.tree().join("d/a"))
+                .unwrap(),
+        );
+        handle_events(&mut live_state);
+        live_state.checkpoint(&state_path, Compression::None, 0);
+        assert!(!state_path.exists());
+        assert!(dir.journaled("journal").is_empty());
+
+        // Any number of changes between two checkpoints is written once.
+        for contents in ["ab", "abc", "abcd"] {
+            fs::write(dir.tree().join("d/a"), contents).unwrap();
+        }
+        handle_events(&mut live_state);
+        live_state.checkpoint(&state_path, Compression::None, 0);
+        let state = read_state(&state_path).unwrap().into_fs_entries();
+        let entry = state
+            .entries
+            .iter()
+            .find(|entry| entry.name == "d/a")
+            .unwrap();
+        assert_eq!(entry.size, 4);
+        assert!(!live_state.changed);
+    }
+
+    #[test]
+    fn rescans_after_lost_events() {
+        let dir = TempDir::new("rescan");
+        let mut live_state = dir.live_state(None);
+        fs::write(dir.tree().join("d/b"), "b").unwrap();
+        drop_events(&mut live_state);
+        let wd = live_state.watched_dirs[OsStr::new("d")].clone();
+        live_state.handle(Event {
+            wd,
+            mask: EventMask::Q_OVERFLOW,
+            cookie: 0,
+            name: None,
+        });
+        assert_eq!(names(&live_state), ["d", "d/a", "d/b"]);
+
+        // Subtrees that could not be watched are rescanned at each checkpoint.
+        live_state.remove_watches(OsStr::new("d"));
+        live_state.unwatched.insert(OsString::from("d"));
+        fs::write(dir.tree().join("d/c"), "c").unwrap();
+        drop_events(&mut live_state);
+        live_state.checkpoint(&dir.0.join("state"), Compression::None, 0);
+        assert_eq!(names(&live_state), ["d", "d/a", "d/b", "d/c"]);
+        assert!(live_state.unwatched.is_empty());
+        assert!(live_state.watched_dirs.contains_key(OsStr::new("d")));
+    }
+}
@@ -1,6 +1,16 @@
 use clap::Parser;
+use jwalk::Parallelism;
 use std::{num::NonZeroUsize, path::PathBuf};
-use utils::arg_parsers::{check_if_directory_exists, check_if_file_exists};
+use utils::arg_parsers::{
+    check_if_directory_exists, check_if_file_exists, check_if_parent_path_exists,
+};
+use utils::logging::LogArgs;
+use utils::metrics::MetricsArgs;
+
+use crate::conflicts::ConflictPolicy;
+use crate::endpoint::Endpoint;
+use crate::priority::IoClass;
+use utils::throttle::Schedule;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -22,28 +32,56 @@ pub(crate) struct Args {
         id = "source path",
         long = "path-source",
         short = 's',
-        value_parser = check_if_directory_exists(),
+        value_parser = Endpoint::parse,
         help = "",
-        long_help = "Path to the source directory"
+        long_help = "Path to the source directory, local or remote as user@host:/path through the remote shell or rsync://host/module/path and host::module/path from an rsync daemon. Only one of the source and destination can be remote"
     )]
-    pub src_path: PathBuf,
+    pub src_path: Endpoint,
     #[arg(
         id = "destination path",
         long = "path-destination",
         short = 'd',
-        value_parser = check_if_directory_exists(),
+        value_parser = Endpoint::parse,
         help = "",
-        long_help = "Path to the destination directory"
+        long_help = "Path to the destination directory, local or remote like the source path"
     )]
-    pub dst_path: PathBuf,
+    pub dst_path: Endpoint,
     #[arg(
         id = "state diff file",
         long = "diff",
         value_parser = check_if_file_exists(),
+        required_unless_present = "journal file",
+        conflicts_with = "journal file",
+        help = "",
+        long_help = "Path to read the differences between the source and destination filesystem states. The differences of the directories relocated by fs_compare rewrite rules, written next to it with .1, .2 and so on appended to its name, are synced too, each from its directory on the source to its directory on the destination"
+    )]
+    pub read_diff_from: Option<PathBuf>,
+    #[arg(
+        id = "journal file",
+        long = "journal",
+        value_parser = check_if_file_exists(),
+        conflicts_with_all = ["reverse state diff file", "conflicts file", "update base state"],
+        help = "",
+        long_help = "Path to read a change journal written by fs_state_gen in watch mode, to sync the changes it recorded instead of the differences of a diff file. Prints the sequence number to resume from on the next run"
+    )]
+    pub read_journal_from: Option<PathBuf>,
+    #[arg(
+        id = "journal sequence",
+        long = "journal-from",
+        default_value = "0",
+        requires = "journal file",
+        help = "",
+        long_help = "Sequence number of the first journal record to sync. Exits with code 76 when the journal was truncated past it or cannot be read, the changes have to be found by comparing the states in full then"
+    )]
+    pub journal_from: u64,
+    #[arg(
+        id = "truncate journal",
+        long = "truncate-journal",
+        requires = "journal file",
         help = "",
-        long_help = "Path to read the differences between the source and destination filesystem states"
+        long_help = "Remove the synced records from the journal once all of them were synced, so it does not grow forever. fs_state_gen can keep appending to it meanwhile"
     )]
-    pub read_diff_from: PathBuf,
+    pub truncate_journal: bool,
     #[arg(
         id = "chunk_size",
         long,
@@ -67,6 +105,66 @@ pub(crate) struct Args {
         long_help = "Delete destination filesystem entries that are not present in the source filesystem"
     )]
     pub delete_destination: Option<bool>,
+    #[arg(
+        id = "reverse state diff file",
+        long = "diff-reverse",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to read the changes made on the destination to apply to the source, written by a three-way fs_compare. Syncs in both directions, deleting entries on the source too with --delete-destination"
+    )]
+    pub read_reverse_diff_from: Option<PathBuf>,
+    #[arg(
+        id = "conflicts file",
+        long = "conflicts",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to read the entries changed on both sides, written by a three-way fs_compare, to resolve with the conflict policy. Needs local source and destination paths"
+    )]
+    pub read_conflicts_from: Option<PathBuf>,
+    #[arg(
+        id = "conflict policy",
+        long = "conflict-policy",
+        value_enum,
+        default_value_t = ConflictPolicy::Skip,
+        help = "",
+        long_help = "How to resolve the entries changed on both sides"
+    )]
+    pub conflict_policy: ConflictPolicy,
+    #[arg(
+        id = "conflict suffix",
+        long = "conflict-suffix",
+        default_value = ".conflict",
+        help = "",
+        long_help = "Suffix appended to the name of the destination entries kept by the keep-both conflict policy, followed by .1, .2 and so on when that name is taken"
+    )]
+    pub conflict_suffix: String,
+    #[arg(
+        id = "update base state",
+        long = "update-base",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the state of the source after a successful run, to use as the base state of the next three-way comparison. Skipped conflicts are left out of it. Needs a local source path"
+    )]
+    pub update_base_state_to: Option<PathBuf>,
+    #[arg(
+        id = "update destination base state",
+        long = "update-base-destination",
+        requires = "update base state",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the state of the destination after a successful run, to use as the destination base state of the next three-way comparison, so the owners, groups or modes the destination keeps different from the source are not seen as changes. Skipped conflicts are left out of it. Needs a local destination path"
+    )]
+    pub update_dst_base_state_to: Option<PathBuf>,
+    #[arg(
+        id = "folders to ignore",
+        long = "ignore-folders",
+        num_args = 0..,
+        default_value = ".snapshot .zfs",
+        help = "",
+        value_delimiter = ' ',
+        long_help = "Folders to skip when scanning the source for the base state, the same as given to fs_state_gen for the source state"
+    )]
+    pub folders_to_ignore: Vec<String>,
     #[arg(
         id = "temporary directory",
         long = "tmp-dir",
@@ -76,6 +174,157 @@ pub(crate) struct Args {
         long_help = "Temporary directory to store intermediate files"
     )]
     pub tmp_dir: PathBuf,
+    #[arg(
+        id = "resumed job",
+        long = "resume",
+        conflicts_with = "chunk_size",
+        help = "",
+        long_help = "Id of an interrupted or failed job to resume, given with the same arguments as the job. Its job directory is reused and the chunks its manifest records as synced are skipped when their entries are unchanged"
+    )]
+    pub resume: Option<String>,
+    #[arg(
+        id = "grace period",
+        long = "grace-period",
+        default_value = "10",
+        help = "",
+        long_help = "Number of seconds rsync processes are given to exit once run_rsync is interrupted by SIGINT or SIGTERM, before they are killed. A second signal exits right away"
+    )]
+    pub grace_period: u64,
+    #[arg(
+        id = "chunk timeout",
+        long = "chunk-timeout",
+        help = "",
+        long_help = "Number of seconds after which the rsync process of a chunk is terminated and the chunk is queued again"
+    )]
+    pub chunk_timeout: Option<u64>,
+    #[arg(
+        id = "stall timeout",
+        long = "stall-timeout",
+        help = "",
+        long_help = "Number of seconds without progress after which the rsync process of a chunk is terminated and the chunk is queued again. Progress is output of rsync or lines added to its log file, which only logs a file once it is transferred, so the timeout has to be longer than the transfer of the largest file unless rsync is given --info=progress2. The files rsync had open are reported on Linux"
+    )]
+    pub stall_timeout: Option<u64>,
+    #[arg(
+        id = "chunk retries",
+        long = "chunk-retries",
+        default_value = "1",
+        help = "",
+        long_help = "Number of times a chunk that timed out or stalled is queued again, once all the chunks of its run were processed"
+    )]
+    pub chunk_retries: usize,
+    #[arg(
+        id = "remote shell",
+        long = "remote-shell",
+        default_value = "ssh",
+        help = "",
+        long_help = "Remote shell command rsync reaches user@host:/path endpoints with, run in batch mode so it fails instead of prompting for a password"
+    )]
+    pub remote_shell: String,
+    #[arg(
+        id = "ssh options",
+        long = "ssh-option",
+        help = "",
+        long_help = "Option passed to the remote shell with -o, such as Port=2222 or IdentityFile=~/.ssh/backup. Can be given several times"
+    )]
+    pub ssh_options: Vec<String>,
+    #[arg(
+        id = "connect timeout",
+        long = "connect-timeout",
+        default_value = "10",
+        help = "",
+        long_help = "Number of seconds to wait for a connection to a remote endpoint"
+    )]
+    pub connect_timeout: u64,
+    #[arg(
+        id = "password file",
+        long = "password-file",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the file holding the password of the rsync daemon user"
+    )]
+    pub password_file: Option<PathBuf>,
+    #[arg(
+        id = "max connections per host",
+        long = "max-connections-per-host",
+        help = "",
+        long_help = "Maximum number of rsync processes connected to a remote host at once, defaults to the number of threads. Chunks wait for a connection before their timeouts start"
+    )]
+    pub max_connections_per_host: Option<NonZeroUsize>,
+    #[arg(
+        id = "total bandwidth limit",
+        long = "total-bwlimit",
+        value_parser = Schedule::parse,
+        help = "",
+        long_help = "Maximum bandwidth of the job in KiB per second, split evenly between the chunks that can run at once, as many as threads or connections per host, and given to each rsync process with --bwlimit. Either a single limit or comma separated time of day windows such as 08:00-18:00=10000, optionally followed by the limit for the rest of the day, as in 08:00-18:00=10000,100000. Windows use the local time and can wrap around midnight, chunks keep the limit they started with"
+    )]
+    pub total_bwlimit: Option<Schedule>,
+    #[arg(
+        id = "nice",
+        long = "nice",
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        allow_negative_numbers = true,
+        help = "",
+        long_help = "Niceness to run the rsync processes with, from -20 to 19, negative values need root"
+    )]
+    pub nice: Option<i32>,
+    #[arg(
+        id = "io class",
+        long = "ionice-class",
+        value_enum,
+        help = "",
+        long_help = "I/O scheduling class to run the rsync processes with, as with ionice -c. Only supported on Linux"
+    )]
+    pub io_class: Option<IoClass>,
+    #[arg(
+        id = "io level",
+        long = "ionice-level",
+        default_value = "4",
+        value_parser = clap::value_parser!(u8).range(0..=7),
+        requires = "io class",
+        help = "",
+        long_help = "Priority within the I/O scheduling class, from 0, the highest, to 7"
+    )]
+    pub io_level: u8,
+    #[arg(
+        id = "id map file",
+        long = "id-map",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to a TOML file mapping source user and group ids or names to destination ones, in [users] and [groups] tables"
+    )]
+    pub id_map: Option<PathBuf>,
+    #[arg(
+        id = "source passwd file",
+        long = "source-passwd",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the passwd file of the source system, used to resolve user names. Users with the same name in both passwd files are mapped automatically"
+    )]
+    pub src_passwd: Option<PathBuf>,
+    #[arg(
+        id = "source group file",
+        long = "source-group",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the group file of the source system, used to resolve group names. Groups with the same name in both group files are mapped automatically"
+    )]
+    pub src_group: Option<PathBuf>,
+    #[arg(
+        id = "destination passwd file",
+        long = "destination-passwd",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the passwd file of the destination system, used to resolve user names"
+    )]
+    pub dst_passwd: Option<PathBuf>,
+    #[arg(
+        id = "destination group file",
+        long = "destination-group",
+        value_parser = check_if_file_exists(),
+        help = "",
+        long_help = "Path to the group file of the destination system, used to resolve group names"
+    )]
+    pub dst_group: Option<PathBuf>,
     #[arg(
         id = "rsync arguments",
         long="rsync-args",
@@ -86,13 +335,16 @@ pub(crate) struct Args {
         long_help = "Arguments to pass to rsync command"
     )]
     pub rsync_args: Vec<String>,
+    #[command(flatten)]
+    pub metrics: MetricsArgs,
+    #[command(flatten)]
+    pub log: LogArgs,
 }
 
 impl Args {
     pub fn threads(&self) -> usize {
         let cpus = num_cpus::get();
         self.threads
-            .clone()
             .unwrap_or_else(|| {
                 if cfg!(target_vendor = "apple") {
                     NonZeroUsize::new(cpus).unwrap()
@@ -102,4 +354,11 @@ impl Args {
             })
             .get()
     }
+
+    pub fn parallelism(&self) -> Parallelism {
+        match self.threads() {
+            1 => Parallelism::Serial,
+            n => Parallelism::RayonNewPool(n),
+        }
+    }
 }
@@ -0,0 +1,330 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::{Child, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    flag,
+};
+use tracing::warn;
+
+/// Delay between two checks of a running rsync process.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How a waited for rsync process ended.
+pub(crate) enum Exit {
+    Finished(ExitStatus),
+    /// Terminated after the job was interrupted.
+    Interrupted,
+    /// Terminated after running for longer than the chunk timeout, with the files it had open.
+    TimedOut(Vec<PathBuf>),
+    /// Terminated after making no progress for the stall timeout, with the files it had open.
+    Stalled(Vec<PathBuf>),
+}
+
+/// Time limits of the rsync process of a chunk.
+pub(crate) struct Limits {
+    pub timeout: Option<Duration>,
+    /// Longest time without progress.
+    pub stall_timeout: Option<Duration>,
+}
+
+/// Returns the files below `roots` held open by the processes of the process group `group`.
+#[cfg(target_os = "linux")]
+fn open_files(group: u32, roots: &[&Path]) -> Vec<PathBuf> {
+    use std::fs;
+
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = processes
+        .filter_map(|process| process.ok())
+        .filter(|process| {
+            // The process group is the third field after the command name, which is in parentheses
+            // and may contain spaces.
+            fs::read_to_string(process.path().join("stat"))
+                .ok()
+                .and_then(|stat| {
+                    let fields = &stat[stat.rfind(')')? + 1..];
+                    fields.split_whitespace().nth(2)?.parse::<u32>().ok()
+                })
+                == Some(group)
+        })
+        .filter_map(|process| fs::read_dir(process.path().join("fd")).ok())
+        .flat_map(|fds| fds.filter_map(|fd| fs::read_link(fd.ok()?.path()).ok()))
+        .filter(|file| roots.iter().any(|root| file.starts_with(root)))
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Open files are found through /proc, which only Linux has.
+#[cfg(not(target_os = "linux"))]
+fn open_files(_group: u32, _roots: &[&Path]) -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Tracks SIGINT and SIGTERM, after which no new chunk is started and running rsync processes
+/// are terminated. A second signal exits right away.
+#[derive(Clone)]
+pub(crate) struct Cancellation {
+    interrupted: Arc<AtomicBool>,
+    grace_period: Duration,
+}
+
+impl Cancellation {
+    pub fn install(grace_period: Duration) -> Result<Cancellation, String> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it only exits on the signals received once the flag is set.
+            flag::register_conditional_shutdown(
+                signal,
+                crate::INTERRUPTED_EXIT_CODE,
+                interrupted.clone(),
+            )
+            .and_then(|_| flag::register(signal, interrupted.clone()))
+            .map_err(|err| format!("Failed to install signal handler. Error : {}", err))?;
+        }
+        Ok(Cancellation {
+            interrupted,
+            grace_period,
+        })
+    }
+
+    /// Returns a cancellation tracking no signal, interrupted with `interrupt`.
+    #[cfg(test)]
+    pub fn new(grace_period: Duration) -> Cancellation {
+        Cancellation {
+            interrupted: Arc::new(AtomicBool::new(false)),
+            grace_period,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Waits for `child`, started in its own process group. Once the job is interrupted or
+    /// `limits` are exceeded the group is sent SIGTERM, then SIGKILL when it is still running after
+    /// the grace period.
+    ///
+    /// `progress` returns a value that changes whenever `child` makes progress. The files below
+    /// `roots` that `child` has open are reported when it times out. A process that does not exit
+    /// after SIGKILL, as when blocked on a hung NFS mount, is left behind.
+    pub fn wait(
+        &self,
+        child: &mut Child,
+        limits: &Limits,
+        progress: impl Fn() -> u64,
+        roots: &[&Path],
+    ) -> io::Result<Exit> {
+        let group = -(child.id() as libc::pid_t);
+        let started = Instant::now();
+        let mut last_progress = (progress(), Instant::now());
+        let mut terminated: Option<(Instant, Exit)> = None;
+        let mut killed_at: Option<Instant> = None;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(match terminated {
+                    Some((_, exit)) => exit,
+                    None => Exit::Finished(status),
+                });
+            }
+            match (&terminated, killed_at) {
+                (None, _) => {
+                    let current_progress = progress();
+                    if current_progress != last_progress.0 {
+                        last_progress = (current_progress, Instant::now());
+                    }
+                    let exit = if self.is_interrupted() {
+                        Some(Exit::Interrupted)
+                    } else if limits
+                        .timeout
+                        .is_some_and(|timeout| started.elapsed() >= timeout)
+                    {
+                        Some(Exit::TimedOut(open_files(child.id(), roots)))
+                    } else if limits
+                        .stall_timeout
+                        .is_some_and(|stall_timeout| last_progress.1.elapsed() >= stall_timeout)
+                    {
+                        Some(Exit::Stalled(open_files(child.id(), roots)))
+                    } else {
+                        None
+                    };
+                    if let Some(exit) = exit {
+                        // SAFETY: kill has no memory safety requirements.
+                        unsafe { libc::kill(group, libc::SIGTERM) };
+                        terminated = Some((Instant::now(), exit));
+                    }
+                }
+                (Some((at, _)), None) if at.elapsed() >= self.grace_period => {
+                    warn!(
+                        pid = child.id(),
+                        "Killing rsync process after the grace period"
+                    );
+                    // SAFETY: kill has no memory safety requirements.
+                    unsafe { libc::kill(group, libc::SIGKILL) };
+                    killed_at = Some(Instant::now());
+                }
+                (Some(_), Some(at)) if at.elapsed() >= self.grace_period => {
+                    warn!(
+                        pid = child.id(),
+                        "Leaving behind rsync process that did not exit once killed"
+                    );
+                    return Ok(terminated.map(|(_, exit)| exit).unwrap());
+                }
+                _ => {}
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::process::CommandExt, process::Command, sync::atomic::AtomicU64};
+
+    const NO_LIMITS: Limits = Limits {
+        timeout: None,
+        stall_timeout: None,
+    };
+
+    fn spawn(script: &str) -> Child {
+        Command::new("sh")
+            .args(["-c", script])
+            .process_group(0)
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn waits_for_finished_processes() {
+        let cancellation = Cancellation::new(Duration::from_secs(10));
+        let mut child = spawn("exit 3");
+        match cancellation
+            .wait(&mut child, &NO_LIMITS, || 0, &[])
+            .unwrap()
+        {
+            Exit::Finished(status) => assert_eq!(status.code(), Some(3)),
+            _ => panic!("not finished"),
+        }
+    }
+
+    #[test]
+    fn terminates_interrupted_processes() {
+        let cancellation = Cancellation::new(Duration::from_secs(10));
+        let mut child = spawn("sleep 10");
+        let started = Instant::now();
+        let interrupted = cancellation.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            interrupted.interrupt();
+        });
+        let exit = cancellation
+            .wait(&mut child, &NO_LIMITS, || 0, &[])
+            .unwrap();
+        assert!(matches!(exit, Exit::Interrupted));
+        assert!(cancellation.is_interrupted());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn kills_processes_ignoring_termination_after_the_grace_period() {
+        let grace_period = Duration::from_millis(200);
+        let cancellation = Cancellation::new(grace_period);
+        // The ignored signal is inherited by sleep, so only SIGKILL stops the group.
+        let mut child = spawn("trap '' TERM; sleep 10");
+        thread::sleep(Duration::from_millis(100));
+        cancellation.interrupt();
+        let started = Instant::now();
+        let exit = cancellation
+            .wait(&mut child, &NO_LIMITS, || 0, &[])
+            .unwrap();
+        assert!(matches!(exit, Exit::Interrupted));
+        assert!(started.elapsed() >= grace_period);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn terminates_processes_running_past_the_timeout() {
+        let cancellation = Cancellation::new(Duration::from_secs(10));
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(200)),
+            stall_timeout: None,
+        };
+        let mut child = spawn("sleep 10");
+        let started = Instant::now();
+        let exit = cancellation.wait(&mut child, &limits, || 0, &[]).unwrap();
+        assert!(matches!(exit, Exit::TimedOut(_)));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!cancellation.is_interrupted());
+    }
+
+    #[test]
+    fn terminates_processes_making_no_progress() {
+        let cancellation = Cancellation::new(Duration::from_secs(10));
+        let limits = Limits {
+            timeout: None,
+            stall_timeout: Some(Duration::from_millis(200)),
+        };
+        let mut child = spawn("sleep 10");
+        let exit = cancellation.wait(&mut child, &limits, || 0, &[]).unwrap();
+        assert!(matches!(exit, Exit::Stalled(_)));
+
+        // A process making progress runs past the stall timeout.
+        let polls = AtomicU64::new(0);
+        let mut child = spawn("sleep 1");
+        let exit = cancellation
+            .wait(
+                &mut child,
+                &limits,
+                || polls.fetch_add(1, Ordering::Relaxed),
+                &[],
+            )
+            .unwrap();
+        assert!(matches!(exit, Exit::Finished(status) if status.success()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reports_the_files_open_when_timing_out() {
+        let dir = std::env::temp_dir().join(format!("fs_tools_cancel_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        std::fs::write(&file, "").unwrap();
+        let cancellation = Cancellation::new(Duration::from_secs(10));
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(500)),
+            stall_timeout: None,
+        };
+        let mut child = spawn(&format!("sleep 10 < '{}'", file.display()));
+        let exit = cancellation
+            .wait(&mut child, &limits, || 0, &[&dir])
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        match exit {
+            Exit::TimedOut(in_flight) => assert_eq!(in_flight, [file]),
+            _ => panic!("not timed out"),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    ffi::OsString,
+    fs::{self, Metadata},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use tracing::error;
+use utils::fs::{escape_name, ChangedFsEntry};
+
+use crate::manifest::{JobManifest, Renamed};
+
+/// How entries changed on both sides since the last sync are resolved.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub(crate) enum ConflictPolicy {
+    /// The side with the most recent modification time replaces the other.
+    Newest,
+    /// The source side replaces the destination side.
+    Source,
+    /// The source side is kept under the original name and the destination side is renamed with a suffix, on both sides.
+    /// A counter is added after the suffix when the name is taken.
+    KeepBoth,
+    /// Nothing is changed and the entry is reported.
+    #[default]
+    Skip,
+}
+
+/// Transfers and deletions resolving a list of conflicts.
+#[derive(Default)]
+pub(crate) struct Resolution {
+    /// Entries to copy from the source to the destination.
+    pub forward: Vec<ChangedFsEntry>,
+    /// Entries to copy from the destination to the source.
+    pub reverse: Vec<ChangedFsEntry>,
+    /// Entries to remove from the destination.
+    pub delete_destination: Vec<ChangedFsEntry>,
+    /// Entries left unresolved.
+    pub skipped: Vec<OsString>,
+}
+
+fn entry(name: OsString, metadata: &Metadata, is_deleted: bool) -> ChangedFsEntry {
+    ChangedFsEntry {
+        name,
+        is_deleted,
+        is_dir: metadata.is_dir(),
+        is_file: metadata.is_file(),
+        is_symlink: metadata.is_symlink(),
+    }
+}
+
+/// Returns `name` with `suffix`, followed by `.<counter>` from the second candidate on.
+fn renamed_name(name: &OsString, suffix: &str, counter: usize) -> OsString {
+    let mut renamed = name.clone();
+    renamed.push(suffix);
+    if counter > 0 {
+        renamed.push(format!(".{}", counter));
+    }
+    renamed
+}
+
+/// Returns the counter of the first name of `name` renamed with `suffix` that is free in
+/// `dst_path`, so existing entries are never replaced.
+fn free_counter(dst_path: &Path, name: &OsString, suffix: &str) -> usize {
+    (0..)
+        .find(|counter| {
+            fs::symlink_metadata(dst_path.join(renamed_name(name, suffix, *counter))).is_err()
+        })
+        .unwrap()
+}
+
+impl Resolution {
+    fn newest(&mut self, name: OsString, src: Option<&Metadata>, dst: Option<&Metadata>) {
+        // A deletion has no time, so the side still holding the entry wins.
+        match (src, dst) {
+            (Some(src), Some(dst)) if dst.mtime() > src.mtime() => {
+                self.reverse.push(entry(name, dst, false))
+            }
+            (Some(src), _) => self.forward.push(entry(name, src, false)),
+            (None, Some(dst)) => self.reverse.push(entry(name, dst, false)),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Decides how to resolve each conflicting entry under `policy`, looking at the entries as they
+/// are now in `src_path` and `dst_path`. Destination entries kept by [`ConflictPolicy::KeepBoth`]
+/// are renamed right away, after recording their new name in `manifest`. A destination entry the
+/// resumed job already renamed, as recorded there, is kept as if it had just been renamed.
+pub(crate) fn resolve(
+    conflicts: &[ChangedFsEntry],
+    policy: ConflictPolicy,
+    suffix: &str,
+    src_path: &Path,
+    dst_path: &Path,
+    manifest: &JobManifest,
+) -> Resolution {
+    let mut resolution = Resolution::default();
+    for (index, conflict) in conflicts.iter().enumerate() {
+        let name = conflict.name.clone();
+        let src = fs::symlink_metadata(src_path.join(&name)).ok();
+        let dst = fs::symlink_metadata(dst_path.join(&name)).ok();
+        match policy {
+            ConflictPolicy::Skip => resolution.skipped.push(name),
+            ConflictPolicy::Source => match (&src, &dst) {
+                (Some(src), _) => resolution.forward.push(entry(name, src, false)),
+                (None, Some(dst)) => resolution.delete_destination.push(entry(name, dst, true)),
+                (None, None) => {}
+            },
+            ConflictPolicy::Newest => resolution.newest(name, src.as_ref(), dst.as_ref()),
+            ConflictPolicy::KeepBoth => match (&src, &dst) {
+                // Directories cannot be renamed without their contents, they are resolved as newest.
+                (Some(src), Some(dst)) if !src.is_dir() && !dst.is_dir() => {
+                    let counter = free_counter(dst_path, &name, suffix);
+                    let renamed = renamed_name(&name, suffix, counter);
+                    // Recorded first, so an interrupted job never leaves an entry renamed
+                    // without a record of its new name.
+                    let recorded = Renamed {
+                        conflict: index,
+                        counter,
+                    };
+                    if let Err(err) = manifest.set_renamed(recorded) {
+                        error!("{}", err);
+                        resolution.skipped.push(name);
+                        continue;
+                    }
+                    if let Err(err) = fs::rename(dst_path.join(&name), dst_path.join(&renamed)) {
+                        error!(
+                            path = %escape_name(dst_path.join(&name).as_os_str()),
+                            "Failed to rename. Error : {}",
+                            err
+                        );
+                        resolution.skipped.push(name);
+                        continue;
+                    }
+                    resolution.reverse.push(entry(renamed, dst, false));
+                    resolution.forward.push(entry(name, src, false));
+                }
+                (Some(src), None) if !src.is_dir() => {
+                    let renamed = manifest
+                        .renamed(index)
+                        .map(|counter| renamed_name(&name, suffix, counter));
+                    match renamed.and_then(|renamed| {
+                        let dst = fs::symlink_metadata(dst_path.join(&renamed)).ok()?;
+                        Some((renamed, dst))
+                    }) {
+                        Some((renamed, dst)) if !dst.is_dir() => {
+                            resolution.reverse.push(entry(renamed, &dst, false));
+                            resolution.forward.push(entry(name, src, false));
+                        }
+                        _ => resolution.newest(name, Some(src), None),
+                    }
+                }
+                _ => resolution.newest(name, src.as_ref(), dst.as_ref()),
+            },
+        }
+    }
+    resolution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{Manifest, Status};
+    use std::{
+        fs::File,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "fs_tools_conflicts_{}_{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            for dir in ["src", "dst"] {
+                fs::create_dir_all(path.join(dir)).unwrap();
+            }
+            TempDir(path)
+        }
+
+        fn src(&self) -> PathBuf {
+            self.0.join("src")
+        }
+
+        fn dst(&self) -> PathBuf {
+            self.0.join("dst")
+        }
+
+        fn manifest(&self) -> JobManifest {
+            JobManifest::new(
+                self.0.join("manifest.toml"),
+                Manifest {
+                    job_id: String::from("job"),
+                    status: Status::Running,
+                    chunk_size: 1,
+                    summary: None,
+                    chunks: Vec::new(),
+                    renamed: Vec::new(),
+                },
+            )
+        }
+
+        /// Returns the manifest of a job resuming the one whose manifest was written.
+        fn resumed_manifest(&self) -> JobManifest {
+            let path = self.0.join("manifest.toml");
+            JobManifest::new(path.clone(), Manifest::load(&path).unwrap())
+        }
+
+        fn resolve(&self, names: &[&str], policy: ConflictPolicy) -> Resolution {
+            self.resolve_with(names, policy, &self.manifest())
+        }
+
+        fn resolve_with(
+            &self,
+            names: &[&str],
+            policy: ConflictPolicy,
+            manifest: &JobManifest,
+        ) -> Resolution {
+            let conflicts: Vec<ChangedFsEntry> = names
+                .iter()
+                .map(|name| ChangedFsEntry {
+                    name: OsString::from(name),
+                    is_deleted: false,
+                    is_dir: false,
+                    is_file: true,
+                    is_symlink: false,
+                })
+                .collect();
+            resolve(
+                &conflicts,
+                policy,
+                ".conflict",
+                &self.src(),
+                &self.dst(),
+                manifest,
+            )
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write(path: PathBuf, contents: &str, age: u64) {
+        fs::write(&path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn names(entries: &[ChangedFsEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.name.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn skips_or_takes_the_source() {
+        let dir = TempDir::new("source");
+        write(dir.src().join("both"), "a", 0);
+        write(dir.dst().join("both"), "b", 0);
+        write(dir.dst().join("removed"), "b", 0);
+
+        let resolution = dir.resolve(&["both", "removed"], ConflictPolicy::Skip);
+        assert_eq!(resolution.skipped, ["both", "removed"]);
+        assert!(resolution.forward.is_empty() && resolution.reverse.is_empty());
+
+        let resolution = dir.resolve(&["both", "removed"], ConflictPolicy::Source);
+        assert_eq!(names(&resolution.forward), ["both"]);
+        assert!(resolution.reverse.is_empty());
+        assert_eq!(names(&resolution.delete_destination), ["removed"]);
+        assert!(resolution.delete_destination[0].is_deleted);
+    }
+
+    #[test]
+    fn takes_the_newest() {
+        let dir = TempDir::new("newest");
+        write(dir.src().join("newer_src"), "a", 0);
+        write(dir.dst().join("newer_src"), "b", 60);
+        write(dir.src().join("newer_dst"), "a", 60);
+        write(dir.dst().join("newer_dst"), "b", 0);
+        write(dir.dst().join("removed_src"), "b", 0);
+
+        let resolution = dir.resolve(
+            &["newer_src", "newer_dst", "removed_src"],
+            ConflictPolicy::Newest,
+        );
+        assert_eq!(names(&resolution.forward), ["newer_src"]);
+        assert_eq!(names(&resolution.reverse), ["newer_dst", "removed_src"]);
+        assert!(resolution.delete_destination.is_empty());
+    }
+
+    #[test]
+    fn keeps_both_under_a_free_name() {
+        let dir = TempDir::new("keep_both");
+        write(dir.src().join("f"), "a", 0);
+        write(dir.dst().join("f"), "b", 0);
+        write(dir.dst().join("f.conflict"), "c", 0);
+        fs::create_dir(dir.src().join("d")).unwrap();
+        fs::create_dir(dir.dst().join("d")).unwrap();
+
+        let manifest = dir.manifest();
+        let resolution = dir.resolve_with(&["d", "f"], ConflictPolicy::KeepBoth, &manifest);
+        assert_eq!(names(&resolution.forward), ["d", "f"]);
+        assert_eq!(names(&resolution.reverse), ["f.conflict.1"]);
+        assert_eq!(fs::read(dir.dst().join("f.conflict.1")).unwrap(), b"b");
+        assert_eq!(fs::read(dir.dst().join("f.conflict")).unwrap(), b"c");
+        assert!(!dir.dst().join("f").exists());
+        assert_eq!(manifest.renamed(1), Some(1));
+        assert_eq!(manifest.renamed(0), None);
+    }
+
+    #[test]
+    fn resumes_with_the_recorded_name() {
+        let dir = TempDir::new("resume");
+        write(dir.src().join("f"), "a", 0);
+        write(dir.dst().join("f"), "b", 0);
+        dir.resolve(&["f"], ConflictPolicy::KeepBoth);
+        // A later name taken since must not be mistaken for the renamed entry.
+        write(dir.dst().join("f.conflict.1"), "c", 0);
+
+        let resolution =
+            dir.resolve_with(&["f"], ConflictPolicy::KeepBoth, &dir.resumed_manifest());
+        assert_eq!(names(&resolution.forward), ["f"]);
+        assert_eq!(names(&resolution.reverse), ["f.conflict"]);
+
+        // Without a record, the entry missing from the destination is copied there.
+        let resolution = dir.resolve(&["f"], ConflictPolicy::KeepBoth);
+        assert_eq!(names(&resolution.forward), ["f"]);
+        assert!(resolution.reverse.is_empty());
+    }
+}
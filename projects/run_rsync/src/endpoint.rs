@@ -0,0 +1,553 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::cancel::Cancellation;
+
+/// A source or destination of rsync, written the way rsync takes it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Endpoint {
+    Local(PathBuf),
+    /// `[user@]host:path`, reached through the remote shell.
+    Shell {
+        user: Option<String>,
+        host: String,
+        path: PathBuf,
+    },
+    /// `rsync://[user@]host[:port]/module/path` or `[user@]host::module/path`, served by an rsync
+    /// daemon. `path` starts with the module.
+    Daemon {
+        user: Option<String>,
+        host: String,
+        port: Option<u16>,
+        path: PathBuf,
+    },
+}
+
+/// Splits `[user@]host` into its user and host, with the brackets of IPv6 addresses removed.
+fn split_user(user_host: &str) -> (Option<String>, String) {
+    let (user, host) = match user_host.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, user_host),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    (user, host.to_string())
+}
+
+/// Returns the position of the colon ending the host of a remote endpoint, skipping the colons of
+/// bracketed IPv6 addresses. Colons after the first slash belong to local paths, as for rsync.
+fn host_end(s: &str) -> Option<usize> {
+    let start = match (s.find('['), s.find(']')) {
+        (Some(open), Some(close)) if open < close => close,
+        _ => 0,
+    };
+    let colon = start + s[start..].find(':')?;
+    match s.find('/') {
+        Some(slash) if slash < colon => None,
+        _ => Some(colon),
+    }
+}
+
+fn parse_daemon_url(url: &str) -> Result<Endpoint, String> {
+    let (authority, path) = url.split_once('/').unwrap_or((url, ""));
+    let (user_host, port) = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => {
+            let port = authority[colon + 1..]
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port in 'rsync://{}'", url))?;
+            (&authority[..colon], Some(port))
+        }
+        _ => (authority, None),
+    };
+    let (user, host) = split_user(user_host);
+    Ok(Endpoint::Daemon {
+        user,
+        host,
+        port,
+        path: PathBuf::from(path),
+    })
+}
+
+impl Endpoint {
+    /// Parses an endpoint, checking local ones are directories.
+    pub fn parse(s: &str) -> Result<Endpoint, String> {
+        let endpoint = if let Some(url) = s.strip_prefix("rsync://") {
+            parse_daemon_url(url)?
+        } else if let Some(colon) = host_end(s) {
+            let (user, host) = split_user(&s[..colon]);
+            match s[colon + 1..].strip_prefix(':') {
+                Some(path) => Endpoint::Daemon {
+                    user,
+                    host,
+                    port: None,
+                    path: PathBuf::from(path),
+                },
+                None => Endpoint::Shell {
+                    user,
+                    host,
+                    path: PathBuf::from(&s[colon + 1..]),
+                },
+            }
+        } else {
+            if !fs::metadata(s).map_err(|e| e.to_string())?.is_dir() {
+                return Err(format!("Unable to access path '{}'", s));
+            }
+            return Ok(Endpoint::Local(PathBuf::from(s)));
+        };
+        match &endpoint {
+            Endpoint::Shell { host, .. } | Endpoint::Daemon { host, .. } if host.is_empty() => {
+                Err(format!("Missing host in '{}'", s))
+            }
+            Endpoint::Daemon { path, .. } if path.as_os_str().is_empty() => {
+                Err(format!("Missing rsync daemon module in '{}'", s))
+            }
+            _ => Ok(endpoint),
+        }
+    }
+
+    pub fn local_path(&self) -> Option<&Path> {
+        match self {
+            Endpoint::Local(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            Endpoint::Local(_) => None,
+            Endpoint::Shell { host, .. } | Endpoint::Daemon { host, .. } => Some(host),
+        }
+    }
+
+    /// Path of the endpoint on its host, for a daemon relative to the root of the module.
+    pub fn path(&self) -> &Path {
+        match self {
+            Endpoint::Local(path) => path,
+            Endpoint::Shell { path, .. } => path,
+            Endpoint::Daemon { path, .. } => path.strip_prefix(module(path)).unwrap_or(path),
+        }
+    }
+
+    /// Returns the endpoint of the directory `dir` below this one.
+    pub fn join(&self, dir: &Path) -> Endpoint {
+        match self {
+            Endpoint::Local(path) => Endpoint::Local(path.join(dir)),
+            Endpoint::Shell { user, host, path } => Endpoint::Shell {
+                user: user.clone(),
+                host: host.clone(),
+                path: path.join(dir),
+            },
+            Endpoint::Daemon {
+                user,
+                host,
+                port,
+                path,
+            } => Endpoint::Daemon {
+                user: user.clone(),
+                host: host.clone(),
+                port: *port,
+                path: path.join(dir),
+            },
+        }
+    }
+
+    /// Returns the endpoint without its trailing slash, naming the directory itself to rsync
+    /// rather than its contents.
+    fn directory(&self) -> String {
+        let endpoint = self.to_string();
+        match endpoint.trim_end_matches('/') {
+            "" => endpoint,
+            trimmed if trimmed.ends_with("::") || trimmed.ends_with(':') => endpoint,
+            trimmed => trimmed.to_string(),
+        }
+    }
+}
+
+fn module(path: &Path) -> &Path {
+    path.iter().next().map(Path::new).unwrap_or(path)
+}
+
+fn write_user_host(f: &mut fmt::Formatter<'_>, user: &Option<String>, host: &str) -> fmt::Result {
+    if let Some(user) = user {
+        write!(f, "{}@", user)?;
+    }
+    if host.contains(':') {
+        write!(f, "[{}]", host)
+    } else {
+        write!(f, "{}", host)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Local(path) => write!(f, "{}", path.display()),
+            Endpoint::Shell { user, host, path } => {
+                write_user_host(f, user, host)?;
+                write!(f, ":{}", path.display())
+            }
+            Endpoint::Daemon {
+                user,
+                host,
+                port,
+                path,
+            } => {
+                f.write_str("rsync://")?;
+                write_user_host(f, user, host)?;
+                if let Some(port) = port {
+                    write!(f, ":{}", port)?;
+                }
+                write!(f, "/{}", path.display())
+            }
+        }
+    }
+}
+
+/// How rsync connects to remote endpoints.
+pub(crate) struct Connection {
+    pub remote_shell: String,
+    pub ssh_options: Vec<String>,
+    /// Seconds to wait for a connection, and for data once connected.
+    pub timeout: u64,
+    pub password_file: Option<PathBuf>,
+}
+
+/// Quotes `arg` for the remote shell command line, which rsync splits on whitespace outside
+/// quotes.
+fn quote(arg: &str) -> String {
+    if arg.contains(char::is_whitespace) || arg.contains(['\'', '"']) {
+        format!("'{}'", arg.replace('\'', "'\"'\"'"))
+    } else {
+        arg.to_string()
+    }
+}
+
+impl Connection {
+    /// Returns the rsync arguments connecting to `endpoint`.
+    pub fn rsync_args(&self, endpoint: &Endpoint) -> Vec<String> {
+        match endpoint {
+            Endpoint::Local(_) => Vec::new(),
+            Endpoint::Shell { .. } => {
+                // Batch mode fails right away instead of waiting for a password no one types.
+                let mut remote_shell = vec![
+                    self.remote_shell.clone(),
+                    String::from("-o BatchMode=yes"),
+                    format!("-o ConnectTimeout={}", self.timeout),
+                ];
+                remote_shell.extend(
+                    self.ssh_options
+                        .iter()
+                        .map(|option| format!("-o {}", quote(option))),
+                );
+                vec![String::from("-e"), remote_shell.join(" ")]
+            }
+            Endpoint::Daemon { .. } => {
+                let mut args = vec![format!("--contimeout={}", self.timeout)];
+                if let Some(password_file) = &self.password_file {
+                    args.push(format!("--password-file={}", password_file.display()));
+                }
+                args
+            }
+        }
+    }
+
+    /// Checks rsync can connect to `endpoint` and list its directory, returning the output of
+    /// rsync when it cannot.
+    pub fn check(&self, endpoint: &Endpoint) -> Result<(), String> {
+        let output = Command::new("rsync")
+            .args(self.rsync_args(endpoint))
+            .arg(format!("--timeout={}", self.timeout))
+            .arg("--list-only")
+            .arg("--dirs")
+            .arg(endpoint.directory())
+            .output()
+            .map_err(|err| format!("Failed to spawn rsync process. Error : {}", err))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        Err(format!(
+            "Failed to reach '{}', rsync exited with {}. Error : {}",
+            endpoint,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Limits the number of rsync processes connected to each host at once.
+pub(crate) struct HostLimit {
+    max: Option<usize>,
+    connections: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+/// A connection counted by a [`HostLimit`], released when dropped.
+pub(crate) struct HostPermit<'a> {
+    limit: &'a HostLimit,
+    host: String,
+}
+
+impl HostLimit {
+    pub fn new(max: Option<usize>) -> HostLimit {
+        HostLimit {
+            max,
+            connections: Mutex::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    fn connections(&self) -> MutexGuard<'_, HashMap<String, usize>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Waits for a connection to `host` to be available. Returns None when the job is
+    /// interrupted while waiting.
+    pub fn acquire(&self, host: &str, cancellation: &Cancellation) -> Option<HostPermit<'_>> {
+        let mut connections = self.connections();
+        loop {
+            if cancellation.is_interrupted() {
+                return None;
+            }
+            let count = connections.entry(host.to_string()).or_default();
+            if self.max.is_none_or(|max| *count < max) {
+                *count += 1;
+                return Some(HostPermit {
+                    limit: self,
+                    host: host.to_string(),
+                });
+            }
+            // Woken on releases, and regularly to notice interruptions.
+            connections = self
+                .released
+                .wait_timeout(connections, Duration::from_millis(100))
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(count) = self.limit.connections().get_mut(&self.host) {
+            *count -= 1;
+        }
+        self.limit.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{TcpListener, TcpStream},
+        process::{Child, Stdio},
+        thread,
+    };
+
+    /// Directory removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "fs_tools_endpoint_{}_{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn shell(user: Option<&str>, host: &str, path: &str) -> Endpoint {
+        Endpoint::Shell {
+            user: user.map(String::from),
+            host: host.to_string(),
+            path: PathBuf::from(path),
+        }
+    }
+
+    fn daemon(user: Option<&str>, host: &str, port: Option<u16>, path: &str) -> Endpoint {
+        Endpoint::Daemon {
+            user: user.map(String::from),
+            host: host.to_string(),
+            port,
+            path: PathBuf::from(path),
+        }
+    }
+
+    #[test]
+    fn parses_shell_endpoints() {
+        let cases = [
+            ("host:/data", shell(None, "host", "/data")),
+            ("backup@host:/data", shell(Some("backup"), "host", "/data")),
+            ("host:", shell(None, "host", "")),
+            ("a:b", shell(None, "a", "b")),
+            ("[::1]:/data", shell(None, "::1", "/data")),
+            (
+                "backup@[fe80::1]:data/x",
+                shell(Some("backup"), "fe80::1", "data/x"),
+            ),
+        ];
+        for (s, endpoint) in cases {
+            assert_eq!(Endpoint::parse(s), Ok(endpoint.clone()), "{}", s);
+            assert_eq!(endpoint.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn parses_daemon_endpoints() {
+        let cases = [
+            (
+                "host::data/x",
+                daemon(None, "host", None, "data/x"),
+                "rsync://host/data/x",
+            ),
+            (
+                "backup@[::1]::data",
+                daemon(Some("backup"), "::1", None, "data"),
+                "rsync://backup@[::1]/data",
+            ),
+            (
+                "rsync://backup@host:873/data/x",
+                daemon(Some("backup"), "host", Some(873), "data/x"),
+                "rsync://backup@host:873/data/x",
+            ),
+            (
+                "rsync://[fe80::1]:8730/data",
+                daemon(None, "fe80::1", Some(8730), "data"),
+                "rsync://[fe80::1]:8730/data",
+            ),
+        ];
+        for (s, endpoint, displayed) in cases {
+            assert_eq!(Endpoint::parse(s), Ok(endpoint.clone()), "{}", s);
+            assert_eq!(endpoint.to_string(), displayed);
+            assert_eq!(Endpoint::parse(displayed), Ok(endpoint));
+        }
+        assert_eq!(
+            daemon(None, "host", None, "data/x/y").path(),
+            Path::new("x/y")
+        );
+    }
+
+    #[test]
+    fn joins_directories() {
+        let dir = Path::new("a/b");
+        assert_eq!(
+            Endpoint::Local(PathBuf::from("/data")).join(dir),
+            Endpoint::Local(PathBuf::from("/data/a/b"))
+        );
+        assert_eq!(
+            shell(Some("backup"), "host", "/data").join(dir),
+            shell(Some("backup"), "host", "/data/a/b")
+        );
+        assert_eq!(
+            daemon(None, "host", Some(873), "data").join(dir),
+            daemon(None, "host", Some(873), "data/a/b")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_endpoints() {
+        for s in [":/data", "host::", "rsync://host", "rsync://host:port/data"] {
+            assert!(Endpoint::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parses_local_paths_with_colons() {
+        let dir = TempDir::new("local");
+        let path = dir.0.join("a:b");
+        fs::create_dir(&path).unwrap();
+        let s = path.to_str().unwrap();
+        assert_eq!(Endpoint::parse(s), Ok(Endpoint::Local(path.clone())));
+        assert_eq!(Endpoint::Local(path.clone()).to_string(), s);
+        let file = dir.0.join("f");
+        fs::write(&file, "").unwrap();
+        assert!(Endpoint::parse(file.to_str().unwrap()).is_err());
+    }
+
+    /// rsync daemon killed when dropped.
+    struct Daemon(Child);
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Needs rsync, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn checks_rsync_daemon() {
+        let dir = TempDir::new("daemon");
+        let data = dir.0.join("data");
+        fs::create_dir(&data).unwrap();
+        let config = dir.0.join("rsyncd.conf");
+        fs::write(
+            &config,
+            format!(
+                "use chroot = no\npid file = {}\n[data]\npath = {}\n",
+                dir.0.join("rsyncd.pid").display(),
+                data.display()
+            ),
+        )
+        .unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let _daemon = Daemon(
+            Command::new("rsync")
+                .arg("--daemon")
+                .arg("--no-detach")
+                .arg("--address=127.0.0.1")
+                .arg(format!("--port={}", port))
+                .arg(format!("--config={}", config.display()))
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let connection = Connection {
+            remote_shell: String::from("ssh"),
+            ssh_options: Vec::new(),
+            timeout: 5,
+            password_file: None,
+        };
+        for s in [
+            format!("rsync://127.0.0.1:{}/data", port),
+            format!("rsync://127.0.0.1:{}/data/", port),
+        ] {
+            let endpoint = Endpoint::parse(&s).unwrap();
+            assert_eq!(connection.check(&endpoint), Ok(()), "{}", s);
+        }
+        let missing = Endpoint::parse(&format!("rsync://127.0.0.1:{}/missing", port)).unwrap();
+        assert!(connection.check(&missing).is_err());
+    }
+}
@@ -1,220 +1,1408 @@
 pub(crate) mod args;
+pub(crate) mod cancel;
+pub(crate) mod conflicts;
+pub(crate) mod endpoint;
+pub(crate) mod manifest;
+pub(crate) mod priority;
+pub(crate) mod rsync_log;
 
 use std::{
-    fs::{create_dir_all, remove_dir_all, remove_file, File},
-    io::{BufReader, Read},
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{create_dir_all, remove_dir_all, remove_file, symlink_metadata},
+    io::Read,
     num::NonZeroUsize,
-    path::PathBuf,
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
     process::{self, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use args::Args;
-use clap::Parser;
+use cancel::{Cancellation, Exit, Limits};
+use endpoint::{Connection, Endpoint, HostLimit};
+use jwalk::Parallelism;
+use manifest::{manifest_path, JobManifest, Manifest, Status, Summary};
+use priority::Priority;
 use rayon::{
-    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
-    slice::ParallelSlice,
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
     ThreadPoolBuilder,
 };
-use utils::fs::{ChangedFsEntries, ChangedFsEntry};
+use rsync_log::{write_results, Outcome, RsyncLog, LOG_FILE_FORMAT};
+use tracing::{error, info, info_span, warn, Span};
+use utils::{
+    encoding::{read_from_file, write_to_file, Compression},
+    fs::{
+        escape_name, read_relocated_changes, relative_name, walk_dir, write_relocated_changes,
+        ChangedFsEntries, ChangedFsEntry, FsEntry, RelocatedChanges,
+    },
+    ids::{IdMapping, IdNames},
+    journal::{truncate_journal, Coalesced, JournalReader},
+    logging::LogFile,
+    metrics::{unix_time, Metrics},
+    state::{write_state, FsState},
+    throttle::Schedule,
+};
+
+/// Exit code used when the job was interrupted by a signal and can be resumed.
+pub(crate) const INTERRUPTED_EXIT_CODE: i32 = 75;
+
+/// Exit code used when the changes to sync cannot be read from the journal, the source and
+/// destination have to be compared in full.
+pub(crate) const JOURNAL_UNUSABLE_EXIT_CODE: i32 = 76;
 
 fn create_temporary_directories(tmp_dir: &PathBuf) -> Result<(), String> {
-    if !tmp_dir.exists() {
-        if create_dir_all(tmp_dir).is_err() {
-            return Err(format!("Failed to create directory '{}'", tmp_dir.display()).into());
-        }
+    if !tmp_dir.exists() && create_dir_all(tmp_dir).is_err() {
+        return Err(format!(
+            "Failed to create directory '{}'",
+            tmp_dir.display()
+        ));
     }
     let parts_dir = tmp_dir.join("parts");
-    if !parts_dir.exists() {
-        if create_dir_all(&parts_dir).is_err() {
-            return Err(format!("Failed to create directory '{}'", parts_dir.display()).into());
-        }
+    if !parts_dir.exists() && create_dir_all(&parts_dir).is_err() {
+        return Err(format!(
+            "Failed to create directory '{}'",
+            parts_dir.display()
+        ));
     }
     let logs_dir = tmp_dir.join("logs");
-    if !logs_dir.exists() {
-        if create_dir_all(&logs_dir).is_err() {
-            return Err(format!("Failed to create directory '{}'", logs_dir.display()).into());
-        }
+    if !logs_dir.exists() && create_dir_all(&logs_dir).is_err() {
+        return Err(format!(
+            "Failed to create directory '{}'",
+            logs_dir.display()
+        ));
     }
     Ok(())
 }
 
+fn read_diff(path: &Path) -> ChangedFsEntries {
+    read_from_file(path).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    })
+}
+
+/// Reads the journal records from sequence `from` on as changes, along with the sequence number
+/// following the last record read. Fails when the records from `from` on are no longer all in the
+/// journal, as the changes they recorded would be missed.
+fn read_journal_changes(path: &Path, from: u64) -> Result<(ChangedFsEntries, u64), String> {
+    let reader = JournalReader::open(path)?;
+    if from < reader.first_sequence() {
+        return Err(format!(
+            "Journal '{}' starts at sequence {}, the records from sequence {} were truncated",
+            path.display(),
+            reader.first_sequence(),
+            from
+        ));
+    }
+    let mut coalesced = Coalesced::default();
+    let mut records = 0;
+    let mut next_sequence = from;
+    for record in reader {
+        let record = record?;
+        if record.sequence >= from {
+            records += 1;
+            next_sequence = record.sequence + 1;
+            coalesced.add(record.entry);
+        }
+    }
+    info!(records, from, "Read journal");
+    Ok((
+        ChangedFsEntries {
+            entries: coalesced.into_changes(),
+        },
+        next_sequence,
+    ))
+}
+
+/// Temporary directories, chunking, limits, metrics and manifest shared by the rsync runs of a
+/// job.
+struct Job {
+    dir: PathBuf,
+    parts_dir: PathBuf,
+    logs_dir: PathBuf,
+    chunk_size: usize,
+    limits: Limits,
+    /// Number of times a timed out chunk is queued again.
+    chunk_retries: usize,
+    metrics: Metrics,
+    cancellation: Cancellation,
+    manifest: JobManifest,
+    host_limit: HostLimit,
+    /// Bandwidth limit of the job in KiB per second.
+    bwlimit: Option<Schedule>,
+    /// Number of chunks that can run at once, sharing the bandwidth limit.
+    slots: usize,
+    priority: Priority,
+    /// Manifest of the job being resumed.
+    resumed: Option<Manifest>,
+    /// Results of the last run of each chunk, by run and chunk number.
+    results: Mutex<BTreeMap<(String, usize), ChunkResults>>,
+    deletions: Mutex<Deletions>,
+    /// Source and destination directories of the relocated directories of the diff, synced by
+    /// the runs named after their number.
+    relocated_dirs: Vec<(OsString, OsString)>,
+}
+
+/// Name of the run syncing the `number`th relocated directory, from 1.
+fn relocated_run(number: usize) -> String {
+    format!("relocated_{}", number)
+}
+
+/// Returns the index of the relocated directory synced by `run`.
+fn relocated_index(run: &str) -> Option<usize> {
+    let number = run.strip_prefix("relocated_")?.parse::<usize>().ok()?;
+    number.checked_sub(1)
+}
+
+/// Syncs each of `chunks` chunks with `sync`, given their index, then queues the chunks that timed
+/// out again, up to `retries` times unless the job is interrupted. Returns the last status of each
+/// chunk.
+fn sync_with_retries(
+    run: &str,
+    chunks: usize,
+    retries: usize,
+    cancellation: &Cancellation,
+    sync: impl Fn(usize) -> Status + Sync,
+) -> Vec<Status> {
+    let mut statuses: Vec<Status> = (0..chunks).into_par_iter().map(&sync).collect();
+    for retry in 1..=retries {
+        let timed_out: Vec<usize> = (0..chunks)
+            .filter(|index| statuses[*index] == Status::TimedOut)
+            .collect();
+        if timed_out.is_empty() || cancellation.is_interrupted() {
+            break;
+        }
+        info!(
+            run,
+            chunks = timed_out.len(),
+            retry,
+            "Queuing timed out chunks again"
+        );
+        let retried: Vec<(usize, Status)> = timed_out
+            .into_par_iter()
+            .map(|index| (index, sync(index)))
+            .collect();
+        for (index, status) in retried {
+            statuses[index] = status;
+        }
+    }
+    statuses
+}
+
+/// Outcome of the entries of a chunk.
+#[derive(Default)]
+struct ChunkResults {
+    transferred: usize,
+    transferred_bytes: u64,
+    skipped: usize,
+    failed: Vec<ChangedFsEntry>,
+}
+
+#[derive(Default)]
+struct Deletions {
+    deleted: usize,
+    /// Entries that could not be removed, with the run they belong to.
+    failed: Vec<(String, ChangedFsEntry)>,
+}
+
+/// An rsync run of a job, copying entries from one side to the other.
+struct Run<'a> {
+    /// Name of the run in the manifest, metrics and messages.
+    name: &'a str,
+    /// Prefix of the part files and logs of the run.
+    file_prefix: String,
+    from: &'a Endpoint,
+    to: &'a Endpoint,
+    rsync_args: &'a [String],
+    span: Span,
+}
+
+impl Job {
+    /// Copies `entries` from `from` to `to` with one rsync process per chunk. Chunks
+    /// that time out are queued again once all the chunks ran.
+    ///
+    /// `label` distinguishes the part files, logs and messages of each run of the job.
+    /// Returns false when a chunk failed or was not run as the job was interrupted.
+    fn sync_entries(
+        &self,
+        label: &str,
+        entries: &[&ChangedFsEntry],
+        from: &Endpoint,
+        to: &Endpoint,
+        rsync_args: &[String],
+    ) -> bool {
+        let run = Run {
+            name: if label.is_empty() { "forward" } else { label },
+            file_prefix: if label.is_empty() {
+                String::new()
+            } else {
+                format!("{}_", label)
+            },
+            from,
+            to,
+            rsync_args,
+            span: Span::current(),
+        };
+        let chunks: Vec<&[&ChangedFsEntry]> = entries.chunks(self.chunk_size).collect();
+        let statuses = sync_with_retries(
+            run.name,
+            chunks.len(),
+            self.chunk_retries,
+            &self.cancellation,
+            |index| self.sync_chunk(&run, index + 1, chunks[index]),
+        );
+        statuses.iter().all(|status| *status == Status::Succeeded)
+    }
+
+    /// Runs rsync on chunk `number` of `run`, unless the job is interrupted or the resumed job
+    /// already synced it.
+    fn sync_chunk(&self, run: &Run, number: usize, chunk: &[&ChangedFsEntry]) -> Status {
+        if self.cancellation.is_interrupted() {
+            return Status::Interrupted;
+        }
+        let _chunk_span = info_span!(parent: &run.span, "chunk", run = run.name, number).entered();
+        let files_str = chunk
+            .iter()
+            .map(|entry| entry.name.as_bytes())
+            .collect::<Vec<&[u8]>>()
+            .join(&b'\0');
+        let file_path = self
+            .parts_dir
+            .join(format!("{}part_{}.list", run.file_prefix, number));
+        if self.is_synced(run.name, number, &file_path, &files_str) {
+            info!(entries = chunk.len(), "Already synced by the resumed job");
+            return Status::Succeeded;
+        }
+        info!(entries = chunk.len(), "Processing");
+        self.metrics
+            .add("chunks", "Chunks processed", &[("run", run.name)], 1.0);
+        {
+            std::fs::write(&file_path, files_str).unwrap();
+        }
+        info!(path = %file_path.display(), "Generated part file");
+        self.set_chunk(run.name, number, chunk.len(), Status::Running);
+        let (status, failure) = self.run_rsync(run, number, &file_path);
+        self.set_chunk(run.name, number, chunk.len(), status);
+        self.record_chunk(run.name, status);
+        self.record_results(run, number, chunk, failure.as_deref());
+        status
+    }
+
+    /// Returns whether the job being resumed synced chunk `number` of `run` with the same entries.
+    fn is_synced(&self, run: &str, number: usize, part_file: &Path, files: &[u8]) -> bool {
+        self.resumed
+            .as_ref()
+            .and_then(|manifest| manifest.chunk(run, number))
+            .is_some_and(|chunk| chunk.status == Status::Succeeded)
+            && std::fs::read(part_file).is_ok_and(|previous| previous == files)
+    }
+
+    /// Removes the `entries` of `run` from `root`, returns false when an entry could not be
+    /// removed. Entries of a remote `root` are removed by rsync, run from `from` with
+    /// `rsync_args`.
+    fn delete_entries(
+        &self,
+        run: &str,
+        entries: &[&ChangedFsEntry],
+        from: &Endpoint,
+        root: &Endpoint,
+        rsync_args: &[String],
+    ) -> bool {
+        let Some(root_path) = root.local_path() else {
+            return self.delete_remote_entries(run, entries, from, root, rsync_args);
+        };
+        let job_span = Span::current();
+        let failed: Vec<ChangedFsEntry> = entries
+            .par_iter()
+            .filter(|entry| {
+                let _job_span = job_span.enter();
+                let dir_or_file_path = root_path.join(&entry.name);
+                if !dir_or_file_path.exists() {
+                    return false;
+                }
+                if entry.is_dir {
+                    if remove_dir_all(&dir_or_file_path).is_err() {
+                        error!(
+                            path = %escape_name(dir_or_file_path.as_os_str()),
+                            "Failed to remove directory"
+                        );
+                        return true;
+                    }
+                } else if remove_file(&dir_or_file_path).is_err() {
+                    if entry.is_file {
+                        error!(
+                            path = %escape_name(dir_or_file_path.as_os_str()),
+                            "Failed to remove file"
+                        );
+                    } else if entry.is_symlink {
+                        error!(
+                            path = %escape_name(dir_or_file_path.as_os_str()),
+                            "Failed to remove symlink"
+                        );
+                    }
+                    return true;
+                }
+                false
+            })
+            .map(|entry| (*entry).clone())
+            .collect();
+        let succeeded = failed.is_empty();
+        let mut deletions = self.deletions.lock().unwrap_or_else(|err| err.into_inner());
+        deletions.deleted += entries.len() - failed.len();
+        deletions
+            .failed
+            .extend(failed.into_iter().map(|entry| (run.to_string(), entry)));
+        succeeded
+    }
+
+    /// Removes the `entries` of `run` from the remote `root` with rsync, which deletes the listed
+    /// entries missing from `from`. Entries present again in `from` are copied instead.
+    fn delete_remote_entries(
+        &self,
+        run: &str,
+        entries: &[&ChangedFsEntry],
+        from: &Endpoint,
+        root: &Endpoint,
+        rsync_args: &[String],
+    ) -> bool {
+        let label = if run == "forward" {
+            String::from("delete")
+        } else {
+            format!("{}_delete", run)
+        };
+        let mut rsync_args = rsync_args.to_vec();
+        rsync_args.extend([
+            String::from("--delete-missing-args"),
+            String::from("--force"),
+        ]);
+        let succeeded = self.sync_entries(&label, entries, from, root, &rsync_args);
+        // Counted as deletions rather than transfers in the summary.
+        let mut results = self.results.lock().unwrap_or_else(|err| err.into_inner());
+        let chunks: Vec<(String, usize)> = results
+            .keys()
+            .filter(|(name, _)| *name == label)
+            .cloned()
+            .collect();
+        let mut deletions = self.deletions.lock().unwrap_or_else(|err| err.into_inner());
+        for chunk in chunks {
+            let Some(chunk_results) = results.remove(&chunk) else {
+                continue;
+            };
+            deletions.deleted += chunk_results.transferred + chunk_results.skipped;
+            deletions.failed.extend(
+                chunk_results
+                    .failed
+                    .into_iter()
+                    .map(|entry| (run.to_string(), entry)),
+            );
+        }
+        succeeded
+    }
+
+    fn rsync_log_path(&self, run: &Run, number: usize) -> PathBuf {
+        self.logs_dir
+            .join(format!("{}rsync_{}.log", run.file_prefix, number))
+    }
+
+    /// Reads the outcome of each entry of chunk `number` of `run` from its rsync log file and
+    /// writes it next to the log. Entries missing from the log fail with `failure` when set.
+    fn record_results(
+        &self,
+        run: &Run,
+        number: usize,
+        chunk: &[&ChangedFsEntry],
+        failure: Option<&str>,
+    ) {
+        let log = std::fs::read(self.rsync_log_path(run, number))
+            .map(|contents| RsyncLog::parse(&contents, &[run.from.path(), run.to.path()]))
+            .unwrap_or_default();
+        let results = log.results(chunk, failure);
+        let results_path = self
+            .logs_dir
+            .join(format!("{}results_{}.tsv", run.file_prefix, number));
+        if let Err(err) = write_results(&results, &results_path) {
+            error!("{}", err);
+        }
+        let mut chunk_results = ChunkResults::default();
+        for result in results {
+            match result.outcome {
+                Outcome::Transferred => {
+                    chunk_results.transferred += 1;
+                    chunk_results.transferred_bytes += result.bytes;
+                }
+                Outcome::Skipped => chunk_results.skipped += 1,
+                Outcome::Failed => {
+                    // Failures of the whole chunk are already reported.
+                    if failure.is_none() {
+                        warn!(
+                            path = %escape_name(&result.entry.name),
+                            reason = result.reason.as_deref().unwrap_or_default(),
+                            "Failed to sync"
+                        );
+                    }
+                    chunk_results.failed.push(result.entry.clone());
+                }
+            }
+        }
+        self.results
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert((run.name.to_string(), number), chunk_results);
+    }
+
+    fn set_chunk(&self, run: &str, number: usize, entries: usize, status: Status) {
+        let result = self.manifest.set_chunk(manifest::Chunk {
+            run: run.to_string(),
+            number,
+            entries,
+            status,
+        });
+        if let Err(err) = result {
+            error!("{}", err);
+        }
+    }
+
+    /// Writes the output of rsync read by `reader` to `path` when there is some, returns whether
+    /// there was.
+    fn write_output(&self, reader: Receiver<Vec<u8>>, name: &str, path: &Path) -> bool {
+        let output = match reader.recv_timeout(self.cancellation.grace_period()) {
+            Ok(output) => output,
+            Err(_) => {
+                error!("Failed to read {} of rsync process", name);
+                return false;
+            }
+        };
+        if output.is_empty() {
+            return false;
+        }
+        if std::fs::write(path, output).is_err() {
+            error!(
+                path = %path.display(),
+                "Failed to write {} of rsync process to file", name
+            );
+        }
+        true
+    }
+
+    /// Runs rsync on the entries listed in `file_path`, in its own process group so that it is
+    /// only terminated through the cancellation of the job or its limits.
+    ///
+    /// Returns the status of the chunk, with the reason of its failure when rsync did not get to
+    /// report the outcome of all its entries in its log file.
+    fn run_rsync(
+        &self,
+        run: &Run,
+        chunk_number: usize,
+        file_path: &Path,
+    ) -> (Status, Option<String>) {
+        let rsync_stdout_log = self.logs_dir.join(format!(
+            "{}rsync_stdout_{}.log",
+            run.file_prefix, chunk_number
+        ));
+        let rsync_stderr_log = self.logs_dir.join(format!(
+            "{}rsync_stderr_{}.log",
+            run.file_prefix, chunk_number
+        ));
+        // Only one of the endpoints of a run can be remote.
+        let _permit = match run.from.host().or(run.to.host()) {
+            Some(host) => match self.host_limit.acquire(host, &self.cancellation) {
+                Some(permit) => Some(permit),
+                None => {
+                    return (
+                        Status::Interrupted,
+                        Some(String::from("rsync process was interrupted")),
+                    )
+                }
+            },
+            None => None,
+        };
+        let rsync_log = self.rsync_log_path(run, chunk_number);
+        // rsync appends to its log file, the results of the chunk only come from this attempt.
+        let _ = remove_file(&rsync_log);
+        let mut rsync_command = std::process::Command::new("rsync");
+        if let Some(bwlimit) = self.bwlimit.as_ref().and_then(Schedule::limit) {
+            let share = (bwlimit / self.slots as u64).max(1);
+            rsync_command.arg(format!("--bwlimit={}", share));
+        }
+        let priority = self.priority;
+        // SAFETY: the priority is applied with system calls only.
+        unsafe {
+            rsync_command.pre_exec(move || priority.apply());
+        }
+        let rsync_process = rsync_command
+            .args(run.rsync_args)
+            .arg(format!("--log-file={}", rsync_log.display()))
+            .arg(format!("--log-file-format={}", LOG_FILE_FORMAT))
+            .arg(format!("--files-from={}", file_path.display()))
+            .arg("--from0")
+            .arg(run.from.to_string())
+            .arg(run.to.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn();
+        let mut rsync_process = match rsync_process {
+            Ok(rsync_process) => rsync_process,
+            Err(err) => {
+                let failure = format!("Failed to spawn rsync process. Error : {}", err);
+                error!("{}", failure);
+                return (Status::Failed, Some(failure));
+            }
+        };
+        // Read while rsync runs, so it never blocks on a full pipe, counting the bytes read as
+        // progress along with the growth of its log file.
+        let output_read = Arc::new(AtomicU64::new(0));
+        let stdout = rsync_process
+            .stdout
+            .take()
+            .map(|stdout| read_in_background(stdout, output_read.clone()));
+        let stderr = rsync_process
+            .stderr
+            .take()
+            .map(|stderr| read_in_background(stderr, output_read.clone()));
+        let progress = || {
+            output_read.load(Ordering::Relaxed)
+                + symlink_metadata(&rsync_log)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0)
+        };
+        let exit = self.cancellation.wait(
+            &mut rsync_process,
+            &self.limits,
+            progress,
+            &[run.from.local_path(), run.to.local_path()]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<&Path>>(),
+        );
+        if let Some(stdout) = stdout {
+            self.write_output(stdout, "stdout", &rsync_stdout_log);
+        }
+        if let Some(stderr) = stderr {
+            if self.write_output(stderr, "stderr", &rsync_stderr_log) {
+                warn!(
+                    path = %rsync_stderr_log.display(),
+                    "Some files/attrs were not transferred"
+                );
+            }
+        }
+        match exit {
+            Ok(Exit::Finished(status)) if status.success() => (Status::Succeeded, None),
+            // Partial transfers, rsync logs the entries it failed to transfer.
+            Ok(Exit::Finished(status)) if matches!(status.code(), Some(23) | Some(24)) => {
+                (Status::Failed, None)
+            }
+            Ok(Exit::Finished(status)) => {
+                let failure = format!("rsync process failed with {}", status);
+                error!("{}", failure);
+                (Status::Failed, Some(failure))
+            }
+            Ok(Exit::Interrupted) => {
+                warn!("Interrupted rsync process");
+                (
+                    Status::Interrupted,
+                    Some(String::from("rsync process was interrupted")),
+                )
+            }
+            Ok(Exit::TimedOut(in_flight)) => {
+                report_in_flight("Chunk timed out", file_path, &in_flight);
+                (
+                    Status::TimedOut,
+                    Some(String::from("rsync process timed out")),
+                )
+            }
+            Ok(Exit::Stalled(in_flight)) => {
+                report_in_flight("Chunk stalled", file_path, &in_flight);
+                (
+                    Status::TimedOut,
+                    Some(String::from("rsync process stalled")),
+                )
+            }
+            Err(err) => {
+                let failure = format!("Failed to wait for rsync process. Error : {}", err);
+                error!("{}", failure);
+                (Status::Failed, Some(failure))
+            }
+        }
+    }
+
+    /// Records the outcome of a chunk in the metrics.
+    fn record_chunk(&self, run: &str, status: Status) {
+        match status {
+            Status::Failed => {
+                self.metrics.add(
+                    "chunk_failures",
+                    "Chunks whose rsync process failed",
+                    &[("run", run)],
+                    1.0,
+                );
+            }
+            Status::Interrupted => {
+                self.metrics.add(
+                    "interrupted_chunks",
+                    "Chunks whose rsync process was terminated as the job was interrupted",
+                    &[("run", run)],
+                    1.0,
+                );
+            }
+            Status::TimedOut => {
+                self.metrics.add(
+                    "timed_out_chunks",
+                    "Chunks whose rsync process was terminated as it timed out or stalled",
+                    &[("run", run)],
+                    1.0,
+                );
+            }
+            Status::Running | Status::Succeeded => {}
+        }
+    }
+}
+
+/// Reads `reader` to its end from a new thread, adding the number of bytes read to `bytes_read`
+/// as it goes.
+fn read_in_background<R: Read + Send + 'static>(
+    mut reader: R,
+    bytes_read: Arc<AtomicU64>,
+) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let mut buf = [0; 8192];
+        while let Ok(read @ 1..) = reader.read(&mut buf) {
+            output.extend_from_slice(&buf[..read]);
+            bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        let _ = sender.send(output);
+    });
+    receiver
+}
+
+/// Reports the files `rsync` had open when the chunk listed in `part_file` was stopped.
+fn report_in_flight(message: &str, part_file: &Path, in_flight: &[PathBuf]) {
+    warn!(
+        part_file = %part_file.display(),
+        in_flight = in_flight.len(),
+        "{}",
+        message
+    );
+    for path in in_flight {
+        warn!(path = %escape_name(path.as_os_str()), "In flight");
+    }
+}
+
+fn split_deleted(entries: &[ChangedFsEntry]) -> (Vec<&ChangedFsEntry>, Vec<&ChangedFsEntry>) {
+    entries.iter().partition(|entry| !entry.is_deleted)
+}
+
+/// Scans `root_path` skipping `folders_to_ignore` and writes it as the base state of the next
+/// three-way comparison. Entries below `unresolved` are left out, so they are compared as added
+/// on both sides again.
+fn update_base_state(
+    root_path: &Path,
+    parallelism: Parallelism,
+    folders_to_ignore: Vec<String>,
+    unresolved: &[OsString],
+    path: &Path,
+) -> Result<(), String> {
+    let entries: Vec<FsEntry> = walk_dir(
+        root_path.to_path_buf(),
+        parallelism,
+        false,
+        false,
+        false,
+        folders_to_ignore,
+        None,
+    )
+    .into_par_iter()
+    .map(|mut entry| {
+        entry.name = relative_name(Path::new(&entry.name), root_path);
+        entry
+    })
+    .filter(|entry| {
+        !unresolved
+            .iter()
+            .any(|name| Path::new(&entry.name).starts_with(name))
+    })
+    .collect();
+    write_state(&FsState::from_entries(entries), path, Compression::None, 0)
+}
+
+/// Records the outcome of the job and writes the metrics file when one is given.
+fn write_metrics(args: &Args, metrics: &Metrics, started: Instant, succeeded: bool) {
+    let Some(metrics_file) = &args.metrics.metrics_file else {
+        return;
+    };
+    metrics.set(
+        "duration_seconds",
+        "Duration of the last job",
+        &[],
+        started.elapsed().as_secs_f64(),
+    );
+    metrics.set(
+        "succeeded",
+        "Whether all the entries of the last job were synced",
+        &[],
+        if succeeded { 1.0 } else { 0.0 },
+    );
+    metrics.set(
+        "last_run_timestamp_seconds",
+        "Time the last job ended at",
+        &[],
+        unix_time(),
+    );
+    if let Err(err) = metrics.write_to_file(metrics_file) {
+        error!("{}", err);
+    }
+}
+
+/// Writes the entries of `failed` to `path` as a diff, and those of `failed_relocated` next to
+/// it, for a later run to sync them again with `option`.
+fn write_failed(
+    failed: Vec<ChangedFsEntry>,
+    failed_relocated: Vec<RelocatedChanges>,
+    path: &Path,
+    option: &str,
+) {
+    let failed_relocated: Vec<RelocatedChanges> = failed_relocated
+        .into_iter()
+        .filter(|relocated| !relocated.entries.is_empty())
+        .collect();
+    let entries = failed.len()
+        + failed_relocated
+            .iter()
+            .map(|relocated| relocated.entries.len())
+            .sum::<usize>();
+    if entries == 0 {
+        let _ = remove_file(path);
+        if let Err(err) = write_relocated_changes(&[], path, Compression::None, 0) {
+            error!("{}", err);
+        }
+        return;
+    }
+    match write_to_file(
+        &ChangedFsEntries { entries: failed },
+        path,
+        Compression::None,
+        0,
+    )
+    .and_then(|_| write_relocated_changes(&failed_relocated, path, Compression::None, 0))
+    {
+        Ok(()) => warn!(
+            entries,
+            path = %path.display(),
+            "Wrote failed entries, run again with {} {} to sync them",
+            option,
+            path.display()
+        ),
+        Err(err) => error!("{}", err),
+    }
+}
+
+/// Summarizes the outcome of the entries of the job in its manifest, its metrics and the diffs of
+/// the failed entries.
+fn summarize(job: &Job) {
+    let mut summary = Summary::default();
+    let mut failed = Vec::new();
+    let mut failed_reverse = Vec::new();
+    let mut failed_relocated: Vec<RelocatedChanges> = job
+        .relocated_dirs
+        .iter()
+        .map(|(src_dir, dst_dir)| RelocatedChanges {
+            src_dir: src_dir.clone(),
+            dst_dir: dst_dir.clone(),
+            entries: Vec::new(),
+        })
+        .collect();
+    let results = std::mem::take(&mut *job.results.lock().unwrap_or_else(|err| err.into_inner()));
+    for ((run, _), results) in results {
+        summary.transferred += results.transferred;
+        summary.transferred_bytes += results.transferred_bytes;
+        summary.skipped += results.skipped;
+        summary.failed += results.failed.len();
+        for (outcome, entries) in [
+            ("transferred", results.transferred),
+            ("skipped", results.skipped),
+            ("failed", results.failed.len()),
+        ] {
+            job.metrics.add(
+                "entries",
+                "Entries of the job by outcome",
+                &[("run", &run), ("outcome", outcome)],
+                entries as f64,
+            );
+        }
+        job.metrics.add(
+            "transferred_bytes",
+            "Bytes rsync transferred",
+            &[("run", &run)],
+            results.transferred_bytes as f64,
+        );
+        match (run.as_str(), relocated_index(&run)) {
+            (_, Some(index)) => failed_relocated[index].entries.extend(results.failed),
+            ("reverse" | "conflict_reverse", None) => failed_reverse.extend(results.failed),
+            _ => failed.extend(results.failed),
+        }
+    }
+    let deletions =
+        std::mem::take(&mut *job.deletions.lock().unwrap_or_else(|err| err.into_inner()));
+    summary.deleted = deletions.deleted;
+    summary.delete_failures = deletions.failed.len();
+    job.metrics.set(
+        "deleted_entries",
+        "Entries removed",
+        &[],
+        summary.deleted as f64,
+    );
+    job.metrics.set(
+        "delete_failures",
+        "Entries that could not be removed",
+        &[],
+        summary.delete_failures as f64,
+    );
+    for (run, entry) in deletions.failed {
+        match (run.as_str(), relocated_index(&run)) {
+            (_, Some(index)) => failed_relocated[index].entries.push(entry),
+            ("reverse", None) => failed_reverse.push(entry),
+            _ => failed.push(entry),
+        }
+    }
+    info!(
+        transferred = summary.transferred,
+        transferred_bytes = summary.transferred_bytes,
+        skipped = summary.skipped,
+        failed = summary.failed,
+        deleted = summary.deleted,
+        delete_failures = summary.delete_failures,
+        "Job summary"
+    );
+    if let Err(err) = job.manifest.set_summary(summary) {
+        error!("{}", err);
+    }
+    write_failed(
+        failed,
+        failed_relocated,
+        &job.dir.join("failed.diff"),
+        "--diff",
+    );
+    write_failed(
+        failed_reverse,
+        Vec::new(),
+        &job.dir.join("failed_reverse.diff"),
+        "--diff-reverse",
+    );
+}
+
+/// Records the final status of the job in its manifest and writes the metrics file.
+fn finish(job: &Job, args: &Args, started: Instant, status: Status) {
+    summarize(job);
+    if let Err(err) = job.manifest.set_status(status) {
+        error!("{}", err);
+    }
+    write_metrics(args, &job.metrics, started, status == Status::Succeeded);
+}
+
 fn main() {
-    let args = Args::parse();
+    let args: Args = utils::config::parse();
+    let log_file = LogFile::default();
+    utils::logging::init(&args.log, Some(log_file.clone()));
 
     let src_path = args.src_path.clone();
     let dst_path = args.dst_path.clone();
-    let read_diff_from = args.read_diff_from.clone();
-    let job_id = uuid::Uuid::new_v4().to_string();
+    let job_id = args
+        .resume
+        .clone()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
     let tmp_dir = args.tmp_dir.clone().join(&job_id);
-    let rsync_args = args.rsync_args.clone();
+    let mut rsync_args = args.rsync_args.clone();
+    let mut reverse_rsync_args = args.rsync_args.clone();
     let delete_destination = args.delete_destination;
+    let metrics = Metrics::new("run_rsync");
+    let started = Instant::now();
 
     ThreadPoolBuilder::new()
         .num_threads(args.threads())
         .build_global()
         .unwrap();
 
-    println!("Job ID: {}", job_id);
+    let id_mapping = IdNames::read(args.src_passwd.as_deref(), args.dst_passwd.as_deref())
+        .and_then(|user_names| {
+            let group_names = IdNames::read(args.src_group.as_deref(), args.dst_group.as_deref())?;
+            IdMapping::read(args.id_map.as_deref(), &user_names, &group_names)
+        })
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        });
+    rsync_args.extend(id_mapping.rsync_args());
+    reverse_rsync_args.extend(id_mapping.inverse().rsync_args());
+
+    // Deleting entries can go through rsync, resolving conflicts and scanning the sides for the
+    // base states cannot.
+    let remote = match (src_path.host(), dst_path.host()) {
+        (Some(_), Some(_)) => {
+            error!("rsync cannot copy between two remote endpoints");
+            process::exit(1);
+        }
+        (Some(_), None) => Some(&src_path),
+        (None, Some(_)) => Some(&dst_path),
+        (None, None) => None,
+    };
+    if remote.is_some() && args.read_conflicts_from.is_some() {
+        error!("Resolving conflicts needs local source and destination paths");
+        process::exit(1);
+    }
+    if src_path.host().is_some() && args.update_base_state_to.is_some() {
+        error!("Updating the base state needs a local source path");
+        process::exit(1);
+    }
+    if dst_path.host().is_some() && args.update_dst_base_state_to.is_some() {
+        error!("Updating the destination base state needs a local destination path");
+        process::exit(1);
+    }
+    if cfg!(not(target_os = "linux")) && args.io_class.is_some() {
+        error!("I/O scheduling classes are only supported on Linux");
+        process::exit(1);
+    }
+    let connection = Connection {
+        remote_shell: args.remote_shell.clone(),
+        ssh_options: args.ssh_options.clone(),
+        timeout: args.connect_timeout,
+        password_file: args.password_file.clone(),
+    };
+    if let Some(remote) = remote {
+        rsync_args.extend(connection.rsync_args(remote));
+        reverse_rsync_args.extend(connection.rsync_args(remote));
+    }
+
+    let job_span = info_span!("job", id = %job_id);
+    let _job_span = job_span.enter();
+
+    let resumed = args.resume.as_ref().map(|_| {
+        Manifest::load(&manifest_path(&tmp_dir)).unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        })
+    });
 
     let tmpdir_result = create_temporary_directories(&tmp_dir);
     if tmpdir_result.is_err() {
-        eprintln!("{}", tmpdir_result.err().unwrap());
+        error!("{}", tmpdir_result.err().unwrap());
         process::exit(1);
     }
-    let tmp_parts_dir = tmp_dir.join("parts");
-    let tmp_logs_dir = tmp_dir.join("logs");
+    if let Err(err) = log_file.open(&tmp_dir.join("job.log")) {
+        warn!("{}", err);
+    }
+    if resumed.is_some() {
+        info!(tmp_dir = %tmp_dir.display(), "Resumed job");
+    } else {
+        info!(tmp_dir = %tmp_dir.display(), "Started job");
+    }
+    if let Some(remote) = remote {
+        if let Err(err) = connection.check(remote) {
+            error!("{}", err);
+            process::exit(1);
+        }
+        info!(endpoint = %remote, "Reached remote endpoint");
+    }
 
-    let fs_diff: ChangedFsEntries;
-    {
-        let mut reader = BufReader::new(File::open(read_diff_from).unwrap());
-        fs_diff = bincode::decode_from_std_read(&mut reader, bincode::config::standard()).unwrap();
+    let mut next_journal_sequence = None;
+    let fs_diff = match (&args.read_journal_from, &args.read_diff_from) {
+        (Some(journal_path), _) => {
+            let (changes, next_sequence) = read_journal_changes(journal_path, args.journal_from)
+                .unwrap_or_else(|err| {
+                    error!("{}, compare the states in full instead", err);
+                    process::exit(JOURNAL_UNUSABLE_EXIT_CODE);
+                });
+            next_journal_sequence = Some(next_sequence);
+            changes
+        }
+        (None, Some(read_diff_from)) => read_diff(read_diff_from),
+        (None, None) => unreachable!("clap requires a diff or a journal"),
+    };
+    let relocated: Vec<RelocatedChanges> = match &args.read_diff_from {
+        Some(read_diff_from) => read_relocated_changes(read_diff_from).unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        }),
+        None => Vec::new(),
+    };
+    let reverse_fs_diff = args.read_reverse_diff_from.as_deref().map(read_diff);
+    let conflicting = args.read_conflicts_from.as_deref().map(read_diff);
+
+    let chunk_size = match &resumed {
+        Some(resumed) => resumed.chunk_size,
+        None => args
+            .chunk_size
+            .unwrap_or_else(|| {
+                let threads = args.threads();
+                let changed_files_count = fs_diff.entries.len()
+                    + relocated
+                        .iter()
+                        .map(|relocated| relocated.entries.len())
+                        .sum::<usize>()
+                    + reverse_fs_diff
+                        .as_ref()
+                        .map(|diff| diff.entries.len())
+                        .unwrap_or(0);
+                let l_chunk_size = changed_files_count / threads;
+                NonZeroUsize::new(l_chunk_size.max(1)).unwrap()
+            })
+            .get(),
+    };
+
+    info!(chunk_size, "Computed chunk size");
+
+    let manifest = JobManifest::new(
+        manifest_path(&tmp_dir),
+        Manifest {
+            job_id: job_id.clone(),
+            status: Status::Running,
+            chunk_size,
+            summary: None,
+            chunks: resumed
+                .as_ref()
+                .map(|resumed| resumed.chunks.clone())
+                .unwrap_or_default(),
+            renamed: resumed
+                .as_ref()
+                .map(|resumed| resumed.renamed.clone())
+                .unwrap_or_default(),
+        },
+    );
+    let cancellation = manifest
+        .set_status(Status::Running)
+        .and_then(|_| Cancellation::install(Duration::from_secs(args.grace_period)))
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        });
+    let job = Job {
+        dir: tmp_dir.clone(),
+        parts_dir: tmp_dir.join("parts"),
+        logs_dir: tmp_dir.join("logs"),
+        chunk_size,
+        limits: Limits {
+            timeout: args.chunk_timeout.map(Duration::from_secs),
+            stall_timeout: args.stall_timeout.map(Duration::from_secs),
+        },
+        chunk_retries: args.chunk_retries,
+        metrics: metrics.clone(),
+        cancellation,
+        manifest,
+        host_limit: HostLimit::new(args.max_connections_per_host.map(NonZeroUsize::get)),
+        bwlimit: args.total_bwlimit.clone(),
+        slots: match (remote, args.max_connections_per_host) {
+            (Some(_), Some(max)) => max.get().min(args.threads()),
+            _ => args.threads(),
+        },
+        priority: Priority {
+            nice: args.nice,
+            io_class: args.io_class,
+            io_level: args.io_level,
+        },
+        resumed,
+        results: Mutex::default(),
+        deletions: Mutex::default(),
+        relocated_dirs: relocated
+            .iter()
+            .map(|relocated| (relocated.src_dir.clone(), relocated.dst_dir.clone()))
+            .collect(),
+    };
+    let exit_if_interrupted = || {
+        if job.cancellation.is_interrupted() {
+            finish(&job, &args, started, Status::Interrupted);
+            warn!(
+                "Interrupted, run again with the same arguments and --resume {} to resume the job",
+                job_id
+            );
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    };
+
+    let (to_sync, to_delete) = split_deleted(&fs_diff.entries);
+    let (reverse_to_sync, reverse_to_delete) = reverse_fs_diff
+        .as_ref()
+        .map(|reverse_fs_diff| split_deleted(&reverse_fs_diff.entries))
+        .unwrap_or_default();
+
+    // Deletions come first, so an entry a journal records as deleted and then created again
+    // is synced afresh.
+    let mut succeeded = true;
+    if delete_destination.unwrap_or(false) {
+        succeeded &= job.delete_entries("forward", &to_delete, &src_path, &dst_path, &rsync_args);
+        succeeded &= job.delete_entries(
+            "reverse",
+            &reverse_to_delete,
+            &dst_path,
+            &src_path,
+            &reverse_rsync_args,
+        );
     }
+    exit_if_interrupted();
 
-    let chunk_size = args
-        .chunk_size
-        .clone()
-        .unwrap_or_else(|| {
-            let threads = args.threads();
-            let changed_files_count = fs_diff.entries.len();
-            let l_chunk_size = changed_files_count / threads;
-            NonZeroUsize::new(l_chunk_size).unwrap()
-        })
-        .get();
-
-    println!("Chunk size: {}", chunk_size);
-
-    fs_diff
-        .entries
-        .par_iter()
-        .filter(|entry| !entry.is_deleted)
-        .collect::<Vec<&ChangedFsEntry>>()
-        .par_chunks(chunk_size)
-        .enumerate()
-        .for_each(|(index, chunk)| {
-            let chunk_number = index + 1;
-            println!("CHUNK {:>8}: Processing", chunk_number);
-            let src_path = src_path.clone();
-            let dst_path = dst_path.clone();
-            let files_str = chunk
+    succeeded &= job.sync_entries("", &to_sync, &src_path, &dst_path, &rsync_args);
+    exit_if_interrupted();
+    for (index, relocated) in relocated.iter().enumerate() {
+        let run = relocated_run(index + 1);
+        let from = src_path.join(Path::new(&relocated.src_dir));
+        let to = dst_path.join(Path::new(&relocated.dst_dir));
+        info!(run, from = %from, to = %to, "Syncing relocated directory");
+        let (to_sync, to_delete) = split_deleted(&relocated.entries);
+        if delete_destination.unwrap_or(false) {
+            succeeded &= job.delete_entries(&run, &to_delete, &from, &to, &rsync_args);
+        }
+        succeeded &= job.sync_entries(&run, &to_sync, &from, &to, &rsync_args);
+        exit_if_interrupted();
+    }
+    if reverse_fs_diff.is_some() {
+        succeeded &= job.sync_entries(
+            "reverse",
+            &reverse_to_sync,
+            &dst_path,
+            &src_path,
+            &reverse_rsync_args,
+        );
+        exit_if_interrupted();
+    }
+
+    let mut unresolved: Vec<OsString> = Vec::new();
+    if let Some(conflicting) = &conflicting {
+        let (Some(src_root), Some(dst_root)) = (src_path.local_path(), dst_path.local_path())
+        else {
+            unreachable!("conflicts are only resolved between local paths");
+        };
+        let resolution = conflicts::resolve(
+            &conflicting.entries,
+            args.conflict_policy,
+            &args.conflict_suffix,
+            src_root,
+            dst_root,
+            &job.manifest,
+        );
+        succeeded &= job.sync_entries(
+            "conflict",
+            &resolution.forward.iter().collect::<Vec<&ChangedFsEntry>>(),
+            &src_path,
+            &dst_path,
+            &rsync_args,
+        );
+        succeeded &= job.sync_entries(
+            "conflict_reverse",
+            &resolution.reverse.iter().collect::<Vec<&ChangedFsEntry>>(),
+            &dst_path,
+            &src_path,
+            &reverse_rsync_args,
+        );
+        succeeded &= job.delete_entries(
+            "conflict",
+            &resolution
+                .delete_destination
                 .iter()
-                .map(|entry| entry.name.clone())
-                .collect::<Vec<String>>()
-                .join("\0");
-            let file_path = tmp_parts_dir.join(format!("part_{}.list", chunk_number));
-            {
-                std::fs::write(&file_path, files_str).unwrap();
-            }
-            println!(
-                "CHUNK {:>8}: Generated part file '{}'",
-                chunk_number,
-                file_path.display()
+                .collect::<Vec<&ChangedFsEntry>>(),
+            &src_path,
+            &dst_path,
+            &rsync_args,
+        );
+        for name in resolution.skipped.iter() {
+            warn!(path = %escape_name(name), "Skipped conflict");
+        }
+        unresolved = resolution.skipped;
+        exit_if_interrupted();
+    }
+
+    if let Some(update_base_state_to) = &args.update_base_state_to {
+        if !succeeded {
+            error!(
+                "Not updating the base state '{}' as some entries were not synced",
+                update_base_state_to.display()
             );
-            let rsync_stdout_log = tmp_logs_dir.join(format!("rsync_stdout_{}.log", chunk_number));
-            let rsync_stderr_log = tmp_logs_dir.join(format!("rsync_stderr_{}.log", chunk_number));
-            let rsync_process = std::process::Command::new("rsync")
-                .args(rsync_args.clone())
-                .arg(format!(
-                    "--log-file={}",
-                    tmp_logs_dir
-                        .join(format!("rsync_{}.log", chunk_number))
-                        .display()
-                ))
-                .arg(format!("--files-from={}", file_path.display()))
-                .arg("--from0")
-                .arg(src_path)
-                .arg(dst_path)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn();
-            if rsync_process.is_err() {
-                eprintln!(
-                    "CHUNK {:>8}: Failed to spawn rsync process. Error : {}",
-                    chunk_number,
-                    rsync_process.err().unwrap()
-                );
-                return;
-            }
-            let mut rsync_process_unwrap = rsync_process.unwrap();
-            let rsync_process_wait = rsync_process_unwrap.wait();
-            if rsync_process_wait.is_err() {
-                eprintln!(
-                    "CHUNK {:>8}: Failed to wait for rsync process. Error : {}",
-                    chunk_number,
-                    rsync_process_wait.err().unwrap()
-                );
-                return;
-            }
-            rsync_process_wait.unwrap();
-            if rsync_process_unwrap.stdout.is_some() {
-                let mut stdout = rsync_process_unwrap.stdout.unwrap();
-                let mut stdout_buf: String = String::new();
-                if stdout.read_to_string(&mut stdout_buf).is_err() {
-                    eprintln!(
-                        "CHUNK {:>8}: Failed to read stdout of rsync process",
-                        chunk_number
-                    );
-                }
-                if !stdout_buf.is_empty() && std::fs::write(&rsync_stdout_log, stdout_buf).is_err()
-                {
-                    eprintln!(
-                        "CHUNK {:>8}: Failed to write stdout of rsync process to file",
-                        chunk_number,
-                    );
-                }
+            finish(&job, &args, started, Status::Failed);
+            process::exit(1);
+        }
+        let Some(src_root) = src_path.local_path() else {
+            unreachable!("the base state is only updated from a local source path");
+        };
+        let mut bases = vec![(src_root, update_base_state_to)];
+        if let Some(update_dst_base_state_to) = &args.update_dst_base_state_to {
+            let Some(dst_root) = dst_path.local_path() else {
+                unreachable!("the destination base state is only updated from a local path");
+            };
+            bases.push((dst_root, update_dst_base_state_to));
+        }
+        for (root_path, base_state_to) in bases {
+            if let Err(err) = update_base_state(
+                root_path,
+                args.parallelism(),
+                args.folders_to_ignore.clone(),
+                &unresolved,
+                base_state_to,
+            ) {
+                error!("{}", err);
+                finish(&job, &args, started, Status::Failed);
+                process::exit(1);
             }
-            if rsync_process_unwrap.stderr.is_some() {
-                let mut stderr = rsync_process_unwrap.stderr.unwrap();
-                let mut stderr_buf: String = String::new();
-                if stderr.read_to_string(&mut stderr_buf).is_err() {
-                    eprintln!(
-                        "CHUNK {:>8}: Failed to read stderr of rsync process",
-                        chunk_number,
-                    );
-                }
-                if !stderr_buf.is_empty() {
-                    eprintln!(
-                        "CHUNK {:>8}: Some files/attrs were not transferred",
-                        chunk_number
-                    );
-                    if std::fs::write(&rsync_stderr_log, stderr_buf).is_err() {
-                        eprintln!(
-                            "CHUNK {:>8}: Failed to write stderr of rsync process to file",
-                            chunk_number,
-                        );
+        }
+    }
+
+    if let Some(next_sequence) = next_journal_sequence {
+        if succeeded {
+            info!(next_sequence, "Synced journal");
+            if let (true, Some(journal_path)) = (args.truncate_journal, &args.read_journal_from) {
+                match truncate_journal(journal_path, next_sequence) {
+                    Ok(records) => info!(records, "Truncated journal"),
+                    Err(err) => {
+                        error!("{}", err);
+                        finish(&job, &args, started, Status::Failed);
+                        process::exit(1);
                     }
                 }
             }
-        });
+        } else {
+            error!(
+                "Some entries were not synced, sync the journal from sequence {} again",
+                args.journal_from
+            );
+        }
+    }
+
+    let status = if succeeded {
+        Status::Succeeded
+    } else {
+        Status::Failed
+    };
+    finish(&job, &args, started, status);
+    if !succeeded {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::journal::JournalWriter;
 
-    if delete_destination.unwrap_or_else(|| false) {
-        fs_diff
+    fn change(name: &str) -> ChangedFsEntry {
+        ChangedFsEntry {
+            name: OsString::from(name),
+            is_deleted: false,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+        }
+    }
+
+    fn names(changes: &ChangedFsEntries) -> Vec<&str> {
+        changes
             .entries
-            .par_iter()
-            .filter(|entry| entry.is_deleted)
-            .for_each(|entry| {
-                let dst_path = dst_path.clone();
-                let dir_or_file_path = dst_path.join(entry.name.clone());
-                if dir_or_file_path.exists() {
-                    if entry.is_dir {
-                        if remove_dir_all(&dir_or_file_path).is_err() {
-                            eprintln!(
-                                "Failed to remove directory '{}'",
-                                dir_or_file_path.display()
-                            );
-                        }
-                    } else {
-                        if remove_file(&dir_or_file_path).is_err() {
-                            if entry.is_file {
-                                eprintln!("Failed to remove file '{}'", dir_or_file_path.display());
-                            } else if entry.is_symlink {
-                                eprintln!(
-                                    "Failed to remove symlink '{}'",
-                                    dir_or_file_path.display()
-                                );
-                            }
-                        }
-                    }
-                }
+            .iter()
+            .map(|change| change.name.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reads_journal_changes_from_a_sequence() {
+        let path =
+            std::env::temp_dir().join(format!("fs_tools_run_rsync_{}_journal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = JournalWriter::open(&path).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            writer.append(change(name)).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let (changes, next_sequence) = read_journal_changes(&path, 1).unwrap();
+        assert_eq!(names(&changes), ["b", "c", "d"]);
+        assert_eq!(next_sequence, 4);
+        let (changes, next_sequence) = read_journal_changes(&path, 4).unwrap();
+        assert!(changes.entries.is_empty());
+        assert_eq!(next_sequence, 4);
+
+        // Records below the first sequence left are lost, reading them must fail.
+        truncate_journal(&path, 2).unwrap();
+        let result = read_journal_changes(&path, 1);
+        let (changes, _) = read_journal_changes(&path, 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let err = result.err().unwrap();
+        assert!(err.contains("starts at sequence 2"), "{}", err);
+        assert_eq!(names(&changes), ["c", "d"]);
+    }
+
+    /// Syncs chunks with the statuses of `attempts`, by chunk then attempt, the last one repeating.
+    /// Returns the statuses of the chunks and their number of attempts.
+    fn sync_attempts(
+        attempts: &[&[Status]],
+        retries: usize,
+        cancellation: &Cancellation,
+    ) -> (Vec<Status>, Vec<usize>) {
+        let counts = Mutex::new(vec![0; attempts.len()]);
+        let statuses =
+            sync_with_retries("forward", attempts.len(), retries, cancellation, |index| {
+                let mut counts = counts.lock().unwrap();
+                counts[index] += 1;
+                let chunk = attempts[index];
+                chunk[(counts[index] - 1).min(chunk.len() - 1)]
             });
+        (statuses, counts.into_inner().unwrap())
+    }
+
+    #[test]
+    fn queues_timed_out_chunks_again() {
+        let cancellation = Cancellation::new(Duration::ZERO);
+        let (statuses, counts) = sync_attempts(
+            &[
+                &[Status::Succeeded],
+                &[Status::TimedOut, Status::Succeeded],
+                &[Status::Failed],
+                &[Status::TimedOut, Status::TimedOut, Status::Succeeded],
+            ],
+            2,
+            &cancellation,
+        );
+        assert_eq!(
+            statuses,
+            [
+                Status::Succeeded,
+                Status::Succeeded,
+                Status::Failed,
+                Status::Succeeded
+            ]
+        );
+        assert_eq!(counts, [1, 2, 1, 3]);
+    }
+
+    #[test]
+    fn gives_up_on_timed_out_chunks_after_the_retries() {
+        let cancellation = Cancellation::new(Duration::ZERO);
+        let (statuses, counts) = sync_attempts(&[&[Status::TimedOut]], 2, &cancellation);
+        assert_eq!(statuses, [Status::TimedOut]);
+        assert_eq!(counts, [3]);
+
+        let (statuses, counts) = sync_attempts(&[&[Status::TimedOut]], 0, &cancellation);
+        assert_eq!(statuses, [Status::TimedOut]);
+        assert_eq!(counts, [1]);
+    }
+
+    #[test]
+    fn queues_no_chunk_again_once_interrupted() {
+        let cancellation = Cancellation::new(Duration::ZERO);
+        cancellation.interrupt();
+        let (statuses, counts) = sync_attempts(
+            &[
+                &[Status::TimedOut, Status::Succeeded],
+                &[Status::Interrupted],
+            ],
+            2,
+            &cancellation,
+        );
+        assert_eq!(statuses, [Status::TimedOut, Status::Interrupted]);
+        assert_eq!(counts, [1, 1]);
     }
 }
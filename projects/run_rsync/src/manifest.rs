@@ -0,0 +1,330 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped by a signal, the job can be resumed.
+    Interrupted,
+    /// Stopped as it ran for too long or stalled.
+    TimedOut,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Chunk {
+    /// Label of the rsync run of the job the chunk belongs to, `forward` for the main one.
+    pub run: String,
+    pub number: usize,
+    pub entries: usize,
+    pub status: Status,
+}
+
+/// Outcome of the entries of a job, from the rsync log files of its chunks.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Summary {
+    pub transferred: usize,
+    pub transferred_bytes: u64,
+    /// Entries already up to date.
+    pub skipped: usize,
+    pub failed: usize,
+    pub deleted: usize,
+    pub delete_failures: usize,
+}
+
+/// Destination entry renamed by the keep-both conflict policy, so a resumed job finds it again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Renamed {
+    /// Index of the entry in the conflicts file.
+    pub conflict: usize,
+    /// Counter after the conflict suffix of the new name, 0 when there is none.
+    pub counter: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Manifest {
+    pub job_id: String,
+    pub status: Status,
+    /// Chunk size of the job, kept when it is resumed so the chunks are the same.
+    pub chunk_size: usize,
+    /// Summary of the last run of the job, not counting the chunks a resumed job skipped.
+    pub summary: Option<Summary>,
+    #[serde(rename = "chunk", default)]
+    pub chunks: Vec<Chunk>,
+    #[serde(default)]
+    pub renamed: Vec<Renamed>,
+}
+
+/// The manifest of a job, written to its directory whenever it changes.
+#[derive(Clone)]
+pub(crate) struct JobManifest {
+    path: PathBuf,
+    manifest: Arc<Mutex<Manifest>>,
+}
+
+pub(crate) fn manifest_path(job_dir: &Path) -> PathBuf {
+    job_dir.join("manifest.toml")
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "Failed to read job manifest '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })?;
+        toml::from_str(&contents).map_err(|err| {
+            format!(
+                "Failed to parse job manifest '{}'. Error : {}",
+                path.display(),
+                err
+            )
+        })
+    }
+
+    pub fn chunk(&self, run: &str, number: usize) -> Option<&Chunk> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.run == run && chunk.number == number)
+    }
+}
+
+impl JobManifest {
+    pub fn new(path: PathBuf, manifest: Manifest) -> JobManifest {
+        JobManifest {
+            path,
+            manifest: Arc::new(Mutex::new(manifest)),
+        }
+    }
+
+    /// Writes the manifest through a temporary file, so an interrupted write never leaves a
+    /// truncated manifest behind.
+    fn save(&self, manifest: &Manifest) -> Result<(), String> {
+        let contents = toml::to_string(manifest)
+            .map_err(|err| format!("Failed to encode job manifest. Error : {}", err))?;
+        let mut tmp_path = self.path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|err| {
+                format!(
+                    "Failed to write job manifest '{}'. Error : {}",
+                    self.path.display(),
+                    err
+                )
+            })
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Manifest)) -> Result<(), String> {
+        let mut manifest = self.manifest.lock().unwrap_or_else(|err| err.into_inner());
+        update(&mut manifest);
+        self.save(&manifest)
+    }
+
+    pub fn set_status(&self, status: Status) -> Result<(), String> {
+        self.update(|manifest| manifest.status = status)
+    }
+
+    pub fn set_summary(&self, summary: Summary) -> Result<(), String> {
+        self.update(|manifest| manifest.summary = Some(summary))
+    }
+
+    /// Returns the counter of the name the entry at `conflict` in the conflicts file was renamed
+    /// to, by this job or the one it resumes.
+    pub fn renamed(&self, conflict: usize) -> Option<usize> {
+        let manifest = self.manifest.lock().unwrap_or_else(|err| err.into_inner());
+        manifest
+            .renamed
+            .iter()
+            .find(|renamed| renamed.conflict == conflict)
+            .map(|renamed| renamed.counter)
+    }
+
+    pub fn set_renamed(&self, renamed: Renamed) -> Result<(), String> {
+        self.update(|manifest| {
+            match manifest
+                .renamed
+                .iter_mut()
+                .find(|known| known.conflict == renamed.conflict)
+            {
+                Some(known) => *known = renamed,
+                None => manifest.renamed.push(renamed),
+            }
+        })
+    }
+
+    pub fn set_chunk(&self, chunk: Chunk) -> Result<(), String> {
+        self.update(|manifest| {
+            match manifest
+                .chunks
+                .iter_mut()
+                .find(|known| known.run == chunk.run && known.number == chunk.number)
+            {
+                Some(known) => *known = chunk,
+                None => manifest.chunks.push(chunk),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "fs_tools_manifest_{}_{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn chunk(run: &str, number: usize, status: Status) -> Chunk {
+        Chunk {
+            run: String::from(run),
+            number,
+            entries: 10,
+            status,
+        }
+    }
+
+    fn job_manifest(dir: &TempDir) -> JobManifest {
+        JobManifest::new(
+            manifest_path(&dir.0),
+            Manifest {
+                job_id: String::from("job"),
+                status: Status::Running,
+                chunk_size: 10,
+                summary: None,
+                chunks: Vec::new(),
+                renamed: Vec::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn resumes_from_saved_manifests() {
+        let dir = TempDir::new("resume");
+        let job_manifest = job_manifest(&dir);
+        job_manifest
+            .set_chunk(chunk("forward", 0, Status::Running))
+            .unwrap();
+        job_manifest
+            .set_chunk(chunk("forward", 1, Status::Succeeded))
+            .unwrap();
+        job_manifest
+            .set_chunk(chunk("forward", 0, Status::Interrupted))
+            .unwrap();
+        job_manifest
+            .set_chunk(chunk("relocated_0", 0, Status::TimedOut))
+            .unwrap();
+        job_manifest
+            .set_renamed(Renamed {
+                conflict: 2,
+                counter: 1,
+            })
+            .unwrap();
+        job_manifest.set_status(Status::Interrupted).unwrap();
+
+        let manifest = Manifest::load(&manifest_path(&dir.0)).unwrap();
+        assert_eq!(manifest.job_id, "job");
+        assert_eq!(manifest.status, Status::Interrupted);
+        assert_eq!(manifest.chunk_size, 10);
+        assert_eq!(manifest.chunks.len(), 3);
+        assert_eq!(
+            manifest.chunk("forward", 0).unwrap().status,
+            Status::Interrupted
+        );
+        assert_eq!(
+            manifest.chunk("forward", 1).unwrap().status,
+            Status::Succeeded
+        );
+        assert_eq!(
+            manifest.chunk("relocated_0", 0).unwrap().status,
+            Status::TimedOut
+        );
+        assert!(manifest.chunk("forward", 2).is_none());
+        assert!(manifest.chunk("relocated_1", 0).is_none());
+
+        let resumed = JobManifest::new(manifest_path(&dir.0), manifest);
+        assert_eq!(resumed.renamed(2), Some(1));
+        assert_eq!(resumed.renamed(0), None);
+        resumed
+            .set_renamed(Renamed {
+                conflict: 2,
+                counter: 3,
+            })
+            .unwrap();
+        assert_eq!(resumed.renamed(2), Some(3));
+        let manifest = Manifest::load(&manifest_path(&dir.0)).unwrap();
+        assert_eq!(
+            manifest.renamed,
+            [Renamed {
+                conflict: 2,
+                counter: 3
+            }]
+        );
+        assert!(!dir.0.join("manifest.toml.tmp").exists());
+    }
+
+    #[test]
+    fn loads_manifests_without_chunks_or_renames() {
+        let dir = TempDir::new("older");
+        let path = manifest_path(&dir.0);
+        fs::write(
+            &path,
+            "job_id = \"job\"\nstatus = \"succeeded\"\nchunk_size = 5\n",
+        )
+        .unwrap();
+        let manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.status, Status::Succeeded);
+        assert!(manifest.summary.is_none());
+        assert!(manifest.chunks.is_empty());
+        assert!(manifest.renamed.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let dir = TempDir::new("invalid");
+        let path = manifest_path(&dir.0);
+        assert!(Manifest::load(&path)
+            .unwrap_err()
+            .starts_with("Failed to read job manifest"));
+        for contents in [
+            "job_id = \"job\"\nstatus = \"succeeded\"\nchunk_size = 5\nunknown = 1\n",
+            "job_id = \"job\"\nstatus = \"paused\"\nchunk_size = 5\n",
+            "job_id = \"job\"\nstatus = \"succeeded\"\n",
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert!(Manifest::load(&path)
+                .unwrap_err()
+                .starts_with("Failed to parse job manifest"));
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::io;
+
+use clap::ValueEnum;
+
+/// I/O scheduling class of `ioprio_set`, as taken by `ionice -c`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum IoClass {
+    /// Needs root, served before the other classes.
+    Realtime = 1,
+    BestEffort = 2,
+    /// Only served when no other process uses the disk, the level is ignored.
+    Idle = 3,
+}
+
+/// Shift of the class in an I/O priority.
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// CPU and I/O priority of the rsync processes.
+#[derive(Clone, Copy)]
+pub(crate) struct Priority {
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    /// From 0, the highest, to 7.
+    pub io_level: u8,
+}
+
+impl Priority {
+    /// Applies the priority to the calling process. Only makes system calls, so it can run between
+    /// fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            // SAFETY: setpriority has no memory safety requirements.
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(io_class) = self.io_class {
+            set_io_priority(((io_class as u32) << IOPRIO_CLASS_SHIFT) | u32::from(self.io_level))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(ioprio: u32) -> io::Result<()> {
+    // SAFETY: ioprio_set has no memory safety requirements.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_ioprio: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "I/O priorities are only supported on Linux",
+    ))
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use utils::fs::{escape_name, ChangedFsEntry};
+
+/// Prefix of the item lines of the rsync log files, telling them apart from its messages.
+const ITEM_PREFIX: &[u8] = b"fs_tools ";
+
+/// Format rsync is given for the lines it logs for each item: the prefix, the itemized changes,
+/// the number of bytes transferred and the name, last as it may contain spaces.
+pub(crate) const LOG_FILE_FORMAT: &str = "fs_tools %i %b %n";
+
+/// Length of the itemized changes, such as `>f+++++++++`.
+const ITEMIZE_LENGTH: usize = 11;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Outcome {
+    /// Created or updated, its data or only its attributes.
+    Transferred,
+    /// Already up to date.
+    Skipped,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Transferred => "transferred",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+        })
+    }
+}
+
+/// Outcome of the sync of an entry.
+pub(crate) struct FileResult<'a> {
+    pub entry: &'a ChangedFsEntry,
+    pub outcome: Outcome,
+    pub bytes: u64,
+    pub reason: Option<String>,
+}
+
+/// Items and errors read from an rsync log file.
+#[derive(Default)]
+pub(crate) struct RsyncLog {
+    /// Bytes transferred by name.
+    items: HashMap<OsString, u64>,
+    /// Messages by name of the entry they are about.
+    errors: HashMap<OsString, String>,
+}
+
+/// Decodes the `\#ooo` octal escapes rsync writes names with, for bytes that are not printable.
+/// Other backslashes are kept, rsync escapes the ones that would read as an escape.
+fn unescape(name: &[u8]) -> Vec<u8> {
+    let mut unescaped: Vec<u8> = Vec::with_capacity(name.len());
+    let mut rest = name;
+    while let Some((&byte, tail)) = rest.split_first() {
+        if let Some([b'#', digits @ ..]) = tail.get(..4) {
+            if byte == b'\\' && digits.iter().all(|digit| (b'0'..=b'7').contains(digit)) {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |value, digit| value * 8 + u32::from(digit - b'0'));
+                if let Ok(value) = u8::try_from(value) {
+                    unescaped.push(value);
+                    rest = &tail[4..];
+                    continue;
+                }
+            }
+        }
+        unescaped.push(byte);
+        rest = tail;
+    }
+    unescaped
+}
+
+/// Returns the name relative to the transfer root of a path quoted in an rsync message, written
+/// `<root>/./<name>` with `--relative` or `<root>/<name>`.
+fn message_name(path: &[u8], roots: &[&Path]) -> OsString {
+    if let Some(position) = path.windows(3).position(|window| window == b"/./") {
+        return OsStr::from_bytes(&path[position + 3..]).to_os_string();
+    }
+    let path = Path::new(OsStr::from_bytes(path));
+    roots
+        .iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+        .as_os_str()
+        .to_os_string()
+}
+
+fn trim_name(name: &[u8]) -> OsString {
+    let name = name.strip_suffix(b"/").unwrap_or(name);
+    OsStr::from_bytes(name).to_os_string()
+}
+
+impl RsyncLog {
+    /// Parses the contents of a log file written with [`LOG_FILE_FORMAT`], naming the entries
+    /// in messages relative to one of `roots`.
+    pub fn parse(contents: &[u8], roots: &[&Path]) -> RsyncLog {
+        let mut log = RsyncLog::default();
+        for line in contents.split(|byte| *byte == b'\n') {
+            // Lines start with the date, time and process id, as in `2024/01/31 12:00:00 [42] `.
+            let Some(start) = line.windows(2).position(|window| window == b"] ") else {
+                continue;
+            };
+            let line = &line[start + 2..];
+            if let Some(item) = line.strip_prefix(ITEM_PREFIX) {
+                let Some(rest) = item.get(ITEMIZE_LENGTH + 1..) else {
+                    continue;
+                };
+                let Some(space) = rest.iter().position(|byte| *byte == b' ') else {
+                    continue;
+                };
+                let bytes = std::str::from_utf8(&rest[..space])
+                    .ok()
+                    .and_then(|bytes| bytes.parse::<u64>().ok())
+                    .unwrap_or(0);
+                log.items
+                    .insert(trim_name(&unescape(&rest[space + 1..])), bytes);
+            } else if line.starts_with(b"rsync: ") || line.starts_with(b"file has vanished: ") {
+                let first_quote = line.iter().position(|byte| *byte == b'"');
+                let last_quote = line.iter().rposition(|byte| *byte == b'"');
+                if let (Some(first), Some(last)) = (first_quote, last_quote) {
+                    if first < last {
+                        let name = message_name(&unescape(&line[first + 1..last]), roots);
+                        let message = String::from_utf8_lossy(line).into_owned();
+                        log.errors.insert(trim_name(name.as_bytes()), message);
+                    }
+                }
+            }
+        }
+        log
+    }
+
+    /// Returns the outcome of each entry of a chunk. Entries neither logged nor failed are
+    /// skipped, or failed with `chunk_failure` when the rsync process of the chunk did not get to
+    /// report them.
+    pub fn results<'a>(
+        &self,
+        chunk: &[&'a ChangedFsEntry],
+        chunk_failure: Option<&str>,
+    ) -> Vec<FileResult<'a>> {
+        chunk
+            .iter()
+            .map(|entry| {
+                let (outcome, bytes, reason) = match (
+                    self.errors.get(&entry.name),
+                    self.items.get(&entry.name),
+                    chunk_failure,
+                ) {
+                    (Some(message), _, _) => (Outcome::Failed, 0, Some(message.clone())),
+                    (None, Some(bytes), _) => (Outcome::Transferred, *bytes, None),
+                    (None, None, Some(failure)) => (Outcome::Failed, 0, Some(failure.to_string())),
+                    (None, None, None) => (Outcome::Skipped, 0, None),
+                };
+                FileResult {
+                    entry,
+                    outcome,
+                    bytes,
+                    reason,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Writes `results` to `path`, one tab separated line per entry with its outcome, the bytes
+/// transferred, its escaped name and the reason of its failure.
+pub(crate) fn write_results(results: &[FileResult], path: &Path) -> Result<(), String> {
+    let write_error =
+        |err: std::io::Error| format!("Failed to write '{}'. Error : {}", path.display(), err);
+    let mut writer = BufWriter::new(File::create(path).map_err(write_error)?);
+    for result in results {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            result.outcome,
+            result.bytes,
+            escape_name(&result.entry.name),
+            result.reason.as_deref().unwrap_or_default()
+        )
+        .map_err(write_error)?;
+    }
+    writer.flush().map_err(write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &[u8]) -> ChangedFsEntry {
+        ChangedFsEntry {
+            name: OsStr::from_bytes(name).to_os_string(),
+            is_deleted: false,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+        }
+    }
+
+    const LOG: &[u8] = b"\
+2024/01/31 12:00:00 [42] building file list
+2024/01/31 12:00:00 [42] fs_tools cd+++++++++ 0 dir/
+2024/01/31 12:00:00 [42] fs_tools >f+++++++++ 1024 dir/with space
+2024/01/31 12:00:00 [42] fs_tools >f.st...... 10 caf\\#303\\#251
+2024/01/31 12:00:00 [42] fs_tools >f+++++++++ 3 back\\#134#101slash
+2024/01/31 12:00:00 [42] rsync: [sender] send_files failed to open \"/src/./dir/locked\": Permission denied (13)
+2024/01/31 12:00:00 [42] rsync: [sender] send_files failed to open \"/src/new\\#012line\": Permission denied (13)
+2024/01/31 12:00:00 [42] file has vanished: \"/other/gone\"
+2024/01/31 12:00:01 [42] sent 1100 bytes  received 35 bytes  total size 1034
+";
+
+    #[test]
+    fn unescapes_names() {
+        assert_eq!(unescape(b"caf\\#303\\#251"), "café".as_bytes());
+        assert_eq!(unescape(b"back\\#134#101slash"), b"back\\#101slash");
+        assert_eq!(
+            unescape(b"plain\\#12 \\n \\#9999"),
+            b"plain\\#12 \\n \\#9999"
+        );
+        assert_eq!(unescape(b"\\#377"), [0xff]);
+    }
+
+    #[test]
+    fn parses_items_and_errors() {
+        let log = RsyncLog::parse(LOG, &[Path::new("/src"), Path::new("/other")]);
+        let items: HashMap<OsString, u64> = [
+            (OsString::from("dir"), 0),
+            (OsString::from("dir/with space"), 1024),
+            (OsString::from("café"), 10),
+            (OsString::from("back\\#101slash"), 3),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(log.items, items);
+        let mut errors: Vec<&OsString> = log.errors.keys().collect();
+        errors.sort();
+        assert_eq!(errors, ["dir/locked", "gone", "new\nline"]);
+        assert!(log.errors[OsStr::new("gone")].starts_with("file has vanished: "));
+    }
+
+    #[test]
+    fn classifies_results() {
+        let log = RsyncLog::parse(LOG, &[Path::new("/src")]);
+        let entries = [
+            entry(b"dir/with space"),
+            entry("café".as_bytes()),
+            entry(b"dir/locked"),
+            entry(b"unlisted"),
+        ];
+        let chunk: Vec<&ChangedFsEntry> = entries.iter().collect();
+        let outcomes = |results: Vec<FileResult>| -> Vec<(Outcome, u64, bool)> {
+            results
+                .iter()
+                .map(|result| (result.outcome, result.bytes, result.reason.is_some()))
+                .collect()
+        };
+        assert_eq!(
+            outcomes(log.results(&chunk, None)),
+            [
+                (Outcome::Transferred, 1024, false),
+                (Outcome::Transferred, 10, false),
+                (Outcome::Failed, 0, true),
+                (Outcome::Skipped, 0, false),
+            ]
+        );
+        let results = log.results(&chunk, Some("rsync exited with 23"));
+        assert_eq!(results[3].outcome, Outcome::Failed);
+        assert_eq!(results[3].reason.as_deref(), Some("rsync exited with 23"));
+        assert_eq!(results[0].outcome, Outcome::Transferred);
+    }
+}
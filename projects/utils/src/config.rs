@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command, Parser};
+use toml::Value;
+
+use crate::arg_parsers::check_if_file_exists;
+
+/// Directory of the configuration shared by all users.
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/fs_tools";
+
+const CONFIG_FILE_NAMES: [&str; 3] = ["config.toml", "config.yaml", "config.yml"];
+
+/// Directory of the configuration of the current user, `$XDG_CONFIG_HOME/fs_tools` or
+/// `~/.config/fs_tools`.
+fn user_config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("fs_tools"))
+}
+
+fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    CONFIG_FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Reads a TOML config file, or a YAML one when its extension is `.yaml` or `.yml`.
+fn read_config_file(path: &Path) -> Result<Value, String> {
+    let contents = fs::read_to_string(path).map_err(|err| {
+        format!(
+            "Failed to read config file '{}'. Error : {}",
+            path.display(),
+            err
+        )
+    })?;
+    let is_yaml = matches!(
+        path.extension().and_then(OsStr::to_str),
+        Some("yaml") | Some("yml")
+    );
+    let parsed = if is_yaml {
+        serde_yaml::from_str(&contents).map_err(|err| err.to_string())
+    } else {
+        toml::from_str(&contents).map_err(|err| err.to_string())
+    };
+    parsed.map_err(|err| {
+        format!(
+            "Failed to parse config file '{}'. Error : {}",
+            path.display(),
+            err
+        )
+    })
+}
+
+/// Returns the value of the option `--long` in `args`, given as `--long VALUE` or `--long=VALUE`.
+fn find_option(args: &[OsString], long: &str) -> Option<OsString> {
+    let flag = format!("--{}", long);
+    let prefix = format!("--{}=", long);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg.as_os_str() == OsStr::new(&flag) {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix(&prefix)) {
+            return Some(OsString::from(value));
+        }
+    }
+    None
+}
+
+fn value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Integer(value) => Some(value.to_string()),
+        Value::Float(value) => Some(value.to_string()),
+        Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns whether the option `arg` is given on the command line parsed into `matches`.
+fn is_given(matches: Option<&ArgMatches>, arg: &Arg) -> bool {
+    matches.is_some_and(|matches| {
+        matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    })
+}
+
+/// Converts the option `arg` set to `value` in a config file to command line arguments.
+fn option_to_args(command: &Command, arg: &Arg, value: &Value) -> Result<Vec<OsString>, String> {
+    let long = arg.get_long().unwrap_or_default();
+    let invalid = || {
+        format!(
+            "Invalid value for option '{}' of {}",
+            long,
+            command.get_name()
+        )
+    };
+    let flag = format!("--{}", long);
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(true)) => Ok(vec![flag.into()]),
+        (ArgAction::SetTrue, Value::Boolean(false)) => Ok(Vec::new()),
+        (ArgAction::SetTrue, _) => Err(invalid()),
+        (ArgAction::Count, Value::Integer(count)) if *count >= 0 => {
+            Ok(vec![OsString::from(&flag); *count as usize])
+        }
+        (ArgAction::Count, _) => Err(invalid()),
+        (ArgAction::Append, Value::Array(values)) => values
+            .iter()
+            .map(|value| {
+                value_to_string(value)
+                    .map(|value| OsString::from(format!("{}={}", flag, value)))
+                    .ok_or_else(invalid)
+            })
+            .collect(),
+        (_, Value::Array(values)) => {
+            let values = values
+                .iter()
+                .map(|value| value_to_string(value).ok_or_else(invalid))
+                .collect::<Result<Vec<String>, String>>()?;
+            match arg.get_value_delimiter() {
+                Some(delimiter) => Ok(vec![format!(
+                    "{}={}",
+                    flag,
+                    values.join(&delimiter.to_string())
+                )
+                .into()]),
+                None => Ok(std::iter::once(flag)
+                    .chain(values)
+                    .map(OsString::from)
+                    .collect()),
+            }
+        }
+        (_, value) => value_to_string(value)
+            .map(|value| vec![OsString::from(format!("{}={}", flag, value))])
+            .ok_or_else(invalid),
+    }
+}
+
+/// Returns the options of `tool` set in a config file, from its `[<tool>]` table or from its
+/// `[profile.<profile>.<tool>]` table.
+fn config_options<'a>(
+    config: &'a Value,
+    tool: &str,
+    profile: Option<&str>,
+) -> Option<&'a toml::Table> {
+    let config = match profile {
+        Some(profile) => config.get("profile")?.get(profile)?,
+        None => config,
+    };
+    config.get(tool)?.as_table()
+}
+
+/// Adds the config file options to `command`, `--config` only when `explicit_config` is set.
+fn with_config_args(command: Command, explicit_config: bool) -> Command {
+    let mut command = command.arg(Arg::new("profile").long("profile").help("").long_help(
+        "Name of the profile of the config files to apply, from their [profile.NAME.TOOL] tables",
+    ));
+    if explicit_config {
+        command = command.arg(
+            Arg::new("config file")
+                .long("config")
+                .value_parser(check_if_file_exists())
+                .help("")
+                .long_help(format!(
+                    "Path to a TOML or YAML config file applied after {dir}/config.toml and ~/.config/fs_tools/config.toml. Options of a [TOOL] table are set as if given on the command line, before the actual command line arguments",
+                    dir = SYSTEM_CONFIG_DIR
+                )),
+        );
+    }
+    command
+}
+
+/// Returns `args` with the options set in the config files, and not given in `args`, inserted
+/// after the binary name.
+///
+/// Config files are read from the system config directory, then the user config directory, then
+/// the `--config` option when `explicit_config` is set. The `[<tool>]` tables of all the files apply first, in that order, then
+/// the `[profile.<name>.<tool>]` tables of the profile given with `--profile`.
+fn args_with_config(
+    command: &Command,
+    args: Vec<OsString>,
+    explicit_config: bool,
+) -> Result<Vec<OsString>, String> {
+    let tool = command.get_name().to_string();
+    let explicit_config = if explicit_config {
+        find_option(&args, "config").map(PathBuf::from)
+    } else {
+        None
+    };
+    let profile =
+        find_option(&args, "profile").map(|profile| profile.to_string_lossy().into_owned());
+
+    let mut configs: Vec<Value> = Vec::new();
+    let config_paths = [
+        find_config_file(Path::new(SYSTEM_CONFIG_DIR)),
+        user_config_dir().and_then(|dir| find_config_file(&dir)),
+        explicit_config,
+    ];
+    for path in config_paths.into_iter().flatten() {
+        configs.push(read_config_file(&path)?);
+    }
+
+    let mut options: BTreeMap<String, Value> = BTreeMap::new();
+    for config in configs.iter() {
+        if let Some(table) = config_options(config, &tool, None) {
+            options.extend(
+                table
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+    }
+    if let Some(profile) = &profile {
+        let mut found = false;
+        for config in configs.iter() {
+            if let Some(table) = config_options(config, &tool, Some(profile)) {
+                found = true;
+                options.extend(
+                    table
+                        .iter()
+                        .map(|(key, value)| (key.clone(), value.clone())),
+                );
+            }
+        }
+        if !found {
+            return Err(format!(
+                "No profile '{}' for {} in the config files",
+                profile, tool
+            ));
+        }
+    }
+
+    // Parsed leniently, as required options can come from the config files.
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok();
+    let mut config_args: Vec<OsString> = Vec::new();
+    for (long, value) in options.iter() {
+        if long == "config" || long == "profile" {
+            return Err(format!("Option '{}' cannot be set in a config file", long));
+        }
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .ok_or_else(|| format!("Unknown option '{}' for {}", long, tool))?;
+        // Options given on the command line replace the config ones instead of adding to them.
+        if !is_given(matches.as_ref(), arg) {
+            config_args.extend(option_to_args(command, arg, value)?);
+        }
+    }
+    let mut args = args.into_iter();
+    Ok(args
+        .next()
+        .into_iter()
+        .chain(config_args)
+        .chain(args)
+        .collect())
+}
+
+/// Parses the command line arguments of a tool merged with its config files, see
+/// [`args_with_config`]. Tools using `--config` for their own configuration only read the system
+/// and user config files.
+pub fn parse<P: Parser>() -> P {
+    let command = P::command();
+    let explicit_config = !command
+        .get_arguments()
+        .any(|arg| arg.get_long() == Some("config"));
+    let command = with_config_args(command, explicit_config);
+    let args = args_with_config(&command, env::args_os().collect(), explicit_config)
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+    let mut matches = command.get_matches_from(args);
+    P::from_arg_matches_mut(&mut matches).unwrap_or_else(|err| err.exit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("tool")
+            .arg(Arg::new("source").long("source").short('s').required(true))
+            .arg(
+                Arg::new("extra")
+                    .long("extra")
+                    .short('e')
+                    .allow_hyphen_values(true),
+            )
+            .arg(
+                Arg::new("verbose")
+                    .long("verbose")
+                    .short('v')
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("level")
+                    .long("level")
+                    .value_parser(clap::value_parser!(u8)),
+            )
+    }
+
+    fn given(args: &[&str]) -> Vec<String> {
+        let command = command();
+        let matches = command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(args)
+            .ok();
+        command
+            .get_arguments()
+            .filter(|arg| is_given(matches.as_ref(), arg))
+            .map(|arg| arg.get_id().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn finds_given_options() {
+        assert_eq!(given(&["tool", "-s", "a"]), ["source"]);
+        assert_eq!(given(&["tool", "-sa"]), ["source"]);
+        assert_eq!(given(&["tool", "--source=a", "-v"]), ["source", "verbose"]);
+        assert_eq!(given(&["tool", "-e", "-sfoo"]), ["extra"]);
+        assert_eq!(given(&["tool", "--extra=-v"]), ["extra"]);
+        assert_eq!(given(&["tool", "--", "-v"]), Vec::<String>::new());
+        assert_eq!(given(&["tool", "--level", "x"]), ["level"]);
+    }
+}
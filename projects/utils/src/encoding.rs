@@ -0,0 +1,212 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Chain, Cursor, Read, Write},
+    path::Path,
+};
+
+use bincode::{Decode, Encode};
+use clap::ValueEnum;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+/// Length of the magic bytes identifying compressed and state files.
+pub const MAGIC_LENGTH: usize = 4;
+
+/// A reader whose first bytes were read and put back in front of it.
+pub type Rewound<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Reads the first [`MAGIC_LENGTH`] bytes of `reader`, fewer when it ends before, and returns them
+/// with a reader starting over from them. Reads until they are all there, as pipes can return
+/// fewer bytes than asked for.
+pub fn read_magic<R: Read>(mut reader: R) -> std::io::Result<(Vec<u8>, Rewound<R>)> {
+    let mut magic: Vec<u8> = Vec::with_capacity(MAGIC_LENGTH);
+    (&mut reader)
+        .take(MAGIC_LENGTH as u64)
+        .read_to_end(&mut magic)?;
+    Ok((magic.clone(), Cursor::new(magic).chain(reader)))
+}
+
+/// Wraps `reader` in a decompressor when its contents start with a zstd or lz4 frame.
+pub fn decompressing_reader<'a, R: BufRead + 'a>(reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+    let (magic, reader) = read_magic(reader)?;
+    if magic.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(reader)?))
+    } else if magic.starts_with(&LZ4_MAGIC) {
+        Ok(Box::new(BufReader::new(
+            lz4_flex::frame::FrameDecoder::new(reader),
+        )))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Opens a state or diff file for reading, transparently decompressing it.
+pub fn open_file(path: &Path) -> Result<BufReader<Box<dyn Read>>, String> {
+    let file = File::open(path)
+        .map_err(|err| format!("Failed to open '{}'. Error : {}", path.display(), err))?;
+    decompressing_reader(BufReader::new(file))
+        .map(BufReader::new)
+        .map_err(|err| format!("Failed to read '{}'. Error : {}", path.display(), err))
+}
+
+/// Decodes a state or diff file, transparently decompressing it.
+pub fn read_from_file<T: Decode>(path: &Path) -> Result<T, String> {
+    let mut reader = open_file(path)?;
+    bincode::decode_from_std_read(&mut reader, bincode::config::standard())
+        .map_err(|err| format!("Failed to decode '{}'. Error : {}", path.display(), err))
+}
+
+fn encode_into<T: Encode, W: Write>(
+    value: &T,
+    writer: W,
+    compression: Compression,
+    level: i32,
+) -> std::io::Result<W> {
+    let to_io_error = |err: bincode::error::EncodeError| std::io::Error::other(err.to_string());
+    match compression {
+        Compression::None => {
+            let mut writer = writer;
+            bincode::encode_into_std_write(value, &mut writer, bincode::config::standard())
+                .map_err(to_io_error)?;
+            Ok(writer)
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, level)?;
+            bincode::encode_into_std_write(value, &mut encoder, bincode::config::standard())
+                .map_err(to_io_error)?;
+            encoder.finish()
+        }
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(writer);
+            bincode::encode_into_std_write(value, &mut encoder, bincode::config::standard())
+                .map_err(to_io_error)?;
+            encoder.finish().map_err(std::io::Error::other)
+        }
+    }
+}
+
+/// Encodes a state or diff to `writer`, compressing it with `compression`.
+/// `level` is only used by zstd, 0 selects its default level.
+pub fn write_to_writer<T: Encode, W: Write>(
+    value: &T,
+    writer: W,
+    compression: Compression,
+    level: i32,
+) -> std::io::Result<()> {
+    encode_into(value, BufWriter::new(writer), compression, level)
+        .and_then(|mut writer| writer.flush())
+}
+
+/// Encodes a state or diff file, compressing it with `compression`.
+/// `level` is only used by zstd, 0 selects its default level.
+pub fn write_to_file<T: Encode>(
+    value: &T,
+    path: &Path,
+    compression: Compression,
+    level: i32,
+) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|err| format!("Failed to create '{}'. Error : {}", path.display(), err))?;
+    write_to_writer(value, file, compression, level)
+        .map_err(|err| format!("Failed to write '{}'. Error : {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader returning one byte per read, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((byte, rest)), Some(first)) => {
+                    *first = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn value() -> Vec<String> {
+        (0..100).map(|index| format!("entry {}", index)).collect()
+    }
+
+    fn encode(compression: Compression) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        write_to_writer(&value(), &mut encoded, compression, 0).unwrap();
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Vec<String> {
+        let mut reader = decompressing_reader(encoded).unwrap();
+        bincode::decode_from_std_read(&mut reader, bincode::config::standard()).unwrap()
+    }
+
+    #[test]
+    fn reads_magic_bytes() {
+        let (magic, mut reader) = read_magic(Trickle(b"magic bytes")).unwrap();
+        assert_eq!(magic, b"magi");
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "magic bytes");
+
+        let (magic, mut reader) = read_magic(Trickle(b"ab")).unwrap();
+        assert_eq!(magic, b"ab");
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "ab");
+    }
+
+    #[test]
+    fn sniffs_compression() {
+        assert!(encode(Compression::Zstd).starts_with(&ZSTD_MAGIC));
+        assert!(encode(Compression::Lz4).starts_with(&LZ4_MAGIC));
+        let uncompressed = encode(Compression::None);
+        assert!(!uncompressed.starts_with(&ZSTD_MAGIC) && !uncompressed.starts_with(&LZ4_MAGIC));
+        assert!(encode(Compression::Zstd).len() < uncompressed.len());
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            assert_eq!(decode(&encode(compression)), value());
+        }
+        // Empty inputs are read as they are.
+        let mut contents = Vec::new();
+        decompressing_reader(&b""[..])
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert!(contents.is_empty());
+    }
+
+    #[test]
+    fn round_trips_files() {
+        let path = std::env::temp_dir().join(format!(
+            "fs_tools_encoding_{}_round_trip",
+            std::process::id()
+        ));
+        for (compression, level) in [
+            (Compression::None, 0),
+            (Compression::Zstd, 19),
+            (Compression::Lz4, 0),
+        ] {
+            write_to_file(&value(), &path, compression, level).unwrap();
+            assert_eq!(read_from_file::<Vec<String>>(&path).unwrap(), value());
+        }
+        std::fs::write(&path, ZSTD_MAGIC).unwrap();
+        let err = read_from_file::<Vec<String>>(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.starts_with("Failed to"), "{}", err);
+    }
+}
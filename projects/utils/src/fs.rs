@@ -1,17 +1,39 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::{self, Metadata},
+    ops::Bound,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use bincode::{Decode, Encode};
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    impl_borrow_decode, Decode, Encode,
+};
 use jwalk::{Parallelism, WalkDirGeneric};
 use rayon::prelude::*;
 use std::os::unix::fs::MetadataExt;
 
-#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+use crate::{
+    encoding::{read_from_file, write_to_file, Compression},
+    throttle::RateLimiter,
+};
+
+/// Entry names are raw bytes so file names that are not valid UTF-8 are kept as is. They are
+/// encoded exactly like a `String` would be, keeping state files written with `String` names readable.
+#[derive(PartialEq, Debug, Clone)]
 pub struct FsEntry {
-    pub name: String,
+    pub name: OsString,
     pub owner: u32,
     pub group: u32,
     pub mode: u32,
     pub mtime: i64,
+    /// Not part of the encoding of an entry, states store it separately. 0 when unknown.
+    pub ctime: i64,
     pub inode: u64,
     pub size: u64,
     pub is_dir: bool,
@@ -19,25 +41,257 @@ pub struct FsEntry {
     pub is_symlink: bool,
 }
 
+fn encode_name<E: Encoder>(name: &OsStr, encoder: &mut E) -> Result<(), EncodeError> {
+    name.as_bytes().encode(encoder)
+}
+
+fn decode_name<D: Decoder>(decoder: &mut D) -> Result<OsString, DecodeError> {
+    Ok(OsString::from_vec(Vec::<u8>::decode(decoder)?))
+}
+
+impl Encode for FsEntry {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        encode_name(&self.name, encoder)?;
+        self.owner.encode(encoder)?;
+        self.group.encode(encoder)?;
+        self.mode.encode(encoder)?;
+        self.mtime.encode(encoder)?;
+        self.inode.encode(encoder)?;
+        self.size.encode(encoder)?;
+        self.is_dir.encode(encoder)?;
+        self.is_file.encode(encoder)?;
+        self.is_symlink.encode(encoder)
+    }
+}
+
+impl Decode for FsEntry {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(FsEntry {
+            name: decode_name(decoder)?,
+            owner: Decode::decode(decoder)?,
+            group: Decode::decode(decoder)?,
+            mode: Decode::decode(decoder)?,
+            mtime: Decode::decode(decoder)?,
+            ctime: 0,
+            inode: Decode::decode(decoder)?,
+            size: Decode::decode(decoder)?,
+            is_dir: Decode::decode(decoder)?,
+            is_file: Decode::decode(decoder)?,
+            is_symlink: Decode::decode(decoder)?,
+        })
+    }
+}
+impl_borrow_decode!(FsEntry);
+
+impl FsEntry {
+    pub fn from_metadata(name: OsString, metadata: &Metadata) -> FsEntry {
+        FsEntry {
+            name,
+            owner: metadata.uid(),
+            group: metadata.gid(),
+            mode: metadata.mode(),
+            mtime: metadata.mtime(),
+            ctime: metadata.ctime(),
+            inode: metadata.ino(),
+            size: metadata.size(),
+            is_dir: metadata.is_dir(),
+            is_file: metadata.is_file(),
+            is_symlink: metadata.is_symlink(),
+        }
+    }
+}
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct FsEntries {
     pub entries: Vec<FsEntry>,
 }
 
-#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct ChangedFsEntry {
-    pub name: String,
+    pub name: OsString,
     pub is_deleted: bool,
     pub is_dir: bool,
     pub is_file: bool,
     pub is_symlink: bool,
 }
 
+impl Encode for ChangedFsEntry {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        encode_name(&self.name, encoder)?;
+        self.is_deleted.encode(encoder)?;
+        self.is_dir.encode(encoder)?;
+        self.is_file.encode(encoder)?;
+        self.is_symlink.encode(encoder)
+    }
+}
+
+impl Decode for ChangedFsEntry {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(ChangedFsEntry {
+            name: decode_name(decoder)?,
+            is_deleted: Decode::decode(decoder)?,
+            is_dir: Decode::decode(decoder)?,
+            is_file: Decode::decode(decoder)?,
+            is_symlink: Decode::decode(decoder)?,
+        })
+    }
+}
+impl_borrow_decode!(ChangedFsEntry);
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct ChangedFsEntries {
     pub entries: Vec<ChangedFsEntry>,
 }
 
+/// Changes of a directory relocated on the destination, named relative to it on both sides.
+/// They are written next to a diff file, see [`relocated_diff_path`].
+#[derive(PartialEq, Debug, Clone)]
+pub struct RelocatedChanges {
+    /// Source directory, relative to the compared source directory.
+    pub src_dir: OsString,
+    /// Destination directory, relative to the compared destination directory.
+    pub dst_dir: OsString,
+    pub entries: Vec<ChangedFsEntry>,
+}
+
+impl Encode for RelocatedChanges {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        encode_name(&self.src_dir, encoder)?;
+        encode_name(&self.dst_dir, encoder)?;
+        self.entries.encode(encoder)
+    }
+}
+
+impl Decode for RelocatedChanges {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(RelocatedChanges {
+            src_dir: decode_name(decoder)?,
+            dst_dir: decode_name(decoder)?,
+            entries: Decode::decode(decoder)?,
+        })
+    }
+}
+impl_borrow_decode!(RelocatedChanges);
+
+/// Path of the changes of the `number`th relocated directory of the diff file `diff_path`, the
+/// diff file name followed by `.<number>`, from 1.
+pub fn relocated_diff_path(diff_path: &Path, number: usize) -> PathBuf {
+    let mut path = diff_path.as_os_str().to_os_string();
+    path.push(format!(".{}", number));
+    PathBuf::from(path)
+}
+
+/// Reads the changes of the relocated directories written next to the diff file `diff_path`.
+pub fn read_relocated_changes(diff_path: &Path) -> Result<Vec<RelocatedChanges>, String> {
+    let mut relocated = Vec::new();
+    loop {
+        let path = relocated_diff_path(diff_path, relocated.len() + 1);
+        if !path.exists() {
+            return Ok(relocated);
+        }
+        relocated.push(read_from_file(&path)?);
+    }
+}
+
+/// Writes the changes of the relocated directories next to the diff file `diff_path`, removing
+/// the ones left by an earlier diff with more relocated directories.
+pub fn write_relocated_changes(
+    relocated: &[RelocatedChanges],
+    diff_path: &Path,
+    compression: Compression,
+    level: i32,
+) -> Result<(), String> {
+    for (index, changes) in relocated.iter().enumerate() {
+        write_to_file(
+            changes,
+            &relocated_diff_path(diff_path, index + 1),
+            compression,
+            level,
+        )?;
+    }
+    let mut number = relocated.len() + 1;
+    loop {
+        let path = relocated_diff_path(diff_path, number);
+        match fs::remove_file(&path) {
+            Ok(()) => number += 1,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(format!(
+                    "Failed to remove '{}'. Error : {}",
+                    path.display(),
+                    err
+                ))
+            }
+        }
+    }
+}
+
+/// Splits an entry name into its parent directory name (empty at the root) and its file name.
+pub fn split_name(name: &OsStr) -> (&OsStr, &OsStr) {
+    let bytes = name.as_bytes();
+    match bytes.iter().rposition(|byte| *byte == b'/') {
+        Some(index) => (
+            OsStr::from_bytes(&bytes[..index]),
+            OsStr::from_bytes(&bytes[index + 1..]),
+        ),
+        None => (OsStr::new(""), name),
+    }
+}
+
+/// Joins a directory name (empty for the root) and a file name into an entry name.
+pub fn join_name(dir_name: &OsStr, file_name: &OsStr) -> OsString {
+    if dir_name.is_empty() {
+        return file_name.to_os_string();
+    }
+    let mut name = OsString::with_capacity(dir_name.len() + 1 + file_name.len());
+    name.push(dir_name);
+    name.push("/");
+    name.push(file_name);
+    name
+}
+
+/// Returns the range of entry names below the directory `name`, all names for the root.
+pub fn names_below(name: &OsStr) -> (Bound<OsString>, Bound<OsString>) {
+    if name.is_empty() {
+        return (Bound::Unbounded, Bound::Unbounded);
+    }
+    // '0' is the byte following '/', so the range holds exactly the names starting with "name/".
+    let mut start = name.to_os_string();
+    start.push("/");
+    let mut end = name.to_os_string();
+    end.push("0");
+    (Bound::Included(start), Bound::Excluded(end))
+}
+
+/// Renders an entry name for text output. Backslashes and control characters are escaped
+/// and bytes that are not valid UTF-8 are written as `\xNN`.
+pub fn escape_name(name: &OsStr) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for chunk in name.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                escaped.push_str("\\\\");
+            } else if c.is_control() {
+                escaped.extend(c.escape_default());
+            } else {
+                escaped.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// Returns `path` relative to `root_path` as an entry name.
+pub fn relative_name(path: &Path, root_path: &Path) -> OsString {
+    path.strip_prefix(root_path)
+        .unwrap_or(path)
+        .as_os_str()
+        .to_os_string()
+}
+
 pub fn walk_dir(
     root_path: PathBuf,
     parallelism: Parallelism,
@@ -45,23 +299,25 @@ pub fn walk_dir(
     skip_hidden: bool,
     sort: bool,
     folder_to_ignore: Vec<String>,
+    limiter: Option<Arc<RateLimiter>>,
 ) -> Vec<FsEntry> {
     WalkDirGeneric::<(Vec<String>, bool)>::new(root_path)
         .root_read_dir_state(folder_to_ignore.clone())
-        .process_read_dir(|_depth, _path, read_dir_state, children| {
+        .process_read_dir(move |_depth, _path, read_dir_state, children| {
             children.retain(|dir_entry_result| {
                 dir_entry_result
                     .as_ref()
                     .map(|dir_entry| {
-                        let file_name = dir_entry
-                            .file_name
-                            .clone()
-                            .into_string()
-                            .unwrap_or(String::new());
-                        !read_dir_state.into_iter().any(|s| s == &file_name)
+                        !read_dir_state
+                            .iter()
+                            .any(|s| OsStr::new(s) == dir_entry.file_name)
                     })
                     .unwrap_or(false)
             });
+            // Holding back the listing of a directory holds back the stat of its entries too.
+            if let Some(limiter) = &limiter {
+                limiter.acquire(children.len() as u64);
+            }
         })
         .skip_hidden(skip_hidden)
         .follow_links(follow_links)
@@ -70,30 +326,11 @@ pub fn walk_dir(
         .into_iter()
         .par_bridge()
         .map(|entry| {
-            if entry.is_ok() {
-                let entry_unwrap = entry.unwrap();
+            if let Ok(entry_unwrap) = entry {
                 if entry_unwrap.depth != 0 && entry_unwrap.metadata().is_ok() {
                     let metadata = entry_unwrap.metadata().unwrap();
-                    let name = String::from(
-                        entry_unwrap
-                            .path()
-                            .as_os_str()
-                            .to_str()
-                            .unwrap()
-                            .trim_start_matches("./"),
-                    );
-                    return Ok(FsEntry {
-                        name,
-                        owner: metadata.uid(),
-                        group: metadata.gid(),
-                        mode: metadata.mode(),
-                        mtime: metadata.mtime(),
-                        inode: metadata.ino(),
-                        size: metadata.size(),
-                        is_dir: metadata.is_dir(),
-                        is_file: metadata.is_file(),
-                        is_symlink: metadata.is_symlink(),
-                    });
+                    let name = entry_unwrap.path().into_os_string();
+                    return Ok(FsEntry::from_metadata(name, &metadata));
                 }
             }
             Err(())
@@ -102,3 +339,332 @@ pub fn walk_dir(
         .map(|entry| entry.unwrap())
         .collect()
 }
+
+struct IncrementalWalk<'a> {
+    root_path: PathBuf,
+    folders_to_ignore: Vec<String>,
+    previous_dirs: HashMap<&'a OsStr, &'a FsEntry>,
+    previous_children: HashMap<&'a OsStr, Vec<&'a FsEntry>>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl IncrementalWalk<'_> {
+    fn is_ignored(&self, file_name: &OsStr) -> bool {
+        self.folders_to_ignore
+            .iter()
+            .any(|s| OsStr::new(s) == file_name)
+    }
+
+    fn is_unchanged(&self, dir_entry: &FsEntry) -> bool {
+        self.previous_dirs
+            .get(dir_entry.name.as_os_str())
+            .map(|previous| {
+                previous.is_dir
+                    && previous.inode == dir_entry.inode
+                    && previous.mtime == dir_entry.mtime
+                    && previous.ctime == dir_entry.ctime
+            })
+            .unwrap_or(false)
+    }
+
+    fn stat(&self, name: OsString) -> Option<FsEntry> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(1);
+        }
+        match fs::symlink_metadata(self.root_path.join(&name)) {
+            Ok(metadata) => Some(FsEntry::from_metadata(name, &metadata)),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns the entries below the directory `dir_name` (relative to the root, empty for the root).
+    fn walk(&self, dir_name: &OsStr, unchanged: bool) -> Vec<FsEntry> {
+        let mut entries: Vec<FsEntry> = Vec::new();
+        let mut dirs: Vec<FsEntry> = Vec::new();
+        if unchanged {
+            for previous in self.previous_children.get(dir_name).into_iter().flatten() {
+                if self.is_ignored(split_name(&previous.name).1) {
+                    continue;
+                }
+                // Files rewritten in place leave their directory unchanged.
+                match self.stat(previous.name.clone()) {
+                    Some(entry) if entry.is_dir => dirs.push(entry),
+                    Some(entry) => entries.push(entry),
+                    None => {}
+                }
+            }
+        } else {
+            let Ok(read_dir) = fs::read_dir(self.root_path.join(dir_name)) else {
+                return entries;
+            };
+            for dir_entry in read_dir.flatten() {
+                let file_name = dir_entry.file_name();
+                if self.is_ignored(&file_name) {
+                    continue;
+                }
+                if let Some(entry) = self.stat(join_name(dir_name, &file_name)) {
+                    if entry.is_dir {
+                        dirs.push(entry);
+                    } else {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+        let nested: Vec<Vec<FsEntry>> = dirs
+            .par_iter()
+            .map(|dir_entry| self.walk(&dir_entry.name, self.is_unchanged(dir_entry)))
+            .collect();
+        entries.extend(dirs);
+        entries.extend(nested.into_iter().flatten());
+        entries
+    }
+}
+
+/// Walks `root_path` reusing the entries of a `previous` state of it.
+///
+/// Directories whose inode, mtime and ctime did not change since `previous` are not listed again,
+/// their entries are taken from `previous` and stat'ed again. Entries of `previous` without a
+/// ctime never match, so their directories are listed.
+/// Entry names are relative to `root_path`. Entries are stat'ed at the rate of `limiter` when
+/// given.
+pub fn walk_dir_incremental(
+    root_path: PathBuf,
+    previous: &FsEntries,
+    folders_to_ignore: Vec<String>,
+    limiter: Option<Arc<RateLimiter>>,
+) -> Vec<FsEntry> {
+    let mut previous_dirs: HashMap<&OsStr, &FsEntry> = HashMap::new();
+    let mut previous_children: HashMap<&OsStr, Vec<&FsEntry>> = HashMap::new();
+    for entry in previous.entries.iter() {
+        let (parent, _) = split_name(&entry.name);
+        previous_children.entry(parent).or_default().push(entry);
+        if entry.is_dir {
+            previous_dirs.insert(entry.name.as_os_str(), entry);
+        }
+    }
+    IncrementalWalk {
+        root_path,
+        folders_to_ignore,
+        previous_dirs,
+        previous_children,
+        limiter,
+    }
+    .walk(OsStr::new(""), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::File,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    /// Directory removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("fs_tools_fs_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            for dir in ["d", "e"] {
+                fs::create_dir_all(path.join(dir)).unwrap();
+            }
+            for file in ["d/a", "d/b", "e/c"] {
+                fs::write(path.join(file), "a").unwrap();
+            }
+            TempDir(path)
+        }
+
+        fn scan(&self, previous: &[FsEntry]) -> Vec<FsEntry> {
+            let previous = FsEntries {
+                entries: previous.to_vec(),
+            };
+            let mut entries = walk_dir_incremental(self.0.clone(), &previous, Vec::new(), None);
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            entries
+        }
+
+        /// Sets the mtime of `name` back to `mtime`, which changes its ctime.
+        fn set_mtime(&self, name: &str, mtime: i64) {
+            File::open(self.0.join(name))
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime as u64))
+                .unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn without(entries: &[FsEntry], name: &str) -> Vec<FsEntry> {
+        entries
+            .iter()
+            .filter(|entry| entry.name != name)
+            .cloned()
+            .collect()
+    }
+
+    fn names(entries: &[FsEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.name.to_str().unwrap())
+            .collect()
+    }
+
+    /// A name that is not valid UTF-8.
+    fn invalid_name() -> OsString {
+        OsString::from_vec(b"d/caf\xe9".to_vec())
+    }
+
+    #[test]
+    fn escapes_names() {
+        assert_eq!(escape_name(OsStr::new("d/caf\u{e9}")), "d/caf\u{e9}");
+        assert_eq!(escape_name(OsStr::new("a\\b")), "a\\\\b");
+        assert_eq!(escape_name(OsStr::new("a\nb\tc\u{1b}")), "a\\nb\\tc\\u{1b}");
+        assert_eq!(escape_name(&invalid_name()), "d/caf\\xe9");
+        assert_eq!(
+            escape_name(OsStr::from_bytes(b"\xff\xfe/ok")),
+            "\\xff\\xfe/ok"
+        );
+    }
+
+    #[test]
+    fn encodes_names_as_strings() {
+        let config = bincode::config::standard();
+        let entry = ChangedFsEntry {
+            name: OsString::from("d/caf\u{e9}"),
+            is_deleted: true,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+        };
+        let encoded = bincode::encode_to_vec(&entry, config).unwrap();
+        let string = bincode::encode_to_vec(String::from("d/caf\u{e9}"), config).unwrap();
+        assert!(encoded.starts_with(&string));
+
+        let entry = ChangedFsEntry {
+            name: invalid_name(),
+            ..entry
+        };
+        let encoded = bincode::encode_to_vec(&entry, config).unwrap();
+        let (decoded, _): (ChangedFsEntry, usize) =
+            bincode::decode_from_slice(&encoded, config).unwrap();
+        assert_eq!(decoded, entry);
+    }
+
+    #[test]
+    fn keeps_names_that_are_not_utf8() {
+        let dir = TempDir::new("invalid_names");
+        fs::write(dir.0.join(invalid_name()), "a").unwrap();
+        let entries = walk_dir(
+            dir.0.clone(),
+            Parallelism::Serial,
+            false,
+            false,
+            false,
+            Vec::new(),
+            None,
+        );
+        let entry = entries
+            .into_iter()
+            .find(|entry| entry.name.as_bytes().ends_with(b"caf\xe9"))
+            .unwrap();
+        assert!(entry.is_file);
+
+        let config = bincode::config::standard();
+        let encoded = bincode::encode_to_vec(&entry, config).unwrap();
+        let (decoded, _): (FsEntry, usize) = bincode::decode_from_slice(&encoded, config).unwrap();
+        assert_eq!(decoded.name, entry.name);
+    }
+
+    fn relocated(src_dir: &str, dst_dir: &str, names: &[&str]) -> RelocatedChanges {
+        RelocatedChanges {
+            src_dir: OsString::from(src_dir),
+            dst_dir: OsString::from(dst_dir),
+            entries: names
+                .iter()
+                .map(|name| ChangedFsEntry {
+                    name: OsString::from(name),
+                    is_deleted: false,
+                    is_dir: false,
+                    is_file: true,
+                    is_symlink: false,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_relocated_changes() {
+        let dir = TempDir::new("relocated");
+        let diff = dir.0.join("changes.diff");
+        let relocated = vec![
+            relocated("home", "users", &["u/f"]),
+            relocated("old", "new/dir", &["a", "b"]),
+        ];
+        write_relocated_changes(&relocated, &diff, Compression::Zstd, 0).unwrap();
+        assert_eq!(read_relocated_changes(&diff).unwrap(), relocated);
+        assert!(dir.0.join("changes.diff.2").exists());
+
+        // The changes of an earlier diff with more relocated directories are removed.
+        write_relocated_changes(&relocated[..1], &diff, Compression::None, 0).unwrap();
+        assert_eq!(read_relocated_changes(&diff).unwrap(), relocated[..1]);
+        assert!(!dir.0.join("changes.diff.2").exists());
+        write_relocated_changes(&[], &diff, Compression::None, 0).unwrap();
+        assert!(read_relocated_changes(&diff).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reuses_unchanged_directories() {
+        let dir = TempDir::new("reuse");
+        let full = dir.scan(&[]);
+        assert_eq!(names(&full), ["d", "d/a", "d/b", "e", "e/c"]);
+        assert_eq!(dir.scan(&full), full);
+        // d is not listed again, so an entry missing from the previous state stays missing.
+        assert_eq!(
+            names(&dir.scan(&without(&full, "d/b"))),
+            ["d", "d/a", "e", "e/c"]
+        );
+    }
+
+    #[test]
+    fn stats_files_of_reused_directories() {
+        let dir = TempDir::new("restat");
+        let previous = dir.scan(&[]);
+        // Rewritten in place, which leaves d as it was.
+        fs::write(dir.0.join("d/a"), "longer").unwrap();
+        let entries = dir.scan(&previous);
+        assert_eq!(entries, dir.scan(&[]));
+        assert_eq!(entries[1].size, 6);
+    }
+
+    #[test]
+    fn lists_directories_whose_ctime_changed() {
+        let dir = TempDir::new("ctime");
+        let mut previous = dir.scan(&[]);
+        // An entry added to d whose mtime is set back, as rsync does, only changes its ctime.
+        fs::write(dir.0.join("d/new"), "a").unwrap();
+        dir.set_mtime("d", previous[0].mtime);
+        previous[0].ctime -= 1;
+        assert_eq!(
+            names(&dir.scan(&previous)),
+            ["d", "d/a", "d/b", "d/new", "e", "e/c"]
+        );
+    }
+
+    #[test]
+    fn lists_directories_without_ctimes() {
+        let dir = TempDir::new("unknown");
+        let previous: Vec<FsEntry> = without(&dir.scan(&[]), "d/b")
+            .into_iter()
+            .map(|entry| FsEntry { ctime: 0, ..entry })
+            .collect();
+        assert_eq!(names(&dir.scan(&previous)), ["d", "d/a", "d/b", "e", "e/c"]);
+    }
+}
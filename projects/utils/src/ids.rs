@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::fs::FsEntry;
+
+/// Reads the names and numeric ids listed in a passwd or group file.
+pub fn read_id_names(path: &Path) -> Result<HashMap<String, u32>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read '{}'. Error : {}", path.display(), err))?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse::<u32>().ok()?;
+            Some((String::from(name), id))
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum IdSpec {
+    Id(u32),
+    Name(String),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct IdMapFile {
+    #[serde(default)]
+    users: HashMap<String, IdSpec>,
+    #[serde(default)]
+    groups: HashMap<String, IdSpec>,
+}
+
+/// Names of the users or groups of the source and destination systems, empty when not supplied.
+#[derive(Default)]
+pub struct IdNames {
+    pub src: HashMap<String, u32>,
+    pub dst: HashMap<String, u32>,
+}
+
+impl IdNames {
+    /// Reads the source and destination passwd or group files that were supplied.
+    pub fn read(src: Option<&Path>, dst: Option<&Path>) -> Result<IdNames, String> {
+        Ok(IdNames {
+            src: src.map(read_id_names).transpose()?.unwrap_or_default(),
+            dst: dst.map(read_id_names).transpose()?.unwrap_or_default(),
+        })
+    }
+
+    /// Maps every name present on both systems from its source id to its destination id.
+    fn common_names(&self) -> HashMap<u32, u32> {
+        self.src
+            .iter()
+            .filter_map(|(name, src_id)| self.dst.get(name).map(|dst_id| (*src_id, *dst_id)))
+            .collect()
+    }
+
+    fn resolve(names: &HashMap<String, u32>, spec: &str, side: &str) -> Result<u32, String> {
+        if let Ok(id) = spec.parse::<u32>() {
+            return Ok(id);
+        }
+        names
+            .get(spec)
+            .copied()
+            .ok_or_else(|| format!("Unknown {} name '{}'", side, spec))
+    }
+
+    fn mapping(
+        &self,
+        specs: &HashMap<String, IdSpec>,
+        kind: &str,
+    ) -> Result<HashMap<u32, u32>, String> {
+        let mut mapping = self.common_names();
+        for (src_spec, dst_spec) in specs {
+            let src_id = IdNames::resolve(&self.src, src_spec, &format!("source {}", kind))?;
+            let dst_id = match dst_spec {
+                IdSpec::Id(id) => *id,
+                IdSpec::Name(name) => {
+                    IdNames::resolve(&self.dst, name, &format!("destination {}", kind))?
+                }
+            };
+            mapping.insert(src_id, dst_id);
+        }
+        Ok(mapping)
+    }
+
+    fn name_of(&self, id: u32) -> Option<&str> {
+        self.src
+            .iter()
+            .find(|(_, src_id)| **src_id == id)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Translation of source user and group ids to the ids used for the same users and groups on the destination.
+///
+/// Ids missing from the mapping are kept as is.
+#[derive(Default, Debug)]
+pub struct IdMapping {
+    pub users: HashMap<u32, u32>,
+    pub groups: HashMap<u32, u32>,
+}
+
+impl IdMapping {
+    /// Builds the mapping from users and groups with the same name on both systems, overridden by
+    /// the entries of the TOML mapping file `path` when given.
+    ///
+    /// ```toml
+    /// [users]
+    /// alice = "alice"
+    /// "1000" = 2000
+    ///
+    /// [groups]
+    /// staff = "users"
+    /// ```
+    pub fn read(
+        path: Option<&Path>,
+        users: &IdNames,
+        groups: &IdNames,
+    ) -> Result<IdMapping, String> {
+        let file: IdMapFile = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path).map_err(|err| {
+                    format!("Failed to read '{}'. Error : {}", path.display(), err)
+                })?;
+                toml::from_str(&contents).map_err(|err| {
+                    format!("Failed to parse '{}'. Error : {}", path.display(), err)
+                })?
+            }
+            None => IdMapFile::default(),
+        };
+        Ok(IdMapping {
+            users: users.mapping(&file.users, "user")?,
+            groups: groups.mapping(&file.groups, "group")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    pub fn user(&self, id: u32) -> u32 {
+        self.users.get(&id).copied().unwrap_or(id)
+    }
+
+    pub fn group(&self, id: u32) -> u32 {
+        self.groups.get(&id).copied().unwrap_or(id)
+    }
+
+    /// Replaces the owner and group of source entries with the destination ids.
+    pub fn apply(&self, entries: &mut [FsEntry]) {
+        for entry in entries.iter_mut() {
+            entry.owner = self.user(entry.owner);
+            entry.group = self.group(entry.group);
+        }
+    }
+
+    /// Returns descriptions of the owners and groups of source entries missing from the mapping.
+    pub fn unmapped(&self, entries: &[FsEntry], users: &IdNames, groups: &IdNames) -> Vec<String> {
+        let owners: BTreeSet<u32> = entries
+            .iter()
+            .map(|entry| entry.owner)
+            .filter(|id| !self.users.contains_key(id))
+            .collect();
+        let entry_groups: BTreeSet<u32> = entries
+            .iter()
+            .map(|entry| entry.group)
+            .filter(|id| !self.groups.contains_key(id))
+            .collect();
+        let describe = |kind: &str, id: u32, names: &IdNames| match names.name_of(id) {
+            Some(name) => format!("{} {} ({})", kind, id, name),
+            None => format!("{} {}", kind, id),
+        };
+        owners
+            .into_iter()
+            .map(|id| describe("user", id, users))
+            .chain(
+                entry_groups
+                    .into_iter()
+                    .map(|id| describe("group", id, groups)),
+            )
+            .collect()
+    }
+
+    /// Returns the mapping from destination ids back to source ids.
+    pub fn inverse(&self) -> IdMapping {
+        let invert = |mapping: &HashMap<u32, u32>| {
+            mapping
+                .iter()
+                .map(|(src_id, dst_id)| (*dst_id, *src_id))
+                .collect()
+        };
+        IdMapping {
+            users: invert(&self.users),
+            groups: invert(&self.groups),
+        }
+    }
+
+    /// Returns the rsync `--usermap` and `--groupmap` options applying the mapping.
+    pub fn rsync_args(&self) -> Vec<String> {
+        let option = |name: &str, mapping: &HashMap<u32, u32>| {
+            let mut pairs: Vec<(&u32, &u32)> = mapping.iter().collect();
+            pairs.sort_unstable();
+            let pairs: Vec<String> = pairs
+                .into_iter()
+                .map(|(src_id, dst_id)| format!("{}:{}", src_id, dst_id))
+                .collect();
+            format!("--{}={}", name, pairs.join(","))
+        };
+        let mut args: Vec<String> = Vec::new();
+        if !self.users.is_empty() {
+            args.push(option("usermap", &self.users));
+        }
+        if !self.groups.is_empty() {
+            args.push(option("groupmap", &self.groups));
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::OsString, path::PathBuf};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("fs_tools_ids_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(names: &[(&str, u32)]) -> HashMap<String, u32> {
+        names
+            .iter()
+            .map(|(name, id)| (String::from(*name), *id))
+            .collect()
+    }
+
+    fn users() -> IdNames {
+        IdNames {
+            src: names(&[("root", 0), ("alice", 1000), ("bob", 1001), ("carol", 1002)]),
+            dst: names(&[("root", 0), ("alice", 2000), ("robert", 2001)]),
+        }
+    }
+
+    fn groups() -> IdNames {
+        IdNames {
+            src: names(&[("staff", 50)]),
+            dst: names(&[("users", 100)]),
+        }
+    }
+
+    fn entry(owner: u32, group: u32) -> FsEntry {
+        FsEntry {
+            name: OsString::from("f"),
+            owner,
+            group,
+            mode: 0o100644,
+            mtime: 1,
+            ctime: 0,
+            inode: 1,
+            size: 1,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+        }
+    }
+
+    #[test]
+    fn reads_passwd_files() {
+        let dir = TempDir::new("passwd");
+        let path = dir.write(
+            "passwd",
+            "# comment\nroot:x:0:0:root:/root:/bin/sh\nalice:x:1000:1000::/home/alice:/bin/sh\nbroken\nbad:x:id:1\n",
+        );
+        assert_eq!(
+            read_id_names(&path).unwrap(),
+            names(&[("root", 0), ("alice", 1000)])
+        );
+        assert!(read_id_names(&dir.0.join("missing")).is_err());
+    }
+
+    #[test]
+    fn maps_common_names_and_overrides() {
+        let dir = TempDir::new("map");
+        let path = dir.write(
+            "map.toml",
+            "[users]\nbob = \"robert\"\n\"1002\" = 3000\n\n[groups]\nstaff = \"users\"\n",
+        );
+        let mapping = IdMapping::read(Some(&path), &users(), &groups()).unwrap();
+        assert_eq!(
+            mapping.users,
+            HashMap::from([(0, 0), (1000, 2000), (1001, 2001), (1002, 3000)])
+        );
+        assert_eq!(mapping.groups, HashMap::from([(50, 100)]));
+        assert_eq!(mapping.user(1000), 2000);
+        // Ids missing from the mapping are kept.
+        assert_eq!(mapping.user(5), 5);
+        assert_eq!(mapping.group(51), 51);
+
+        let mapping = IdMapping::read(None, &users(), &IdNames::default()).unwrap();
+        assert_eq!(mapping.users, HashMap::from([(0, 0), (1000, 2000)]));
+        assert!(mapping.groups.is_empty());
+        assert!(
+            IdMapping::read(None, &IdNames::default(), &IdNames::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        let dir = TempDir::new("unknown");
+        for (contents, err) in [
+            ("[users]\ndave = 1\n", "Unknown source user name 'dave'"),
+            (
+                "[users]\nbob = \"bobby\"\n",
+                "Unknown destination user name 'bobby'",
+            ),
+            (
+                "[groups]\nstaff = \"wheel\"\n",
+                "Unknown destination group name 'wheel'",
+            ),
+        ] {
+            let path = dir.write("map.toml", contents);
+            assert_eq!(
+                IdMapping::read(Some(&path), &users(), &groups()).unwrap_err(),
+                err
+            );
+        }
+        let path = dir.write("map.toml", "[owners]\nbob = 1\n");
+        let err = IdMapping::read(Some(&path), &users(), &groups()).unwrap_err();
+        assert!(err.starts_with("Failed to parse"), "{}", err);
+    }
+
+    #[test]
+    fn applies_mappings() {
+        let mapping = IdMapping::read(None, &users(), &IdNames::default()).unwrap();
+        let mut entries = vec![entry(1000, 50), entry(1001, 50), entry(7, 51)];
+        assert_eq!(
+            mapping.unmapped(&entries, &users(), &groups()),
+            ["user 7", "user 1001 (bob)", "group 50 (staff)", "group 51"]
+        );
+        mapping.apply(&mut entries);
+        let ids: Vec<(u32, u32)> = entries
+            .iter()
+            .map(|entry| (entry.owner, entry.group))
+            .collect();
+        assert_eq!(ids, [(2000, 50), (1001, 50), (7, 51)]);
+
+        let inverse = mapping.inverse();
+        assert_eq!(inverse.user(2000), 1000);
+        assert_eq!(inverse.user(1000), 1000);
+    }
+
+    #[test]
+    fn builds_rsync_arguments() {
+        let mapping = IdMapping {
+            users: HashMap::from([(1001, 2001), (1000, 2000)]),
+            groups: HashMap::new(),
+        };
+        assert_eq!(mapping.rsync_args(), ["--usermap=1000:2000,1001:2001"]);
+        let mapping = IdMapping {
+            groups: HashMap::from([(50, 100)]),
+            ..mapping
+        };
+        assert_eq!(
+            mapping.rsync_args(),
+            ["--usermap=1000:2000,1001:2001", "--groupmap=50:100"]
+        );
+        assert!(IdMapping::default().rsync_args().is_empty());
+    }
+}
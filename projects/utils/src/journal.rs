@@ -0,0 +1,488 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bincode::{error::DecodeError, Decode, Encode};
+
+use crate::fs::{names_below, ChangedFsEntry};
+
+pub const JOURNAL_MAGIC: [u8; 4] = *b"FSJL";
+pub const JOURNAL_VERSION: u8 = 2;
+
+/// Length of the header of a journal file: the magic, the version and the first sequence number.
+const HEADER_LENGTH: u64 = 13;
+
+/// A change recorded in a journal, in the order the changes were seen.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct JournalRecord {
+    pub sequence: u64,
+    /// Unix time in seconds at which the change was recorded.
+    pub timestamp: i64,
+    pub entry: ChangedFsEntry,
+}
+
+fn read_error(path: &Path) -> impl Fn(std::io::Error) -> String + '_ {
+    move |err| format!("Failed to read '{}'. Error : {}", path.display(), err)
+}
+
+fn write_error(path: &Path) -> impl Fn(std::io::Error) -> String + '_ {
+    move |err| format!("Failed to write '{}'. Error : {}", path.display(), err)
+}
+
+/// Header of a journal file. The first sequence number is stored with a fixed length, so the
+/// header can be rewritten in place when the journal is truncated.
+fn encode_header(first_sequence: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+    header.extend_from_slice(&JOURNAL_MAGIC);
+    header.push(JOURNAL_VERSION);
+    header.extend_from_slice(&first_sequence.to_le_bytes());
+    header
+}
+
+/// Counts the bytes read, to know where the last complete record ends.
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads the records of a journal file one at a time. The file is locked for reading until the
+/// reader is dropped, so it is not truncated meanwhile.
+pub struct JournalReader {
+    path: PathBuf,
+    reader: Counted<BufReader<File>>,
+    first_sequence: u64,
+    /// Length of the header and of the complete records read.
+    length: u64,
+    done: bool,
+}
+
+impl JournalReader {
+    pub fn open(path: &Path) -> Result<JournalReader, String> {
+        let file = File::open(path)
+            .map_err(|err| format!("Failed to open '{}'. Error : {}", path.display(), err))?;
+        file.lock_shared().map_err(read_error(path))?;
+        JournalReader::new(file, path)
+    }
+
+    fn new(file: File, path: &Path) -> Result<JournalReader, String> {
+        let mut reader = Counted {
+            inner: BufReader::new(file),
+            count: 0,
+        };
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        reader.read_exact(&mut header).map_err(read_error(path))?;
+        if header[..4] != JOURNAL_MAGIC {
+            return Err(format!("'{}' is not a journal file", path.display()));
+        }
+        if header[4] != JOURNAL_VERSION {
+            return Err(format!(
+                "Unsupported journal version {} in '{}'",
+                header[4],
+                path.display()
+            ));
+        }
+        let mut first_sequence = [0u8; 8];
+        first_sequence.copy_from_slice(&header[5..]);
+        Ok(JournalReader {
+            path: path.to_path_buf(),
+            reader,
+            first_sequence: u64::from_le_bytes(first_sequence),
+            length: HEADER_LENGTH,
+            done: false,
+        })
+    }
+
+    /// Sequence number of the first record, or of the next one appended when there is none.
+    pub fn first_sequence(&self) -> u64 {
+        self.first_sequence
+    }
+
+    /// Length of the header and of the complete records read so far.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+impl Iterator for JournalReader {
+    type Item = Result<JournalRecord, String>;
+
+    /// Returns the next record. A record cut short by a writer still appending to the journal
+    /// ends the records.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match bincode::decode_from_std_read::<JournalRecord, _, _>(
+            &mut self.reader,
+            bincode::config::standard(),
+        ) {
+            Ok(record) => {
+                self.length = self.reader.count;
+                Some(Ok(record))
+            }
+            Err(DecodeError::UnexpectedEnd { .. }) => {
+                self.done = true;
+                None
+            }
+            Err(DecodeError::Io { inner, .. }) if inner.kind() == ErrorKind::UnexpectedEof => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(format!(
+                    "Failed to decode '{}'. Error : {}",
+                    self.path.display(),
+                    err
+                )))
+            }
+        }
+    }
+}
+
+/// Appends records to a journal file, continuing the sequence numbers of the records it holds.
+///
+/// Appended records are buffered and written at once when flushed, with the file locked so they
+/// never interleave with a truncation.
+pub struct JournalWriter {
+    path: PathBuf,
+    file: File,
+    buffer: Vec<u8>,
+    next_sequence: u64,
+}
+
+impl JournalWriter {
+    pub fn open(path: &Path) -> Result<JournalWriter, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Failed to open '{}'. Error : {}", path.display(), err))?;
+        file.lock().map_err(write_error(path))?;
+        let length = file.metadata().map_err(read_error(path))?.len();
+        let next_sequence = if length == 0 {
+            (&file)
+                .write_all(&encode_header(0))
+                .map_err(write_error(path))?;
+            0
+        } else {
+            let mut reader = JournalReader::new(file.try_clone().map_err(read_error(path))?, path)?;
+            let mut next_sequence = reader.first_sequence();
+            for record in reader.by_ref() {
+                next_sequence = record?.sequence + 1;
+            }
+            // Drops a record cut short when the previous writer stopped.
+            file.set_len(reader.length()).map_err(write_error(path))?;
+            next_sequence
+        };
+        file.unlock().map_err(write_error(path))?;
+        Ok(JournalWriter {
+            path: path.to_path_buf(),
+            file,
+            buffer: Vec::new(),
+            next_sequence,
+        })
+    }
+
+    /// Sequence number of the next record appended.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub fn append(&mut self, entry: ChangedFsEntry) -> Result<(), String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        let record = JournalRecord {
+            sequence: self.next_sequence,
+            timestamp,
+            entry,
+        };
+        bincode::encode_into_std_write(&record, &mut self.buffer, bincode::config::standard())
+            .map_err(|err| format!("Failed to write '{}'. Error : {}", self.path.display(), err))?;
+        self.next_sequence += 1;
+        Ok(())
+    }
+
+    /// Writes the appended records to the journal file, making them visible to readers.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.file.lock().map_err(write_error(&self.path))?;
+        let written = (&self.file).write_all(&self.buffer);
+        let unlocked = self.file.unlock();
+        written.and(unlocked).map_err(write_error(&self.path))?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// Removes the records numbered below `sequence` from a journal file, such as the ones already
+/// synced, returning the number of records removed. The file is compacted in place, so a writer
+/// can keep appending to it meanwhile.
+pub fn truncate_journal(path: &Path, sequence: u64) -> Result<usize, String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|err| format!("Failed to open '{}'. Error : {}", path.display(), err))?;
+    file.lock().map_err(write_error(path))?;
+    let mut reader = JournalReader::new(file.try_clone().map_err(read_error(path))?, path)?;
+    let mut removed = 0;
+    let mut kept_start = None;
+    let mut next_sequence = reader.first_sequence();
+    loop {
+        let start = reader.length();
+        let Some(record) = reader.next() else {
+            break;
+        };
+        let record = record?;
+        if kept_start.is_none() && record.sequence >= sequence {
+            kept_start = Some(start);
+        }
+        if kept_start.is_none() {
+            removed += 1;
+        }
+        next_sequence = record.sequence + 1;
+    }
+    let end = reader.length();
+    let kept_start = kept_start.unwrap_or(end);
+    // A record cut short by a stopped writer is dropped along with the removed records.
+    let first_sequence = sequence.clamp(reader.first_sequence(), next_sequence);
+    drop(reader);
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut from = kept_start;
+    let mut to = HEADER_LENGTH;
+    while from < end {
+        let length = buffer.len().min((end - from) as usize);
+        file.read_exact_at(&mut buffer[..length], from)
+            .map_err(read_error(path))?;
+        file.write_all_at(&buffer[..length], to)
+            .map_err(write_error(path))?;
+        from += length as u64;
+        to += length as u64;
+    }
+    file.write_all_at(&encode_header(first_sequence), 0)
+        .and_then(|_| file.set_len(to))
+        .and_then(|_| file.sync_all())
+        .map_err(write_error(path))?;
+    file.unlock().map_err(write_error(path))?;
+    Ok(removed)
+}
+
+/// Changes folded from journal records, deletions first. An entry deleted and then created again
+/// is listed both as deleted and as changed, so it is removed before being synced and nothing left
+/// from its previous contents remains.
+#[derive(Default)]
+pub struct Coalesced {
+    deleted: BTreeMap<OsString, ChangedFsEntry>,
+    changed: BTreeMap<OsString, ChangedFsEntry>,
+}
+
+impl Coalesced {
+    pub fn add(&mut self, entry: ChangedFsEntry) {
+        if entry.is_deleted {
+            let below: Vec<OsString> = self
+                .changed
+                .range(names_below(&entry.name))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in below {
+                self.changed.remove(&name);
+            }
+            self.changed.remove(&entry.name);
+            self.deleted.insert(entry.name.clone(), entry);
+        } else {
+            self.changed.insert(entry.name.clone(), entry);
+        }
+    }
+
+    pub fn into_changes(self) -> Vec<ChangedFsEntry> {
+        self.deleted
+            .into_values()
+            .chain(self.changed.into_values())
+            .collect()
+    }
+}
+
+/// Folds journal records into the changes to apply, see [`Coalesced`].
+pub fn coalesce(records: &[JournalRecord]) -> Vec<ChangedFsEntry> {
+    let mut coalesced = Coalesced::default();
+    for record in records {
+        coalesced.add(record.entry.clone());
+    }
+    coalesced.into_changes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn change(name: &str, is_deleted: bool, is_dir: bool) -> ChangedFsEntry {
+        ChangedFsEntry {
+            name: OsString::from(name),
+            is_deleted,
+            is_dir,
+            is_file: !is_dir,
+            is_symlink: false,
+        }
+    }
+
+    fn records(changes: &[ChangedFsEntry]) -> Vec<JournalRecord> {
+        changes
+            .iter()
+            .enumerate()
+            .map(|(sequence, entry)| JournalRecord {
+                sequence: sequence as u64,
+                timestamp: 0,
+                entry: entry.clone(),
+            })
+            .collect()
+    }
+
+    fn names(changes: &[ChangedFsEntry]) -> Vec<(&str, bool)> {
+        changes
+            .iter()
+            .map(|change| (change.name.to_str().unwrap(), change.is_deleted))
+            .collect()
+    }
+
+    /// Journal file removed when dropped.
+    struct TempJournal(PathBuf);
+
+    impl TempJournal {
+        fn new(name: &str) -> TempJournal {
+            let path = std::env::temp_dir().join(format!(
+                "fs_tools_journal_{}_{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_file(&path);
+            TempJournal(path)
+        }
+
+        fn sequences(&self) -> Vec<u64> {
+            JournalReader::open(&self.0)
+                .unwrap()
+                .map(|record| record.unwrap().sequence)
+                .collect()
+        }
+    }
+
+    impl Drop for TempJournal {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn coalesces_repeated_changes() {
+        let changes = coalesce(&records(&[
+            change("a", false, false),
+            change("b", false, false),
+            change("a", false, false),
+        ]));
+        assert_eq!(names(&changes), [("a", false), ("b", false)]);
+    }
+
+    #[test]
+    fn coalesces_deletions_below_directories() {
+        let changes = coalesce(&records(&[
+            change("dir", false, true),
+            change("dir/a", false, false),
+            change("dir/sub/b", false, false),
+            change("dir0", false, false),
+            change("dir", true, true),
+        ]));
+        assert_eq!(names(&changes), [("dir", true), ("dir0", false)]);
+    }
+
+    #[test]
+    fn coalesces_recreated_entries() {
+        let changes = coalesce(&records(&[
+            change("a", false, false),
+            change("a", true, false),
+            change("a", false, true),
+            change("a/b", false, false),
+        ]));
+        assert_eq!(names(&changes), [("a", true), ("a", false), ("a/b", false)]);
+    }
+
+    #[test]
+    fn writes_and_reads_records() {
+        let journal = TempJournal::new("round_trip");
+        let mut writer = JournalWriter::open(&journal.0).unwrap();
+        for name in ["a", "b", "c"] {
+            writer.append(change(name, false, false)).unwrap();
+        }
+        assert!(
+            journal.sequences().is_empty(),
+            "records are only written when flushed"
+        );
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(journal.sequences(), [0, 1, 2]);
+
+        // A record cut short is dropped, and the sequence continues after the last complete one.
+        let length = fs::metadata(&journal.0).unwrap().len();
+        fs::File::options()
+            .write(true)
+            .open(&journal.0)
+            .unwrap()
+            .set_len(length - 1)
+            .unwrap();
+        assert_eq!(journal.sequences(), [0, 1]);
+        let mut writer = JournalWriter::open(&journal.0).unwrap();
+        assert_eq!(writer.next_sequence(), 2);
+        writer.append(change("d", false, false)).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(journal.sequences(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn truncates_below_sequence() {
+        let journal = TempJournal::new("truncate");
+        let mut writer = JournalWriter::open(&journal.0).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            writer.append(change(name, false, false)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(truncate_journal(&journal.0, 2).unwrap(), 2);
+        assert_eq!(journal.sequences(), [2, 3]);
+        // The writer keeps appending after the truncation.
+        writer.append(change("e", false, false)).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(journal.sequences(), [2, 3, 4]);
+        let reader = JournalReader::open(&journal.0).unwrap();
+        let names: Vec<OsString> = reader.map(|record| record.unwrap().entry.name).collect();
+        assert_eq!(names, ["c", "d", "e"]);
+
+        // Sequences keep increasing once every record is removed.
+        assert_eq!(truncate_journal(&journal.0, 10).unwrap(), 3);
+        assert!(journal.sequences().is_empty());
+        drop(writer);
+        let writer = JournalWriter::open(&journal.0).unwrap();
+        assert_eq!(writer.next_sequence(), 5);
+    }
+}
@@ -1,2 +1,10 @@
 pub mod arg_parsers;
+pub mod config;
+pub mod encoding;
 pub mod fs;
+pub mod ids;
+pub mod journal;
+pub mod logging;
+pub mod metrics;
+pub mod state;
+pub mod throttle;
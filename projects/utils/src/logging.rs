@@ -0,0 +1,136 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use clap::{ArgAction, ValueEnum};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, Layer, Registry};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the event and of its spans.
+    Json,
+}
+
+/// Logging options shared by all the tools.
+#[derive(clap::Args, Debug)]
+pub struct LogArgs {
+    #[arg(
+        id = "verbose",
+        long = "verbose",
+        short = 'v',
+        action = ArgAction::Count,
+        conflicts_with = "quiet",
+        help = "",
+        long_help = "Log more details, debug messages when given once and trace messages when given twice"
+    )]
+    pub verbose: u8,
+    #[arg(
+        id = "quiet",
+        long = "quiet",
+        short = 'q',
+        action = ArgAction::Count,
+        help = "",
+        long_help = "Log less, warnings and errors only when given once and errors only when given twice"
+    )]
+    pub quiet: u8,
+    #[arg(
+        id = "log format",
+        long = "log-format",
+        value_enum,
+        default_value_t = LogFormat::Text,
+        help = "",
+        long_help = "Format of the log messages written to stderr"
+    )]
+    pub log_format: LogFormat,
+}
+
+impl LogArgs {
+    pub fn level(&self) -> LevelFilter {
+        match (self.verbose, self.quiet) {
+            (0, 0) => LevelFilter::INFO,
+            (1, _) => LevelFilter::DEBUG,
+            (_, 0) => LevelFilter::TRACE,
+            (_, 1) => LevelFilter::WARN,
+            _ => LevelFilter::ERROR,
+        }
+    }
+}
+
+/// A log file opened once the directory it is written to exists, appended to when it already
+/// exists. Messages logged before are not written to it.
+#[derive(Clone, Default)]
+pub struct LogFile(Arc<Mutex<Option<File>>>);
+
+impl LogFile {
+    pub fn open(&self, path: &Path) -> Result<(), String> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Failed to open '{}'. Error : {}", path.display(), err))?;
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = Some(file);
+        Ok(())
+    }
+}
+
+pub struct LogFileWriter<'a>(MutexGuard<'a, Option<File>>);
+
+impl Write for LogFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.as_mut() {
+            Some(file) => file.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogFileWriter(self.0.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+}
+
+fn layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_target(false)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    }
+}
+
+/// Sets up logging to stderr, and to `log_file` once it is opened.
+pub fn init(args: &LogArgs, log_file: Option<LogFile>) {
+    let mut layers = vec![layer(
+        args.log_format,
+        io::stderr,
+        io::IsTerminal::is_terminal(&io::stderr()),
+    )];
+    if let Some(log_file) = log_file {
+        layers.push(layer(args.log_format, log_file, false));
+    }
+    let subscriber = Registry::default().with(layers).with(args.level());
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        eprintln!("Logging is already set up");
+    }
+}
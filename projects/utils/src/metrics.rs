@@ -0,0 +1,328 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::arg_parsers::check_if_parent_path_exists;
+
+/// Longest time a metrics client can take to send its request or read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most metrics clients served at once, so clients that never finish their requests cannot start
+/// threads without bound.
+const MAX_CLIENTS: usize = 4;
+
+/// Metrics options shared by the tools.
+#[derive(clap::Args, Debug)]
+pub struct MetricsArgs {
+    #[arg(
+        id = "metrics file",
+        long = "metrics-file",
+        value_parser = check_if_parent_path_exists(),
+        help = "",
+        long_help = "Path to write the metrics of the run to in the Prometheus text format, for the textfile collector of node_exporter. The file is replaced atomically, so it should end with .prom in the collector directory"
+    )]
+    pub metrics_file: Option<PathBuf>,
+}
+
+/// Seconds since the epoch, for the timestamp metrics.
+pub fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MetricType {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    help: String,
+    metric_type: MetricType,
+    /// Values by rendered labels.
+    samples: BTreeMap<String, f64>,
+}
+
+/// Metrics of a tool, named `<namespace>_<name>`, shared between threads.
+#[derive(Clone)]
+pub struct Metrics {
+    namespace: String,
+    families: Arc<Mutex<BTreeMap<String, Family>>>,
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+impl Metrics {
+    pub fn new(namespace: &str) -> Self {
+        Metrics {
+            namespace: namespace.to_string(),
+            families: Arc::default(),
+        }
+    }
+
+    fn update(
+        &self,
+        metric_type: MetricType,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        update: impl FnOnce(&mut f64),
+    ) {
+        let mut families = self.families.lock().unwrap_or_else(|err| err.into_inner());
+        let family = families
+            .entry(format!("{}_{}", self.namespace, name))
+            .or_insert_with(|| Family {
+                help: help.to_string(),
+                metric_type,
+                samples: BTreeMap::new(),
+            });
+        update(family.samples.entry(render_labels(labels)).or_default());
+    }
+
+    /// Sets the gauge `name` to `value`.
+    pub fn set(&self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(MetricType::Gauge, name, help, labels, |sample| {
+            *sample = value
+        });
+    }
+
+    /// Adds `value` to the gauge `name`, for totals of a single run.
+    pub fn add(&self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(MetricType::Gauge, name, help, labels, |sample| {
+            *sample += value
+        });
+    }
+
+    /// Adds `value` to the counter `name`, for totals of a long-running process. `name` should
+    /// end with `_total`.
+    pub fn increment(&self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(MetricType::Counter, name, help, labels, |sample| {
+            *sample += value
+        });
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap_or_else(|err| err.into_inner());
+        let mut text = String::new();
+        for (name, family) in families.iter() {
+            let metric_type = match family.metric_type {
+                MetricType::Counter => "counter",
+                MetricType::Gauge => "gauge",
+            };
+            let _ = writeln!(text, "# HELP {} {}", name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", name, metric_type);
+            for (labels, value) in family.samples.iter() {
+                let _ = writeln!(text, "{}{} {}", name, labels, value);
+            }
+        }
+        text
+    }
+
+    /// Writes the metrics to `path` through a temporary file, so the textfile collector never
+    /// reads a partially written file.
+    pub fn write_to_file(&self, path: &Path) -> Result<(), String> {
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, self.render())
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|err| {
+                format!(
+                    "Failed to write metrics to '{}'. Error : {}",
+                    path.display(),
+                    err
+                )
+            })
+    }
+
+    fn respond(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Skips the headers, the requests served have no body.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let mut stream = reader.into_inner();
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            _ => ("404 Not Found", String::from("Not found\n")),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    /// Serves the metrics at `http://<address>/metrics` from a background thread, returning the
+    /// address listened on.
+    pub fn serve(&self, address: &str) -> Result<SocketAddr, String> {
+        let listener = TcpListener::bind(address).map_err(|err| {
+            format!(
+                "Failed to listen on '{}' for metrics. Error : {}",
+                address, err
+            )
+        })?;
+        let local_address = listener.local_addr().map_err(|err| {
+            format!(
+                "Failed to get the address listened on for metrics '{}'. Error : {}",
+                address, err
+            )
+        })?;
+        let metrics = self.clone();
+        thread::spawn(move || {
+            let clients = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming().flatten() {
+                // Only this thread adds clients, so the count cannot go past the limit. The
+                // clients beyond it are disconnected by dropping their stream.
+                if clients.load(Ordering::SeqCst) >= MAX_CLIENTS {
+                    continue;
+                }
+                clients.fetch_add(1, Ordering::SeqCst);
+                // Each client is served from its own thread, so a slow one does not hold back
+                // the others, and is dropped once it stops reading or writing for a while.
+                let metrics = metrics.clone();
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    // A client going away mid-request only affects its own response.
+                    let _ = stream
+                        .set_read_timeout(Some(CLIENT_TIMEOUT))
+                        .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+                        .and_then(|_| metrics.respond(stream));
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Ok(local_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::Shutdown};
+
+    fn metrics() -> Metrics {
+        let metrics = Metrics::new("tool");
+        metrics.set("entries", "Entries scanned", &[], 3.0);
+        metrics.add("bytes", "Bytes read", &[("side", "source")], 10.0);
+        metrics.add("bytes", "Bytes read", &[("side", "source")], 5.0);
+        metrics.add("bytes", "Bytes read", &[("side", "destination")], 1.5);
+        metrics.increment("errors_total", "Errors", &[("kind", "io")], 1.0);
+        metrics.increment("errors_total", "Errors", &[("kind", "io")], 1.0);
+        metrics
+    }
+
+    /// Sends `request` to `address` and returns the response.
+    fn get(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn renders_metrics() {
+        assert_eq!(
+            metrics().render(),
+            "# HELP tool_bytes Bytes read\n\
+             # TYPE tool_bytes gauge\n\
+             tool_bytes{side=\"destination\"} 1.5\n\
+             tool_bytes{side=\"source\"} 15\n\
+             # HELP tool_entries Entries scanned\n\
+             # TYPE tool_entries gauge\n\
+             tool_entries 3\n\
+             # HELP tool_errors_total Errors\n\
+             # TYPE tool_errors_total counter\n\
+             tool_errors_total{kind=\"io\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(
+            render_labels(&[("path", "a\\b \"c\"\nd"), ("side", "source")]),
+            "{path=\"a\\\\b \\\"c\\\"\\nd\",side=\"source\"}"
+        );
+        assert_eq!(render_labels(&[]), "");
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let metrics = metrics();
+        let address = metrics.serve("127.0.0.1:0").unwrap();
+
+        let response = get(address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(body, metrics.render());
+
+        let response = get(address, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("\r\n\r\nNot found\n"));
+    }
+
+    #[test]
+    fn limits_the_clients_served_at_once() {
+        let address = metrics().serve("127.0.0.1:0").unwrap();
+        // Clients that never send their request hold the slots.
+        let idle: Vec<TcpStream> = (0..MAX_CLIENTS)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        let _ = stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n");
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert_eq!(response, "");
+
+        // Once one leaves, its slot is taken by the next client.
+        idle[0].shutdown(Shutdown::Both).unwrap();
+        let served = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            let mut stream = TcpStream::connect(address).unwrap();
+            let _ = stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n");
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response.starts_with("HTTP/1.1 200 OK")
+        });
+        assert!(served);
+    }
+}
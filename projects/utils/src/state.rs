@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::{BufRead, BufReader, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use bincode::{Decode, Encode};
+
+use crate::{
+    encoding::{
+        decompressing_reader, open_file, read_magic, write_to_file, write_to_writer, Compression,
+    },
+    fs::{split_name, FsEntries, FsEntry},
+};
+
+/// Leading bytes of a state file in the [`FsState`] format. Files without them hold the plain
+/// entries written before it.
+pub const STATE_MAGIC: [u8; 4] = *b"FSST";
+/// Version 3 stores the ctime of the entries after the state, version 2 states are read without
+/// them.
+pub const STATE_VERSION: u8 = 3;
+const STATE_VERSION_WITHOUT_CTIMES: u8 = 2;
+
+/// Parent index of the entries at the root of a state.
+pub const ROOT: u32 = u32::MAX;
+
+/// File system state storing the file name of each entry and the index of its parent
+/// directory instead of its full path, so directory prefixes are stored only once.
+#[derive(Encode, Decode, PartialEq, Debug, Default)]
+pub struct FsState {
+    /// Index in `entries` of the parent directory of each entry, [`ROOT`] for entries at the root.
+    pub parents: Vec<u32>,
+    /// Entries whose `name` only holds the file name.
+    pub entries: Vec<FsEntry>,
+}
+
+/// Entry of the state files written before the [`FsState`] format, named by its full path.
+#[derive(Decode)]
+struct LegacyFsEntry {
+    name: String,
+    owner: u32,
+    group: u32,
+    mode: u32,
+    mtime: i64,
+    inode: u64,
+    size: u64,
+    is_dir: bool,
+    is_file: bool,
+    is_symlink: bool,
+}
+
+impl From<LegacyFsEntry> for FsEntry {
+    fn from(entry: LegacyFsEntry) -> FsEntry {
+        FsEntry {
+            name: OsString::from(entry.name),
+            owner: entry.owner,
+            group: entry.group,
+            mode: entry.mode,
+            mtime: entry.mtime,
+            ctime: 0,
+            inode: entry.inode,
+            size: entry.size,
+            is_dir: entry.is_dir,
+            is_file: entry.is_file,
+            is_symlink: entry.is_symlink,
+        }
+    }
+}
+
+#[derive(Decode)]
+struct LegacyFsEntries {
+    entries: Vec<LegacyFsEntry>,
+}
+
+/// Children of every directory of a [`FsState`].
+pub struct ChildIndex {
+    offsets: Vec<usize>,
+    children: Vec<u32>,
+}
+
+impl ChildIndex {
+    /// Returns the indexes of the entries in the directory `parent`, [`ROOT`] for the root.
+    pub fn children(&self, parent: u32) -> &[u32] {
+        let slot = if parent == ROOT {
+            self.offsets.len() - 2
+        } else {
+            parent as usize
+        };
+        &self.children[self.offsets[slot]..self.offsets[slot + 1]]
+    }
+}
+
+impl FsState {
+    /// Builds a state from entries named by their full path relative to the root.
+    pub fn from_entries(entries: Vec<FsEntry>) -> FsState {
+        let dirs: HashMap<&OsStr, u32> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_dir)
+            .map(|(index, entry)| (entry.name.as_os_str(), index as u32))
+            .collect();
+        let mut parents: Vec<u32> = Vec::with_capacity(entries.len());
+        let mut name_starts: Vec<usize> = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            // Entries whose parent directory is missing keep their full name at the root.
+            let (parent_name, file_name) = split_name(&entry.name);
+            match dirs.get(parent_name) {
+                Some(parent) if !parent_name.is_empty() => {
+                    parents.push(*parent);
+                    name_starts.push(entry.name.len() - file_name.len());
+                }
+                _ => {
+                    parents.push(ROOT);
+                    name_starts.push(0);
+                }
+            }
+        }
+        drop(dirs);
+        let entries = entries
+            .into_iter()
+            .zip(name_starts)
+            .map(|(mut entry, name_start)| {
+                if name_start > 0 {
+                    let mut name = entry.name.into_vec();
+                    entry.name = OsString::from_vec(name.split_off(name_start));
+                }
+                entry
+            })
+            .collect();
+        FsState { parents, entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the full path of an entry relative to the root.
+    pub fn path(&self, index: u32) -> OsString {
+        self.path_below(index, ROOT)
+    }
+
+    /// Returns the path of an entry relative to the directory `ancestor`, [`ROOT`] for the root.
+    pub fn path_below(&self, index: u32, ancestor: u32) -> OsString {
+        let mut components: Vec<&[u8]> = Vec::new();
+        let mut current = index;
+        while current != ancestor && current != ROOT && components.len() <= self.entries.len() {
+            components.push(self.entries[current as usize].name.as_bytes());
+            current = self.parents[current as usize];
+        }
+        components.reverse();
+        OsString::from_vec(components.join(&b'/'))
+    }
+
+    /// Returns a copy of an entry named by its full path.
+    pub fn entry(&self, index: u32) -> FsEntry {
+        FsEntry {
+            name: self.path(index),
+            ..self.entries[index as usize].clone()
+        }
+    }
+
+    pub fn child_index(&self) -> ChildIndex {
+        let slots = self.entries.len() + 1;
+        let slot_of = |parent: u32| {
+            if parent == ROOT {
+                slots - 1
+            } else {
+                parent as usize
+            }
+        };
+        let mut offsets: Vec<usize> = vec![0; slots + 1];
+        for parent in self.parents.iter() {
+            offsets[slot_of(*parent) + 1] += 1;
+        }
+        for slot in 1..offsets.len() {
+            offsets[slot] += offsets[slot - 1];
+        }
+        let mut next = offsets.clone();
+        let mut children: Vec<u32> = vec![0; self.entries.len()];
+        for (index, parent) in self.parents.iter().enumerate() {
+            let slot = slot_of(*parent);
+            children[next[slot]] = index as u32;
+            next[slot] += 1;
+        }
+        ChildIndex { offsets, children }
+    }
+
+    /// Converts the state into entries named by their full path.
+    pub fn into_fs_entries(self) -> FsEntries {
+        let paths: Vec<OsString> = (0..self.entries.len() as u32)
+            .map(|index| self.path(index))
+            .collect();
+        FsEntries {
+            entries: self
+                .entries
+                .into_iter()
+                .zip(paths)
+                .map(|(entry, name)| FsEntry { name, ..entry })
+                .collect(),
+        }
+    }
+}
+
+/// Decodes a state read from `reader`, named `name` in errors.
+fn decode_state<R: BufRead>(reader: R, name: &str) -> Result<FsState, String> {
+    let (header, mut reader) =
+        read_magic(reader).map_err(|err| format!("Failed to read '{}'. Error : {}", name, err))?;
+    let decode_error =
+        |err: bincode::error::DecodeError| format!("Failed to decode '{}'. Error : {}", name, err);
+    if header.starts_with(&STATE_MAGIC) {
+        let (_, version): ([u8; 4], u8) =
+            bincode::decode_from_std_read(&mut reader, bincode::config::standard())
+                .map_err(decode_error)?;
+        if version != STATE_VERSION && version != STATE_VERSION_WITHOUT_CTIMES {
+            return Err(format!(
+                "Unsupported state version {} in '{}'",
+                version, name
+            ));
+        }
+        let mut state: FsState =
+            bincode::decode_from_std_read(&mut reader, bincode::config::standard())
+                .map_err(decode_error)?;
+        if version == STATE_VERSION {
+            let ctimes: Vec<i64> =
+                bincode::decode_from_std_read(&mut reader, bincode::config::standard())
+                    .map_err(decode_error)?;
+            if ctimes.len() != state.entries.len() {
+                return Err(format!(
+                    "Failed to decode '{}'. Error : {} ctimes for {} entries",
+                    name,
+                    ctimes.len(),
+                    state.entries.len()
+                ));
+            }
+            for (entry, ctime) in state.entries.iter_mut().zip(ctimes) {
+                entry.ctime = ctime;
+            }
+        }
+        Ok(state)
+    } else {
+        let legacy: LegacyFsEntries =
+            bincode::decode_from_std_read(&mut reader, bincode::config::standard())
+                .map_err(decode_error)?;
+        Ok(FsState::from_entries(
+            legacy.entries.into_iter().map(FsEntry::from).collect(),
+        ))
+    }
+}
+
+/// Reads a state file, accepting both the [`FsState`] format and the legacy plain entries.
+pub fn read_state(path: &Path) -> Result<FsState, String> {
+    decode_state(open_file(path)?, &path.display().to_string())
+}
+
+/// Reads a state from the standard output of `command`, run by `sh`, such as
+/// `ssh host fs_state_gen --stdout -s /data`. Its standard error is passed through.
+pub fn read_state_from_command(command: &str) -> Result<FsState, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Failed to run '{}'. Error : {}", command, err))?;
+    let state = match child.stdout.take() {
+        Some(stdout) => decompressing_reader(BufReader::new(stdout))
+            .map_err(|err| format!("Failed to read '{}'. Error : {}", command, err))
+            .and_then(|reader| decode_state(BufReader::new(reader), command)),
+        None => Err(format!("Failed to read '{}'", command)),
+    };
+    // The output is closed first, so a command still writing after a decoding error exits.
+    let status = child
+        .wait()
+        .map_err(|err| format!("Failed to wait for '{}'. Error : {}", command, err))?;
+    if !status.success() {
+        return Err(format!("'{}' failed with {}", command, status));
+    }
+    state
+}
+
+fn ctimes(state: &FsState) -> Vec<i64> {
+    state.entries.iter().map(|entry| entry.ctime).collect()
+}
+
+/// Writes a state to `writer`, such as the standard output.
+pub fn write_state_to_writer<W: Write>(
+    state: &FsState,
+    writer: W,
+    compression: Compression,
+    level: i32,
+) -> std::io::Result<()> {
+    write_to_writer(
+        &(STATE_MAGIC, STATE_VERSION, state, ctimes(state)),
+        writer,
+        compression,
+        level,
+    )
+}
+
+pub fn write_state(
+    state: &FsState,
+    path: &Path,
+    compression: Compression,
+    level: i32,
+) -> Result<(), String> {
+    write_to_file(
+        &(STATE_MAGIC, STATE_VERSION, state, ctimes(state)),
+        path,
+        compression,
+        level,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State of a directory `d` holding a 3 bytes file `f`, written by `fs_state_gen` before the
+    /// [`FsState`] format.
+    const LEGACY_STATE: [u8; 47] = [
+        0x02, 0x01, 0x64, 0x00, 0x00, 0xfb, 0xed, 0x41, 0xfc, 0x38, 0x18, 0xab, 0xd5, 0xfc, 0x03,
+        0x00, 0x14, 0x00, 0xfb, 0x00, 0x10, 0x01, 0x00, 0x00, 0x03, 0x64, 0x2f, 0x66, 0x00, 0x00,
+        0xfb, 0xa4, 0x81, 0xfc, 0x38, 0x18, 0xab, 0xd5, 0xfc, 0x04, 0x00, 0x14, 0x00, 0x03, 0x00,
+        0x01, 0x00,
+    ];
+
+    #[test]
+    fn decodes_legacy_state() {
+        let state = decode_state(&LEGACY_STATE[..], "fixture").unwrap();
+        assert_eq!(state.parents, vec![ROOT, 0]);
+        let entries = state.into_fs_entries().entries;
+        assert_eq!(
+            entries,
+            vec![
+                FsEntry {
+                    name: OsString::from("d"),
+                    owner: 0,
+                    group: 0,
+                    mode: 0o40755,
+                    mtime: 1792379932,
+                    ctime: 0,
+                    inode: 1310723,
+                    size: 4096,
+                    is_dir: true,
+                    is_file: false,
+                    is_symlink: false,
+                },
+                FsEntry {
+                    name: OsString::from("d/f"),
+                    owner: 0,
+                    group: 0,
+                    mode: 0o100644,
+                    mtime: 1792379932,
+                    ctime: 0,
+                    inode: 1310724,
+                    size: 3,
+                    is_dir: false,
+                    is_file: true,
+                    is_symlink: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_state() {
+        let state = FsState::from_entries(
+            decode_state(&LEGACY_STATE[..], "fixture")
+                .unwrap()
+                .into_fs_entries()
+                .entries,
+        );
+        let mut encoded = Vec::new();
+        write_state_to_writer(&state, &mut encoded, Compression::None, 0).unwrap();
+        assert!(encoded.starts_with(&STATE_MAGIC));
+        assert_eq!(decode_state(&encoded[..], "encoded").unwrap(), state);
+    }
+
+    #[test]
+    fn round_trips_ctimes() {
+        let mut state = decode_state(&LEGACY_STATE[..], "fixture").unwrap();
+        assert!(state.entries.iter().all(|entry| entry.ctime == 0));
+        state.entries[0].ctime = 1792379940;
+        state.entries[1].ctime = 1792379950;
+        let mut encoded = Vec::new();
+        write_state_to_writer(&state, &mut encoded, Compression::None, 0).unwrap();
+        assert_eq!(decode_state(&encoded[..], "encoded").unwrap(), state);
+    }
+
+    #[test]
+    fn decodes_states_without_ctimes() {
+        let mut state = decode_state(&LEGACY_STATE[..], "fixture").unwrap();
+        let encoded = bincode::encode_to_vec(
+            (STATE_MAGIC, STATE_VERSION_WITHOUT_CTIMES, &state),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        assert_eq!(decode_state(&encoded[..], "version 2").unwrap(), state);
+
+        // Version 3 states hold a ctime for each entry.
+        state.entries[0].ctime = 1792379940;
+        let truncated = bincode::encode_to_vec(
+            (STATE_MAGIC, STATE_VERSION, &state, vec![1792379940i64]),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        assert!(decode_state(&truncated[..], "truncated").is_err());
+    }
+
+    #[test]
+    fn decodes_short_reads() {
+        let state = decode_state(&LEGACY_STATE[..], "fixture").unwrap();
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let mut encoded = Vec::new();
+            write_state_to_writer(&state, &mut encoded, compression, 0).unwrap();
+            // Returns a single byte per read, as a pipe can.
+            let reader = decompressing_reader(BufReader::with_capacity(1, &encoded[..])).unwrap();
+            let decoded = decode_state(BufReader::with_capacity(1, reader), "pipe").unwrap();
+            assert_eq!(decoded, state, "{:?}", compression);
+        }
+    }
+}
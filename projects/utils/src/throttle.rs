@@ -0,0 +1,197 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{Local, NaiveTime};
+
+/// Part of the day a limit applies to, wrapping around midnight when `end` is before `start`.
+#[derive(Clone, Debug, PartialEq)]
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    limit: u64,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// A limit changing with the time of day, written as comma separated `HH:MM-HH:MM=LIMIT` windows
+/// and an optional `LIMIT` for the rest of the day, such as `08:00-18:00=500,5000`. There is no
+/// limit outside of the windows without it, and a single `LIMIT` applies all day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    default: Option<u64>,
+}
+
+fn parse_limit(s: &str) -> Result<u64, String> {
+    match s.trim().parse::<u64>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(format!("Invalid limit '{}', expected a positive number", s)),
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M")
+        .map_err(|_| format!("Invalid time '{}', expected HH:MM", s))
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Result<Schedule, String> {
+        let mut schedule = Schedule {
+            windows: Vec::new(),
+            default: None,
+        };
+        for part in s.split(',') {
+            match part.split_once('=') {
+                Some((window, limit)) => {
+                    let Some((start, end)) = window.split_once('-') else {
+                        return Err(format!("Invalid window '{}', expected HH:MM-HH:MM", window));
+                    };
+                    let window = Window {
+                        start: parse_time(start)?,
+                        end: parse_time(end)?,
+                        limit: parse_limit(limit)?,
+                    };
+                    if window.start == window.end {
+                        return Err(format!("Empty window '{}'", part));
+                    }
+                    schedule.windows.push(window);
+                }
+                None if schedule.default.is_none() => schedule.default = Some(parse_limit(part)?),
+                None => return Err(format!("Several limits without a window in '{}'", s)),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Returns the limit at `time`, the one of the first window containing it.
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time))
+            .map(|window| window.limit)
+            .or(self.default)
+    }
+
+    /// Returns the limit at the current local time.
+    pub fn limit(&self) -> Option<u64> {
+        self.limit_at(Local::now().time())
+    }
+}
+
+/// Limits the rate of operations shared between threads to the limit per second of a
+/// [`Schedule`].
+pub struct RateLimiter {
+    schedule: Schedule,
+    /// Time at which the next operations may start.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: Schedule) -> RateLimiter {
+        RateLimiter {
+            schedule,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the operations before to fit in the current limit, then accounts for `count`
+    /// more operations.
+    pub fn acquire(&self, count: u64) {
+        let Some(limit) = self.schedule.limit() else {
+            return;
+        };
+        let wait = {
+            let mut next = self.next.lock().unwrap_or_else(|err| err.into_inner());
+            let now = Instant::now();
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs_f64(count as f64 / limit as f64);
+            start - now
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        parse_time(time).unwrap()
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let schedule = Schedule::parse("08:00-18:00=500, 5000").unwrap();
+        assert_eq!(
+            schedule,
+            Schedule {
+                windows: vec![Window {
+                    start: at("08:00"),
+                    end: at("18:00"),
+                    limit: 500,
+                }],
+                default: Some(5000),
+            }
+        );
+        assert_eq!(
+            Schedule::parse("100").unwrap(),
+            Schedule {
+                windows: Vec::new(),
+                default: Some(100),
+            }
+        );
+        for invalid in [
+            "",
+            "0",
+            "fast",
+            "100,200",
+            "08:00=100",
+            "08:00-08:00=100",
+            "08:00-24:00=100",
+            "8h-18h=100",
+            "08:00-18:00=0",
+        ] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn finds_limits_of_windows() {
+        let schedule = Schedule::parse("08:00-18:00=500,12:00-13:00=50,5000").unwrap();
+        assert_eq!(schedule.limit_at(at("07:59")), Some(5000));
+        assert_eq!(schedule.limit_at(at("08:00")), Some(500));
+        // The first window containing the time wins.
+        assert_eq!(schedule.limit_at(at("12:30")), Some(500));
+        assert_eq!(schedule.limit_at(at("17:59")), Some(500));
+        assert_eq!(schedule.limit_at(at("18:00")), Some(5000));
+
+        let schedule = Schedule::parse("09:00-17:00=500").unwrap();
+        assert_eq!(schedule.limit_at(at("12:00")), Some(500));
+        assert_eq!(schedule.limit_at(at("20:00")), None);
+    }
+
+    #[test]
+    fn wraps_windows_around_midnight() {
+        let schedule = Schedule::parse("22:00-06:00=10000,100").unwrap();
+        assert_eq!(schedule.limit_at(at("21:59")), Some(100));
+        assert_eq!(schedule.limit_at(at("22:00")), Some(10000));
+        assert_eq!(schedule.limit_at(at("23:59")), Some(10000));
+        assert_eq!(schedule.limit_at(at("00:00")), Some(10000));
+        assert_eq!(schedule.limit_at(at("05:59")), Some(10000));
+        assert_eq!(schedule.limit_at(at("06:00")), Some(100));
+        assert_eq!(schedule.limit_at(at("12:00")), Some(100));
+    }
+}